@@ -0,0 +1,224 @@
+use std::{io::Write, path::PathBuf, process::ExitCode, sync::Arc};
+
+use simpledb_rust_impl::{
+    error::DbError,
+    file::file_manager::{FileManager, FORMAT_VERSION},
+    log::log_manager::LogManager,
+    server::simple_db::{BLOCK_SIZE, LOG_FILE},
+    tx::recovery::{
+        log_dump::{format_record, LogFilter},
+        log_reader::LogReader,
+        log_record::op_from_name,
+    },
+};
+
+const USAGE: &str = "usage: simpledb-logdump <db-dir> [--log-file NAME] [--block-size N] [--json]
+                        [--tx N] [--type TYPE] [--file NAME]";
+
+struct Args {
+    dir: PathBuf,
+    log_file: String,
+    block_size: i32,
+    json: bool,
+    filter: LogFilter,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut dir = None;
+    let mut log_file = LOG_FILE.to_string();
+    let mut block_size = BLOCK_SIZE;
+    let mut json = false;
+    let mut filter = LogFilter::new();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--log-file" => log_file = value("--log-file")?,
+            "--block-size" => {
+                block_size = value("--block-size")?
+                    .parse()
+                    .map_err(|_| "invalid block size")?
+            }
+            "--json" => json = true,
+            "--tx" => {
+                let tx_num = value("--tx")?.parse().map_err(|_| "invalid tx number")?;
+                filter = filter.with_tx_num(tx_num);
+            }
+            "--type" => {
+                let name = value("--type")?;
+                let op = op_from_name(&name).ok_or(format!("unknown record type {}", name))?;
+                filter = filter.with_op(op);
+            }
+            "--file" => filter = filter.with_file(&value("--file")?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if dir.is_none() => dir = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    Ok(Args {
+        dir: dir.ok_or("missing database directory")?,
+        log_file,
+        block_size,
+        json,
+        filter,
+    })
+}
+
+// Print the records of the log that pass the filter, and return whether the log ends at a corrupt record.
+// The log is read as it is on disk: nothing in the directory is created or written,
+// so that a database in use, or one with a torn log, is left untouched.
+fn dump(args: &Args, out: &mut impl Write) -> Result<bool, String> {
+    let fm = FileManager::open(args.dir.clone(), args.block_size).map_err(|e| e.to_string())?;
+    match fm.format_version(&args.log_file) {
+        Ok(None) => {
+            return Err(format!(
+                "no log {} in {}",
+                args.log_file,
+                args.dir.display()
+            ))
+        }
+        Ok(Some(FORMAT_VERSION)) => {}
+        Ok(Some(version)) => return Err(DbError::UnsupportedFormat(version).to_string()),
+        Err(e) => return Err(e.to_string()),
+    }
+    let iter =
+        LogManager::read_segments(Arc::new(fm), &args.log_file).map_err(|e| e.to_string())?;
+
+    let mut reader = LogReader::from(iter);
+    for record in reader.by_ref() {
+        let (lsn, record) = record.map_err(|e| e.to_string())?;
+        if args.filter.matches(record.as_ref()) {
+            writeln!(out, "{}", format_record(lsn, record.as_ref(), args.json))
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(reader.is_corrupt())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match dump(&args, &mut std::io::stdout().lock()) {
+        Ok(false) => ExitCode::SUCCESS,
+        Ok(true) => {
+            eprintln!("the log ends at a corrupt record");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use simpledb_rust_impl::{file::block_id::BlockId, server::simple_db::SimpleDB};
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_args() {
+        let args = parse(&[
+            "db",
+            "--log-file",
+            "other.log",
+            "--block-size",
+            "100",
+            "--json",
+        ])
+        .unwrap();
+        assert_eq!(args.dir, PathBuf::from("db"));
+        assert_eq!(args.log_file, "other.log");
+        assert_eq!(args.block_size, 100);
+        assert!(args.json);
+
+        let args = parse(&["db", "--tx", "3", "--type", "COMMIT", "--file", "t.tbl"]).unwrap();
+        assert_eq!(args.log_file, LOG_FILE);
+        assert_eq!(args.block_size, BLOCK_SIZE);
+        assert!(!args.json);
+    }
+
+    #[test]
+    fn rejects_invalid_args() {
+        assert_eq!(parse(&[]).err().unwrap(), "missing database directory");
+        assert_eq!(parse(&["db", "--tx"]).err().unwrap(), "--tx needs a value");
+        assert_eq!(
+            parse(&["db", "--block-size", "x"]).err().unwrap(),
+            "invalid block size"
+        );
+        assert_eq!(
+            parse(&["db", "--type", "NOPE"]).err().unwrap(),
+            "unknown record type NOPE"
+        );
+        assert_eq!(
+            parse(&["db", "--verbose"]).err().unwrap(),
+            "unknown option --verbose"
+        );
+        assert_eq!(
+            parse(&["db", "other"]).err().unwrap(),
+            "unexpected argument other"
+        );
+    }
+
+    #[test]
+    fn dump_leaves_torn_log_unchanged() {
+        let dir = "testdata/bin/simpledb_logdump/dump_leaves_torn_log_unchanged";
+        let db = SimpleDB::new(dir, BLOCK_SIZE, 8, LOG_FILE);
+        let block = BlockId::new("data.tbl".to_string(), 0);
+        let mut tx = db.new_tx();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 0, 1, true).unwrap();
+        tx.commit().unwrap();
+        // the newest record is torn
+        let lm = db.log_manager();
+        lm.flush(lm.append(b"torn").unwrap()).unwrap();
+        drop(lm);
+        drop(db);
+        let path = format!("{}/{}.0", dir, LOG_FILE);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let block_start = bytes.len() - BLOCK_SIZE as usize;
+        let boundary = i32::from_be_bytes(bytes[block_start..block_start + 4].try_into().unwrap());
+        bytes[block_start + boundary as usize + 12] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        let files = std::fs::read_dir(dir).unwrap().count();
+
+        let args = parse(&[dir]).unwrap();
+        let mut out = vec![];
+        assert_eq!(dump(&args, &mut out), Ok(true));
+        assert!(String::from_utf8(out).unwrap().contains("<COMMIT"));
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), files);
+    }
+
+    #[test]
+    fn dump_filters_records() {
+        let dir = "testdata/bin/simpledb_logdump/dump_filters_records";
+        let db = SimpleDB::new(dir, BLOCK_SIZE, 8, LOG_FILE);
+        let block = BlockId::new("data.tbl".to_string(), 0);
+        let mut tx = db.new_tx();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 0, 1, true).unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        let args = parse(&[dir, "--type", "SET_INT"]).unwrap();
+        let mut out = vec![];
+        assert_eq!(dump(&args, &mut out), Ok(false));
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains("<SETINT"));
+
+        let args = parse(&["testdata/bin/simpledb_logdump/missing"]).unwrap();
+        assert!(dump(&args, &mut vec![]).is_err());
+    }
+}
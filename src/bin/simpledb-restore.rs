@@ -0,0 +1,76 @@
+use std::process::ExitCode;
+
+use simpledb_rust_impl::{server::restore::Restore, tx::recovery::log_replay::RestoreTarget};
+
+const USAGE: &str = "usage: simpledb-restore <new-db-dir> --backup DIR [--archive DIR]
+                        [--log-file NAME] [--block-size N]
+                        [--to-lsn N | --to-tx N | --to-time RFC3339]";
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(String, Restore), String> {
+    let mut dir = None;
+    let mut backup = None;
+    let mut archive = None;
+    let mut log_file = None;
+    let mut block_size = None;
+    let mut target = RestoreTarget::End;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--backup" => backup = Some(value("--backup")?),
+            "--archive" => archive = Some(value("--archive")?),
+            "--log-file" => log_file = Some(value("--log-file")?),
+            "--block-size" => {
+                let size = value("--block-size")?;
+                block_size = Some(size.parse().map_err(|_| "invalid block size")?);
+            }
+            "--to-lsn" => {
+                let lsn = value("--to-lsn")?.parse().map_err(|_| "invalid lsn")?;
+                target = RestoreTarget::Lsn(lsn);
+            }
+            "--to-tx" => {
+                let tx_num = value("--to-tx")?.parse().map_err(|_| "invalid tx number")?;
+                target = RestoreTarget::Tx(tx_num);
+            }
+            "--to-time" => {
+                let time = chrono::DateTime::parse_from_rfc3339(&value("--to-time")?)
+                    .map_err(|e| format!("invalid time: {}", e))?;
+                target = RestoreTarget::Time(time);
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if dir.is_none() => dir = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let mut restore = Restore::new(&backup.ok_or("missing --backup")?).with_target(target);
+    if let Some(archive) = archive {
+        restore = restore.with_archive(&archive);
+    }
+    if let Some(log_file) = log_file {
+        restore = restore.with_log_file(&log_file);
+    }
+    if let Some(block_size) = block_size {
+        restore = restore.with_block_size(block_size);
+    }
+    Ok((dir.ok_or("missing database directory")?, restore))
+}
+
+fn main() -> ExitCode {
+    let (dir, restore) = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match restore.run(&dir) {
+        Ok(lsn) => {
+            println!("restored {} up to lsn {}", dir, lsn);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
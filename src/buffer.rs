@@ -2,3 +2,4 @@
 
 pub mod buffer;
 pub mod buffer_manager;
+pub mod pinned_buffer;
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 use crate::{
     file::{block_id::BlockId, file_manager::FileManager, page::Page},
@@ -13,8 +13,9 @@ pub struct Buffer {
     lm: Arc<LogManager>,
     pub contents: Page,
     block: Option<BlockId>,
-    pins: i32,
-    tx_num: i32,
+    // Transactions that modified the buffer since it was last flushed.
+    // A block locked record by record can be modified by several of them.
+    tx_nums: HashSet<i32>,
     lsn: i32,
 }
 
@@ -26,8 +27,7 @@ impl Buffer {
             lm,
             contents,
             block: None,
-            pins: 0,
-            tx_num: -1,
+            tx_nums: HashSet::new(),
             lsn: -1,
         }
     }
@@ -37,51 +37,35 @@ impl Buffer {
     }
 
     pub fn set_modified(&mut self, tx_num: i32, lsn: i32) {
-        self.tx_num = tx_num;
+        self.tx_nums.insert(tx_num);
         if lsn >= 0 {
-            self.lsn = lsn;
+            self.lsn = self.lsn.max(lsn);
         }
     }
 
-    pub fn is_pinned(&self) -> bool {
-        self.pins > 0
-    }
-
-    pub fn modifying_tx(&self) -> i32 {
-        self.tx_num
+    // Whether the transaction modified the buffer, or any transaction did if `tx_num` is -1.
+    pub fn is_modified_by(&self, tx_num: i32) -> bool {
+        if tx_num == -1 {
+            !self.tx_nums.is_empty()
+        } else {
+            self.tx_nums.contains(&tx_num)
+        }
     }
 
     pub(super) fn assign_to_block(&mut self, block: BlockId) -> Result<()> {
         self.flush()?;
+        self.fm.read(&block, &mut self.contents)?;
         self.block = Some(block);
-        let fm = Arc::as_ptr(&self.fm) as *mut FileManager;
-        unsafe {
-            (*fm).read(self.block.as_ref().unwrap(), &mut self.contents)?;
-        }
-        self.pins = 0;
         Ok(())
     }
 
     pub(super) fn flush(&mut self) -> Result<()> {
-        if self.tx_num >= 0 {
-            let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-            unsafe {
-                (*lm).flush(self.lsn)?;
-            }
-            let fm = Arc::as_ptr(&self.fm) as *mut FileManager;
-            unsafe {
-                (*fm).write(self.block.as_ref().unwrap(), &self.contents)?;
-            }
-            self.tx_num = -1;
+        if !self.tx_nums.is_empty() {
+            self.lm.flush(self.lsn)?;
+            self.fm
+                .write(self.block.as_ref().unwrap(), &self.contents)?;
+            self.tx_nums.clear();
         }
         Ok(())
     }
-
-    pub(super) fn pin(&mut self) {
-        self.pins += 1;
-    }
-
-    pub(super) fn unpin(&mut self) {
-        self.pins -= 1;
-    }
 }
@@ -2,21 +2,28 @@
 
 use std::{
     collections::{BTreeSet, HashMap},
-    sync::Arc,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
 use crate::{
+    error::DbError,
     file::{block_id::BlockId, file_manager::FileManager},
     log::log_manager::LogManager,
     util::{current_time_millis, waiting_too_long, CondMutex, Result, MAX_WAIT_TIME_MILLIS},
 };
 
-use super::buffer::Buffer;
+use super::{buffer::Buffer, pinned_buffer::PinnedBuffer};
 
+// Locks are taken in this order: the pool state, then a buffer latch.
 pub struct BufferManager {
-    m: CondMutex<()>,
-    buffer_pool: Vec<Buffer>,
+    m: CondMutex<PoolState>,
+    // Each buffer is latched while its page is accessed: shared to read it, exclusive to change it.
+    buffer_pool: Vec<RwLock<Buffer>>,
+}
+
+struct PoolState {
     num_available: i32,
+    pins: Vec<i32>,
     unpinned_positions: BTreeSet<i32>,
     existing_positions: HashMap<BlockId, i32>,
 }
@@ -25,119 +32,114 @@ impl BufferManager {
     pub fn new(fm: Arc<FileManager>, lm: Arc<LogManager>, num_buffers: i32) -> Self {
         let mut buffer_pool = Vec::new();
         for _ in 0..num_buffers {
-            buffer_pool.push(Buffer::new(fm.clone(), lm.clone()));
+            buffer_pool.push(RwLock::new(Buffer::new(fm.clone(), lm.clone())));
         }
         BufferManager {
-            m: CondMutex::new(()),
+            m: CondMutex::new(PoolState {
+                num_available: num_buffers,
+                pins: vec![0; num_buffers as usize],
+                unpinned_positions: (0..num_buffers).collect(),
+                existing_positions: HashMap::new(),
+            }),
             buffer_pool,
-            num_available: num_buffers,
-            unpinned_positions: (0..num_buffers).collect(),
-            existing_positions: HashMap::new(),
         }
     }
 
-    pub fn get(&self, buf_idx: i32) -> &Buffer {
-        &self.buffer_pool[buf_idx as usize]
+    pub fn read(&self, buf_idx: i32) -> RwLockReadGuard<'_, Buffer> {
+        self.buffer_pool[buf_idx as usize].read().unwrap()
     }
 
-    pub fn get_mut(&mut self, buf_idx: i32) -> &mut Buffer {
-        &mut self.buffer_pool[buf_idx as usize]
+    pub fn write(&self, buf_idx: i32) -> RwLockWriteGuard<'_, Buffer> {
+        self.buffer_pool[buf_idx as usize].write().unwrap()
     }
 
     pub fn available(&self) -> i32 {
-        let _lock = self.m.lock();
-        self.num_available
+        self.m.lock().num_available
     }
 
-    pub fn flush_all(&mut self, tx_num: i32) -> Result<()> {
-        let _lock = self.m.lock();
-        for buffer in self.buffer_pool.iter_mut() {
-            if buffer.modifying_tx() == tx_num || (tx_num == -1 && buffer.modifying_tx() != -1) {
+    // Write the buffers modified by the transaction.
+    // Each is flushed under its own latch only, since flushing waits for the log,
+    // which may wait for other commits to share the write.
+    pub fn flush_all(&self, tx_num: i32) -> Result<()> {
+        let positions: Vec<usize> = {
+            let _state = self.m.lock();
+            self.buffer_pool
+                .iter()
+                .enumerate()
+                // A buffer latched by another flush is checked again below.
+                .filter(|(_, buffer)| {
+                    buffer
+                        .try_read()
+                        .map_or(true, |buffer| buffer.is_modified_by(tx_num))
+                })
+                .map(|(position, _)| position)
+                .collect()
+        };
+        for position in positions {
+            // A buffer reassigned in the meantime was flushed when it was.
+            let mut buffer = self.buffer_pool[position].write().unwrap();
+            if buffer.is_modified_by(tx_num) {
                 buffer.flush()?;
             }
         }
         Ok(())
     }
 
-    pub fn unpin(&mut self, buf_idx: i32) {
-        let _lock = self.m.lock();
-        let buffer = &mut self.buffer_pool[buf_idx as usize];
-        buffer.unpin();
-        if !buffer.is_pinned() {
-            self.num_available += 1;
-            self.unpinned_positions.insert(buf_idx);
-            self.existing_positions
-                .remove(buffer.block().as_ref().unwrap());
-            self.m.notify_all();
-        }
-    }
-
-    pub fn pin(&mut self, block: &BlockId) -> Result<i32> {
-        let mut lock = self.m.lock();
+    // The buffer stays pinned until the returned guard is dropped.
+    pub fn pin(self: &Arc<Self>, block: &BlockId) -> Result<PinnedBuffer> {
+        let mut state = self.m.lock();
         let start_time = current_time_millis();
-        let buffer_pool_ptr = &mut self.buffer_pool as *mut Vec<Buffer>;
         loop {
-            let buffer_pool = unsafe { &mut *buffer_pool_ptr };
-            let buffer = Self::try_to_pin(
-                buffer_pool,
-                &mut self.num_available,
-                block,
-                &mut self.unpinned_positions,
-                &mut self.existing_positions,
-            )?;
-            if buffer.is_some() || waiting_too_long(start_time) {
-                return buffer.ok_or("no available buffer".into());
+            if let Some(position) = self.try_to_pin(&mut state, block)? {
+                return Ok(PinnedBuffer::new(self.clone(), position));
+            }
+            if waiting_too_long(start_time) {
+                return Err(DbError::BufferAbort);
             }
 
-            lock = self.m.wait_timeout(lock, MAX_WAIT_TIME_MILLIS as u64);
+            state = self.m.wait_timeout(state, MAX_WAIT_TIME_MILLIS as u64);
         }
     }
 
-    fn try_to_pin(
-        buffer_pool: &mut [Buffer],
-        num_available: &mut i32,
-        block: &BlockId,
-        unpinned_positions: &mut BTreeSet<i32>,
-        existing_positions: &mut HashMap<BlockId, i32>,
-    ) -> Result<Option<i32>> {
-        let existing_position = Self::existing_position(existing_positions, block);
-        let unpinned_position = Self::unpinned_position(unpinned_positions);
-        if existing_position.is_none() && unpinned_position.is_none() {
-            return Ok(None);
+    pub(super) fn unpin(&self, buf_idx: i32) {
+        let mut state = self.m.lock();
+        state.pins[buf_idx as usize] -= 1;
+        if state.pins[buf_idx as usize] == 0 {
+            state.num_available += 1;
+            state.unpinned_positions.insert(buf_idx);
+            let buffer = self.read(buf_idx);
+            state
+                .existing_positions
+                .remove(buffer.block().as_ref().unwrap());
+            self.m.notify_all();
         }
+    }
 
-        let (buffer, position) = if existing_position.is_some() {
-            let position = existing_position.unwrap();
-            (&mut buffer_pool[position as usize], position)
-        } else {
-            let position = unpinned_position.unwrap();
-            let buffer = &mut buffer_pool[position as usize];
-            buffer.assign_to_block(block.clone())?;
-            existing_positions.insert(block.clone(), position);
-            (buffer, position)
+    fn try_to_pin(&self, state: &mut PoolState, block: &BlockId) -> Result<Option<i32>> {
+        let existing_position = state.existing_positions.get(block).copied();
+        let unpinned_position = state.unpinned_positions.iter().next().copied();
+        let position = match (existing_position, unpinned_position) {
+            (Some(position), _) => position,
+            (None, Some(position)) => {
+                self.write(position).assign_to_block(block.clone())?;
+                state.existing_positions.insert(block.clone(), position);
+                position
+            }
+            (None, None) => return Ok(None),
         };
-        if !buffer.is_pinned() {
-            *num_available -= 1;
-            unpinned_positions.remove(&position);
+        if state.pins[position as usize] == 0 {
+            state.num_available -= 1;
+            state.unpinned_positions.remove(&position);
         }
-        buffer.pin();
+        state.pins[position as usize] += 1;
         Ok(Some(position))
     }
-
-    fn existing_position(
-        existing_positions: &HashMap<BlockId, i32>,
-        block: &BlockId,
-    ) -> Option<i32> {
-        existing_positions.get(block).copied()
-    }
-
-    fn unpinned_position(unpinned_positions: &BTreeSet<i32>) -> Option<i32> {
-        unpinned_positions.iter().next().copied()
-    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
     use crate::server::simple_db::SimpleDB;
 
     use super::*;
@@ -157,31 +159,29 @@ mod tests {
             3,
             "templog",
         );
-        let bm = Arc::as_ptr(&db.buffer_manager()) as *mut BufferManager;
-        let bm = unsafe { &mut *bm };
+        let bm = db.buffer_manager();
         assert_eq!(bm.available(), 3);
 
-        let mut buffers: Vec<i32> = vec![];
+        let mut buffers: Vec<Option<PinnedBuffer>> = vec![];
 
         // fill buffer
         for i in 0..3 {
             let buf = bm.pin(&BlockId::new("testfile".to_string(), i)).unwrap();
-            assert_eq!(buf, i);
+            assert_eq!(buf.index(), i);
             assert_eq!(bm.available(), 2 - i);
-            buffers.push(buf);
+            buffers.push(Some(buf));
         }
 
         // free
-        bm.unpin(buffers[1]);
+        buffers[1] = None;
         assert_eq!(bm.available(), 1);
-        buffers[1] = -1;
 
         // fill buffer
         for i in 0..2 {
             let buf = bm.pin(&BlockId::new("testfile".to_string(), i)).unwrap();
-            assert_eq!(buf, i);
+            assert_eq!(buf.index(), i);
             assert_eq!(bm.available(), 1 - i);
-            buffers.push(buf);
+            buffers.push(Some(buf));
         }
 
         // buffer is full
@@ -189,15 +189,18 @@ mod tests {
         assert!(res.is_err());
 
         // free
-        bm.unpin(buffers[2]);
+        buffers[2] = None;
         assert_eq!(bm.available(), 1);
-        buffers[2] = -1;
 
         // now buffer is available
         let buf = bm.pin(&BlockId::new("testfile".to_string(), 3)).unwrap();
-        assert_eq!(buf, 2);
+        assert_eq!(buf.index(), 2);
         assert_eq!(bm.available(), 0);
-        buffers.push(buf);
+        buffers.push(Some(buf));
+
+        // dropping the guards unpins everything
+        buffers.clear();
+        assert_eq!(bm.available(), 3);
 
         // delete testfile
         std::fs::remove_file("testdata/buffer/buffer_manager/pin_and_unpin/testfile").unwrap();
@@ -218,25 +221,25 @@ mod tests {
             3,
             "templog",
         );
-        let bm = Arc::as_ptr(&db.buffer_manager()) as *mut BufferManager;
-        let bm = unsafe { &mut *bm };
+        let bm = db.buffer_manager();
 
         // 0: modify and set_modified
-        bm.pin(&BlockId::new("testfile".to_string(), 0)).unwrap();
-        let buf = bm.get_mut(0);
+        let buf0 = bm.pin(&BlockId::new("testfile".to_string(), 0)).unwrap();
+        let mut buf = buf0.write();
         buf.contents.set_string(0, "abcde");
         buf.set_modified(1, 1);
+        drop(buf);
 
         // 1: modify and set_modified
-        bm.pin(&BlockId::new("testfile".to_string(), 1)).unwrap();
-        let buf = bm.get_mut(1);
+        let buf1 = bm.pin(&BlockId::new("testfile".to_string(), 1)).unwrap();
+        let mut buf = buf1.write();
         buf.contents.set_string(0, "fghij");
         buf.set_modified(1, 2);
+        drop(buf);
 
         // 2: just modify, not set_modified
-        bm.pin(&BlockId::new("testfile".to_string(), 2)).unwrap();
-        let buf = bm.get_mut(2);
-        buf.contents.set_string(0, "klmno");
+        let buf2 = bm.pin(&BlockId::new("testfile".to_string(), 2)).unwrap();
+        buf2.write().contents.set_string(0, "klmno");
 
         bm.flush_all(1).unwrap();
 
@@ -250,4 +253,40 @@ mod tests {
         // delete testfile
         std::fs::remove_file("testdata/buffer/buffer_manager/modify_and_flush/testfile").unwrap();
     }
+
+    #[test]
+    fn pins_while_flushing() {
+        let db = SimpleDB::new(
+            "testdata/buffer/buffer_manager/pins_while_flushing",
+            400,
+            3,
+            "templog",
+        );
+        let bm = db.buffer_manager();
+
+        // the flush waits for the latch on the modified buffer
+        let buf0 = bm.pin(&BlockId::new("tempfile".to_string(), 0)).unwrap();
+        let mut latch = buf0.write();
+        latch.set_modified(1, -1);
+        let flusher = {
+            let bm = bm.clone();
+            thread::spawn(move || bm.flush_all(1).unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        // without holding the pool meanwhile
+        let (sender, receiver) = mpsc::channel();
+        let pinner = {
+            let bm = bm.clone();
+            thread::spawn(move || {
+                let buf1 = bm.pin(&BlockId::new("tempfile".to_string(), 1)).unwrap();
+                sender.send(buf1.index()).unwrap();
+            })
+        };
+        let pinned = receiver.recv_timeout(Duration::from_secs(1));
+        drop(latch);
+        flusher.join().unwrap();
+        pinner.join().unwrap();
+        assert!(pinned.is_ok());
+    }
 }
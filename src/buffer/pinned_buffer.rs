@@ -0,0 +1,36 @@
+#![allow(dead_code)]
+
+use std::sync::{Arc, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{buffer::Buffer, buffer_manager::BufferManager};
+
+// A pin on a buffer, released when it is dropped.
+// The buffer keeps its block while pinned, and is latched by `read` and `write` while its page is accessed.
+pub struct PinnedBuffer {
+    bm: Arc<BufferManager>,
+    index: i32,
+}
+
+impl PinnedBuffer {
+    pub(super) fn new(bm: Arc<BufferManager>, index: i32) -> Self {
+        Self { bm, index }
+    }
+
+    pub fn index(&self) -> i32 {
+        self.index
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Buffer> {
+        self.bm.read(self.index)
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, Buffer> {
+        self.bm.write(self.index)
+    }
+}
+
+impl Drop for PinnedBuffer {
+    fn drop(&mut self) {
+        self.bm.unpin(self.index);
+    }
+}
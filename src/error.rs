@@ -0,0 +1,129 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum DbError {
+    // Concurrency control. The transaction is rolled back, and may succeed if retried.
+    LockTimeout,
+    Deadlock,
+    Wounded,
+    BufferAbort,
+    SerializationFailure,
+    ConcurrentUpdate,
+    ValidationFailure,
+
+    // Misuse of a transaction.
+    ReadOnly,
+    Prepared,
+    NotInDoubt(i32),
+    NotOptimistic,
+    SavepointNotFound(String),
+    CheckpointTimeout,
+
+    // Records.
+    UnknownField(String),
+    UnknownFlag(String),
+    ValueTooLong(String),
+    TypeMismatch(String),
+    // A stored value that cannot be decoded, e.g. a null date.
+    InvalidValue(&'static str),
+    RecordNotVisible,
+
+    // Opening files written in an older format.
+    UnsupportedFormat(i32),
+
+    // Restoring from a backup.
+    MissingLogSegment(i32),
+    // Records lost from the log other than in its torn tail.
+    CorruptLog(String),
+
+    Io(std::io::Error),
+}
+
+impl DbError {
+    // Whether the failure comes from a conflict with other transactions,
+    // so that running the transaction again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            DbError::LockTimeout
+                | DbError::Deadlock
+                | DbError::Wounded
+                | DbError::BufferAbort
+                | DbError::SerializationFailure
+                | DbError::ConcurrentUpdate
+                | DbError::ValidationFailure
+        )
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::LockTimeout => write!(f, "lock wait timeout"),
+            DbError::Deadlock => write!(f, "deadlock"),
+            DbError::Wounded => write!(f, "transaction wounded"),
+            DbError::BufferAbort => write!(f, "no available buffer"),
+            DbError::SerializationFailure => write!(f, "serialization failure"),
+            DbError::ConcurrentUpdate => write!(f, "concurrent update"),
+            DbError::ValidationFailure => write!(f, "optimistic validation failed"),
+            DbError::ReadOnly => write!(f, "transaction is read-only"),
+            DbError::Prepared => write!(f, "transaction is prepared"),
+            DbError::NotInDoubt(tx_num) => write!(f, "transaction {} is not in doubt", tx_num),
+            DbError::NotOptimistic => write!(f, "transaction is not optimistic"),
+            DbError::SavepointNotFound(name) => write!(f, "savepoint not found: {}", name),
+            DbError::CheckpointTimeout => write!(f, "checkpoint timeout"),
+            DbError::UnknownField(name) => write!(f, "field not found: {}", name),
+            DbError::UnknownFlag(name) => write!(f, "flag not found: {}", name),
+            DbError::ValueTooLong(name) => write!(f, "value too long for field {}", name),
+            DbError::TypeMismatch(name) => write!(f, "type mismatch for field {}", name),
+            DbError::InvalidValue(kind) => write!(f, "invalid {}", kind),
+            DbError::RecordNotVisible => write!(f, "record not visible"),
+            DbError::UnsupportedFormat(version) => {
+                write!(f, "unsupported file format version {}", version)
+            }
+            DbError::MissingLogSegment(segment) => write!(f, "log segment {} is missing", segment),
+            DbError::CorruptLog(at) => write!(f, "corrupt log: {}", at),
+            DbError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_retryable() {
+        assert!(DbError::Deadlock.is_retryable());
+        assert!(DbError::SerializationFailure.is_retryable());
+        assert!(!DbError::UnknownField("a".to_string()).is_retryable());
+        assert!(!DbError::Io(std::io::Error::other("disk")).is_retryable());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(DbError::LockTimeout.to_string(), "lock wait timeout");
+        assert_eq!(
+            DbError::UnknownField("a".to_string()).to_string(),
+            "field not found: a"
+        );
+        assert_eq!(DbError::InvalidValue("date").to_string(), "invalid date");
+    }
+}
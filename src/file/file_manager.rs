@@ -1,20 +1,27 @@
 #![allow(dead_code)]
 
 use crate::file::page::Page;
-use crate::{file::block_id::BlockId, util::Result};
+use crate::{error::DbError, file::block_id::BlockId, util::Result};
 use std::{
     collections::HashMap,
     fs::{File, OpenOptions},
     os::unix::fs::FileExt,
-    path::PathBuf,
-    sync::{Mutex, MutexGuard},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
 };
 
+// The version of the format of the files, recorded in FORMAT_FILE.
+// Version 2 added the transactions that created and deleted a record to its slot,
+// and the log segments whose records carry an LSN and a checksum.
+pub const FORMAT_VERSION: i32 = 2;
+pub const FORMAT_FILE: &str = "simpledb.format";
+
 pub struct FileManager {
     db_directory: PathBuf,
     block_size: i32,
     is_new: bool,
-    open_files: HashMap<String, Mutex<File>>,
+    // Each file has its own lock, so that appending does not race with computing the length.
+    open_files: Mutex<HashMap<String, Arc<Mutex<File>>>>,
 }
 
 impl FileManager {
@@ -46,33 +53,52 @@ impl FileManager {
             db_directory,
             block_size,
             is_new,
-            open_files: HashMap::new(),
+            open_files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Open an existing directory as is, e.g. for tools inspecting a database.
+    pub fn open(db_directory: PathBuf, block_size: i32) -> Result<FileManager> {
+        if !db_directory.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not a directory", db_directory.display()),
+            )
+            .into());
         }
+        Ok(FileManager {
+            db_directory,
+            block_size,
+            is_new: false,
+            open_files: Mutex::new(HashMap::new()),
+        })
     }
 
-    pub fn read(&mut self, block: &BlockId, page: &mut Page) -> Result<()> {
+    pub fn read(&self, block: &BlockId, page: &mut Page) -> Result<()> {
         let offset = block.block_num() * self.block_size;
 
-        let file = self.get_file(block.filename()).lock().unwrap();
+        let file = self.get_file(block.filename());
+        let file = file.lock().unwrap();
         file.read_at(&mut page.buf, offset as u64)?;
         Ok(())
     }
 
-    pub fn write(&mut self, block: &BlockId, page: &Page) -> Result<()> {
+    pub fn write(&self, block: &BlockId, page: &Page) -> Result<()> {
         let offset = block.block_num() * self.block_size;
 
-        let file = self.get_file(block.filename()).lock().unwrap();
+        let file = self.get_file(block.filename());
+        let file = file.lock().unwrap();
         file.write_all_at(&page.buf, offset as u64)?;
         file.sync_all()?;
         Ok(())
     }
 
-    pub fn append(&mut self, filename: &str) -> Result<BlockId> {
+    pub fn append(&self, filename: &str) -> Result<BlockId> {
         let block_size = self.block_size;
 
-        let file = self.get_file(filename).lock().unwrap();
+        let file = self.get_file(filename);
+        let file = file.lock().unwrap();
         // `new_block_num` must be calculated after the file is locked.
-        // `length()` can't be called here because it borrows immutably.
         let new_block_num = FileManager::length_from_file(&file, block_size)?;
         let new_size = (new_block_num + 1) * block_size;
         file.set_len(new_size as u64)?;
@@ -85,13 +111,85 @@ impl FileManager {
         Ok(file.metadata()?.len() as i32 / block_size)
     }
 
-    pub fn length(&mut self, filename: &str) -> Result<i32> {
+    pub fn length(&self, filename: &str) -> Result<i32> {
         let block_size = self.block_size;
 
-        let file = self.get_file(filename).lock().unwrap();
+        let file = self.get_file(filename);
+        let file = file.lock().unwrap();
         FileManager::length_from_file(&file, block_size)
     }
 
+    // The names of the files in the database directory, leaving out its subdirectories.
+    pub fn file_names(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(&self.db_directory)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    pub fn remove(&self, filename: &str) -> Result<()> {
+        self.open_files.lock().unwrap().remove(filename);
+        std::fs::remove_file(self.db_directory.join(filename))?;
+        Ok(())
+    }
+
+    // Move a file out of the database directory into `dir`.
+    pub fn archive(&self, filename: &str, dir: &Path) -> Result<()> {
+        self.open_files.lock().unwrap().remove(filename);
+        std::fs::create_dir_all(dir)?;
+        std::fs::rename(self.db_directory.join(filename), dir.join(filename))?;
+        Ok(())
+    }
+
+    // Copy a file into `dir`. Writes to the file wait for the copy, so no block is copied half written.
+    pub fn copy(&self, filename: &str, dir: &Path) -> Result<()> {
+        let file = self.get_file(filename);
+        let _file = file.lock().unwrap();
+        std::fs::create_dir_all(dir)?;
+        std::fs::copy(self.db_directory.join(filename), dir.join(filename))?;
+        Ok(())
+    }
+
+    // The format version recorded in the directory. A directory without a record is version 1
+    // if it holds a log, and has no version if no database was written to it yet.
+    pub fn format_version(&self, log_file: &str) -> Result<Option<i32>> {
+        match std::fs::read_to_string(self.db_directory.join(FORMAT_FILE)) {
+            Ok(contents) => Ok(Some(contents.trim().parse().unwrap_or(0))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let segment_prefix = format!("{}.", log_file);
+                let has_log = self
+                    .file_names()?
+                    .iter()
+                    .any(|name| name == log_file || name.starts_with(&segment_prefix));
+                Ok(has_log.then_some(1))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Check that the files were written in the current format,
+    // and record the format in a directory that holds no database yet.
+    pub fn check_format(&self, log_file: &str) -> Result<()> {
+        match self.format_version(log_file)? {
+            None => {
+                std::fs::write(
+                    self.db_directory.join(FORMAT_FILE),
+                    FORMAT_VERSION.to_string(),
+                )?;
+                Ok(())
+            }
+            Some(FORMAT_VERSION) => Ok(()),
+            Some(version) => Err(DbError::UnsupportedFormat(version)),
+        }
+    }
+
     pub fn is_new(&self) -> bool {
         self.is_new
     }
@@ -100,8 +198,9 @@ impl FileManager {
         self.block_size
     }
 
-    fn get_file(&mut self, filename: &str) -> &Mutex<File> {
-        if !self.open_files.contains_key(filename) {
+    fn get_file(&self, filename: &str) -> Arc<Mutex<File>> {
+        let mut open_files = self.open_files.lock().unwrap();
+        if !open_files.contains_key(filename) {
             let path = self.db_directory.join(filename);
             let file = OpenOptions::new()
                 .read(true)
@@ -110,17 +209,15 @@ impl FileManager {
                 .truncate(false)
                 .open(path)
                 .unwrap();
-            let mutex = Mutex::new(file);
-            self.open_files.insert(filename.to_string(), mutex);
+            let mutex = Arc::new(Mutex::new(file));
+            open_files.insert(filename.to_string(), mutex);
         }
-        self.open_files.get(filename).unwrap()
+        open_files.get(filename).unwrap().clone()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
-
     use crate::server::simple_db::SimpleDB;
 
     use super::*;
@@ -128,8 +225,7 @@ mod tests {
     #[test]
     fn read() {
         let db = SimpleDB::new("testdata/file/file_manager/read", 10, 8, "templog");
-        let fm = Arc::as_ptr(&db.file_manager()) as *mut FileManager;
-        let fm = unsafe { &mut *fm };
+        let fm = db.file_manager();
         let mut page = Page::new(fm.block_size());
 
         let block = BlockId::new("testfile".to_string(), 1);
@@ -140,8 +236,7 @@ mod tests {
     #[test]
     fn write() {
         let db = SimpleDB::new("testdata/file/file_manager/write", 10, 8, "templog");
-        let fm = Arc::as_ptr(&db.file_manager()) as *mut FileManager;
-        let fm = unsafe { &mut *fm };
+        let fm = db.file_manager();
         let mut page = Page::new(fm.block_size());
 
         let block = BlockId::new("tempfile1".to_string(), 1);
@@ -157,8 +252,7 @@ mod tests {
     #[test]
     fn append() {
         let db = SimpleDB::new("testdata/file/file_manager/append", 10, 8, "templog");
-        let fm = Arc::as_ptr(&db.file_manager()) as *mut FileManager;
-        let fm = unsafe { &mut *fm };
+        let fm = db.file_manager();
 
         let block = fm.append("tempfile2").unwrap();
         assert_eq!(block, BlockId::new("tempfile2".to_string(), 0));
@@ -0,0 +1,10 @@
+pub mod buffer;
+pub mod error;
+pub mod file;
+pub mod log;
+pub mod query;
+pub mod record;
+pub mod server;
+pub mod sql;
+pub mod tx;
+pub mod util;
@@ -1,61 +1,296 @@
 #![allow(dead_code)]
 
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use tracing::warn;
+
 use crate::{
+    error::DbError,
     file::{block_id::BlockId, file_manager::FileManager, page::Page},
     util::{Result, INTEGER_BYTES},
 };
 
-pub(super) struct LogIterator<'a> {
-    fm: &'a mut FileManager,
-    block: BlockId,
+use super::log_manager::{LogManager, HEADER_BYTES, RECORD_HEADER_BYTES};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // From the oldest record to the newest.
+    Forward,
+    // From the newest record to the oldest.
+    Backward,
+}
+
+// The records of a block with their LSNs.
+type BlockRecords = Vec<(i32, Vec<u8>)>;
+
+// Where a block of the log is: its segment, and its number in the segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LogPosition {
+    pub segment: i32,
+    pub block: i32,
+}
+
+// Iterates over the log records with their LSNs in either direction.
+// Each block is read whole, and the iterator moves to the neighbouring segment at either end of a segment.
+// Only the newest block can be torn by a crash. A corrupt record there hides the newer records
+// of the block: the iteration forward ends there, and the iteration backward goes on with the older records.
+// A corrupt record in any other block, or a gap in the LSNs, means records were lost,
+// and is returned as an error like a block that cannot be read. Either ends the iteration.
+pub struct LogIterator {
+    fm: Arc<FileManager>,
+    log_file: String,
+    first_segment: i32,
+    last_segment: i32,
+    direction: Direction,
+    // Records before this LSN in the iteration order are skipped.
+    start_lsn: Option<i32>,
+    segment: i32,
+    block: Option<BlockId>,
     page: Page,
-    current_pos: i32,
-    boundary: i32,
+    // The records of the current block not yet returned, in iteration order.
+    records: VecDeque<(i32, Vec<u8>)>,
+    // The LSN of the last record read, which the next one must follow.
+    last_lsn: Option<i32>,
+    // The position of the oldest record of the current block kept after a corrupt one.
+    intact_pos: i32,
+    corrupt: bool,
 }
 
-impl<'a> LogIterator<'a> {
-    pub fn new(fm: &'a mut FileManager, block: BlockId) -> Self {
+impl LogIterator {
+    pub fn new(
+        fm: Arc<FileManager>,
+        log_file: String,
+        first_segment: i32,
+        last_segment: i32,
+        block: BlockId,
+        direction: Direction,
+    ) -> Result<Self> {
         let page = Page::new(fm.block_size());
+        let segment = match direction {
+            Direction::Forward => first_segment,
+            Direction::Backward => last_segment,
+        };
         let mut iter = Self {
             fm,
-            block,
+            log_file,
+            first_segment,
+            last_segment,
+            direction,
+            start_lsn: None,
+            segment,
+            block: None,
             page,
-            current_pos: 0,
-            boundary: 0,
+            records: VecDeque::new(),
+            last_lsn: None,
+            intact_pos: 0,
+            corrupt: false,
         };
-        iter.move_to_block().unwrap();
-        iter
+        iter.move_to_block(block)?;
+        Ok(iter)
+    }
+
+    // Start at the record `lsn`, or at the first one past it in the iteration order.
+    pub fn starting_at(mut self, lsn: i32) -> Self {
+        self.start_lsn = Some(lsn);
+        self
+    }
+
+    // The block being read, which holds the last record returned.
+    // Reading forward again from there finds the records appended since.
+    pub fn position(&self) -> LogPosition {
+        LogPosition {
+            segment: self.segment,
+            block: self.block.as_ref().map_or(0, |block| block.block_num()),
+        }
+    }
+
+    // Whether a corrupt record was found.
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt
     }
 
-    fn has_next(&self) -> bool {
-        self.current_pos < self.fm.block_size() || self.block.block_num() > 0
+    // Where the records of the current block kept past a corrupt one start.
+    pub(super) fn intact_pos(&self) -> i32 {
+        self.intact_pos
     }
 
-    fn move_to_block(&mut self) -> Result<()> {
-        self.fm.read(&self.block, &mut self.page)?;
-        self.boundary = self.page.get_int(0);
-        self.current_pos = self.boundary;
+    fn move_to_block(&mut self, block: BlockId) -> Result<()> {
+        let (mut records, mut corrupt_pos) = self.read_block(&block)?;
+        // A block read while it is written looks torn. It is written whole before a newer block
+        // is appended, so a torn block that is no longer the newest is read again.
+        if corrupt_pos.is_some() && !self.is_tail(&block)? {
+            (records, corrupt_pos) = self.read_block(&block)?;
+        }
+        self.intact_pos = self.page.get_int(0);
+        if let Some(pos) = corrupt_pos {
+            warn!(%block, pos, "corrupt log record");
+            self.corrupt = true;
+            if !self.is_tail(&block)? {
+                return Err(DbError::CorruptLog(format!(
+                    "record at {} in {}",
+                    pos, block
+                )));
+            }
+            // The records newer than the corrupt one are dropped with it.
+            (self.intact_pos, records) = self.intact_records(pos);
+        }
+
+        match self.direction {
+            Direction::Forward => self.records.extend(records.into_iter().rev()),
+            Direction::Backward => self.records.extend(records),
+        }
+        self.block = Some(block);
         Ok(())
     }
-}
 
-impl<'a> Iterator for LogIterator<'a> {
-    type Item = Vec<u8>;
+    // Read the records of the block, newest first, up to the position of a corrupt one if any.
+    fn read_block(&mut self, block: &BlockId) -> Result<(BlockRecords, Option<i32>)> {
+        self.fm.read(block, &mut self.page)?;
+        // A torn block can still hold intact records, so each record is checked on its own.
+        let checksum = self.page.get_int(INTEGER_BYTES) as u32;
+        if LogManager::block_checksum(&self.page) != Some(checksum) {
+            warn!(%block, "log block checksum mismatch");
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.has_next() {
+        // Records are stored from the end of the block, so the newest comes first.
+        let mut records = vec![];
+        let mut pos = self.page.get_int(0);
+        if !(HEADER_BYTES..=self.fm.block_size()).contains(&pos) {
+            return Ok((records, Some(HEADER_BYTES)));
+        }
+        while pos < self.fm.block_size() {
+            let Some((lsn, record, next_pos)) = self.read_record(pos) else {
+                return Ok((records, Some(pos)));
+            };
+            records.push((lsn, record));
+            pos = next_pos;
+        }
+        Ok((records, None))
+    }
+
+    // Whether the block is the newest one of the log, the only one a crash can tear.
+    fn is_tail(&self, block: &BlockId) -> Result<bool> {
+        Ok(self.segment == self.last_segment
+            && block.block_num() + 1 >= self.fm.length(block.filename())?)
+    }
+
+    // The records older than a corrupt one at `pos`, newest first, and where they start:
+    // the first position past it from which the records run intact to the end of the block.
+    fn intact_records(&self, pos: i32) -> (i32, BlockRecords) {
+        let block_size = self.fm.block_size();
+        'start: for start in pos + 1..block_size {
+            let mut records = vec![];
+            let mut pos = start;
+            while pos < block_size {
+                let Some((lsn, record, next_pos)) = self.read_record(pos) else {
+                    continue 'start;
+                };
+                records.push((lsn, record));
+                pos = next_pos;
+            }
+            return (start, records);
+        }
+        (block_size, vec![])
+    }
+
+    // The LSN and the contents of the record at `pos`, and the position of the next one,
+    // or `None` if the record is corrupt.
+    fn read_record(&self, pos: i32) -> Option<(i32, Vec<u8>, i32)> {
+        let block_size = self.fm.block_size();
+        if pos + INTEGER_BYTES > block_size {
+            return None;
+        }
+        let len = self.page.get_int(pos);
+        if len < RECORD_HEADER_BYTES || len > block_size - pos - INTEGER_BYTES {
+            return None;
+        }
+        let lsn = self.page.get_int(pos + INTEGER_BYTES);
+        let checksum = self.page.get_int(pos + 2 * INTEGER_BYTES) as u32;
+        let start = (pos + INTEGER_BYTES + RECORD_HEADER_BYTES) as usize;
+        let end = (pos + INTEGER_BYTES + len) as usize;
+        let record = self.page.buffer()[start..end].to_vec();
+        if LogManager::record_checksum(lsn, &record) != checksum {
             return None;
         }
+        Some((lsn, record, end as i32))
+    }
+
+    fn next_block(&mut self) -> Result<Option<BlockId>> {
+        // The records after a corrupt one cannot be found.
+        let stop = self.corrupt && self.direction == Direction::Forward;
+        let Some(block) = self.block.as_ref().filter(|_| !stop) else {
+            return Ok(None);
+        };
+        let block = match self.direction {
+            Direction::Backward if block.block_num() > 0 => {
+                BlockId::new(block.filename().to_string(), block.block_num() - 1)
+            }
+            Direction::Backward if self.segment > self.first_segment => {
+                self.segment -= 1;
+                let filename = LogManager::segment_file(&self.log_file, self.segment);
+                let size = self.fm.length(&filename)?;
+                BlockId::new(filename, size - 1)
+            }
+            Direction::Forward if block.block_num() + 1 < self.fm.length(block.filename())? => {
+                BlockId::new(block.filename().to_string(), block.block_num() + 1)
+            }
+            Direction::Forward if self.segment < self.last_segment => {
+                self.segment += 1;
+                BlockId::new(LogManager::segment_file(&self.log_file, self.segment), 0)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(block))
+    }
 
-        if self.current_pos == self.fm.block_size() {
-            self.block = BlockId::new(
-                self.block.filename().to_string(),
-                self.block.block_num() - 1,
-            );
-            self.move_to_block().unwrap();
+    fn is_before_start(&self, lsn: i32) -> bool {
+        match (self.start_lsn, self.direction) {
+            (None, _) => false,
+            (Some(start), Direction::Forward) => lsn < start,
+            (Some(start), Direction::Backward) => lsn > start,
+        }
+    }
+}
+
+impl Iterator for LogIterator {
+    type Item = Result<(i32, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while self.records.is_empty() {
+                let res = self
+                    .next_block()
+                    .and_then(|block| block.map(|block| self.move_to_block(block)).transpose());
+                match res {
+                    Ok(Some(())) => {}
+                    Ok(None) => return None,
+                    Err(e) => {
+                        self.block = None;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            let (lsn, record) = self.records.pop_front().unwrap();
+            // LSNs are given one after another, so a gap means records were lost.
+            let expected = self.last_lsn.map(|last| match self.direction {
+                Direction::Forward => last + 1,
+                Direction::Backward => last - 1,
+            });
+            if expected.is_some_and(|expected| lsn != expected) {
+                self.corrupt = true;
+                self.block = None;
+                self.records.clear();
+                let last = self.last_lsn.unwrap();
+                return Some(Err(DbError::CorruptLog(format!(
+                    "record {} follows record {}",
+                    lsn, last
+                ))));
+            }
+            self.last_lsn = Some(lsn);
+            if !self.is_before_start(lsn) {
+                return Some(Ok((lsn, record)));
+            }
         }
-        let record = self.page.get_bytes(self.current_pos);
-        self.current_pos += record.len() as i32 + INTEGER_BYTES;
-        Some(record.to_vec())
     }
 }
@@ -1,178 +1,796 @@
 #![allow(dead_code)]
 
-use std::sync::{Arc, Mutex};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use tracing::warn;
 
 use crate::{
+    error::DbError,
     file::{block_id::BlockId, file_manager::FileManager, page::Page},
-    util::{Result, INTEGER_BYTES},
+    util::{crc32, CondMutex, Result, INTEGER_BYTES, MAX_WAIT_TIME_MILLIS},
 };
 
-use super::log_iterator::LogIterator;
+use super::log_iterator::{Direction, LogIterator, LogPosition};
+
+const ASYNC_FLUSH_INTERVAL_MILLIS: u64 = 100;
+const SEGMENT_BLOCKS: i32 = 64;
+// A block starts with the boundary of its records and the checksum of the block.
+pub(super) const HEADER_BYTES: i32 = 2 * INTEGER_BYTES;
+// A record starts with its LSN and its checksum.
+pub(super) const RECORD_HEADER_BYTES: i32 = 2 * INTEGER_BYTES;
 
+// Each record is stored with its LSN, so LSNs keep growing across restarts.
+// Records and blocks carry CRCs, so a block torn by a crash is detected when the log is read.
+// The log is split into numbered segment files of at most `segment_blocks` blocks each.
+// Segments before the last checkpoint can be archived or deleted.
+//
+// Commits are grouped: a single flusher writes the log page for every transaction waiting on it,
+// and the others wait until their records are on disk.
 pub struct LogManager {
-    m: Mutex<()>,
     fm: Arc<FileManager>,
+    state: CondMutex<LogState>,
+}
+
+struct LogState {
+    log_file: String,
+    first_segment: i32,
+    current_segment: i32,
+    segment_blocks: i32,
+    // Where old segments are moved to, or `None` to delete them.
+    archive_dir: Option<PathBuf>,
+    // The number of backups in progress. Old segments are kept until they are done.
+    backups: i32,
     log_page: Page,
     current_block: BlockId,
     latest_lsn: i32,
     last_saved_lsn: i32,
+    // Whether a flusher is writing the log page outside the lock.
+    flushing: bool,
+    // How long a flusher waits for more records before writing.
+    max_flush_delay: Duration,
+    flush_count: u64,
+    // The background flusher makes asynchronous commits durable within this interval.
+    async_flush_interval: Duration,
+    flusher_started: bool,
 }
 
 impl LogManager {
-    pub fn new(fm: Arc<FileManager>, log_file: String) -> Self {
+    pub fn new(fm: Arc<FileManager>, log_file: String) -> Result<Self> {
         let mut log_page = Page::new(fm.block_size());
 
-        let fm_ptr = Arc::as_ptr(&fm) as *mut FileManager;
-        let log_size = unsafe { (*fm_ptr).length(&log_file).unwrap() };
+        let segments = Self::segments(&fm, &log_file)?;
+        let first_segment = segments.first().copied().unwrap_or(0);
+        let current_segment = segments.last().copied().unwrap_or(0);
+        let segment_file = Self::segment_file(&log_file, current_segment);
+        let log_size = fm.length(&segment_file)?;
         let current_block: BlockId = if log_size == 0 {
-            unsafe { Self::append_new_block(&mut *fm_ptr, &log_file, &mut log_page).unwrap() }
+            Self::append_new_block(&fm, &segment_file, &mut log_page)?
         } else {
-            let current_block = BlockId::new(log_file, log_size - 1);
-            unsafe {
-                (*fm_ptr).read(&current_block, &mut log_page).unwrap();
-            }
+            let current_block = BlockId::new(segment_file, log_size - 1);
+            fm.read(&current_block, &mut log_page)?;
             current_block
         };
+        let mut iter = LogIterator::new(
+            fm.clone(),
+            log_file.clone(),
+            first_segment,
+            current_segment,
+            current_block.clone(),
+            Direction::Backward,
+        )?;
+        // A block torn by a crash keeps only its intact records,
+        // so that the records appended next are not hidden behind the corrupt ones.
+        if iter.is_corrupt() {
+            warn!(%current_block, "truncating the torn tail of the log");
+            log_page.set_int(0, iter.intact_pos());
+            Self::seal(&mut log_page);
+            fm.write(&current_block, &log_page)?;
+        }
+        // The newest record holds the latest LSN.
+        let latest_lsn = iter.next().transpose()?.map_or(0, |(lsn, _)| lsn);
 
-        LogManager {
-            m: Mutex::new(()),
+        Ok(LogManager {
             fm,
-            log_page,
-            current_block,
-            latest_lsn: 0,
-            last_saved_lsn: 0,
+            state: CondMutex::new(LogState {
+                log_file,
+                first_segment,
+                current_segment,
+                segment_blocks: SEGMENT_BLOCKS,
+                archive_dir: None,
+                backups: 0,
+                log_page,
+                current_block,
+                latest_lsn,
+                last_saved_lsn: latest_lsn,
+                flushing: false,
+                max_flush_delay: Duration::ZERO,
+                flush_count: 0,
+                async_flush_interval: Duration::from_millis(ASYNC_FLUSH_INTERVAL_MILLIS),
+                flusher_started: false,
+            }),
+        })
+    }
+
+    pub fn segment_file(log_file: &str, segment: i32) -> String {
+        format!("{}.{}", log_file, segment)
+    }
+
+    // The numbers of the segments of the log in the directory, in order.
+    // A log file without a segment number is from before segments and checksums, and is refused.
+    pub fn segments(fm: &FileManager, log_file: &str) -> Result<Vec<i32>> {
+        let names = fm.file_names()?;
+        if names.iter().any(|name| name == log_file) {
+            return Err(DbError::UnsupportedFormat(1));
         }
+        let prefix = format!("{}.", log_file);
+        let mut segments: Vec<i32> = names
+            .iter()
+            .filter_map(|name| name.strip_prefix(&prefix)?.parse().ok())
+            .collect();
+        segments.sort();
+        Ok(segments)
+    }
+
+    // The records of the log in the directory from the oldest on, read as they are on disk.
+    // The log is not opened for appending, so nothing is written and a torn tail is left as is.
+    pub fn read_segments(fm: Arc<FileManager>, log_file: &str) -> Result<LogIterator> {
+        let segments = Self::segments(&fm, log_file)?;
+        let (Some(&first_segment), Some(&last_segment)) = (segments.first(), segments.last())
+        else {
+            return Err(DbError::MissingLogSegment(0));
+        };
+        let block = BlockId::new(Self::segment_file(log_file, first_segment), 0);
+        LogIterator::new(
+            fm,
+            log_file.to_string(),
+            first_segment,
+            last_segment,
+            block,
+            Direction::Forward,
+        )
+    }
+
+    // The number of blocks after which the log moves on to a new segment.
+    pub fn set_segment_blocks(&self, blocks: i32) {
+        self.state.lock().segment_blocks = blocks;
+    }
+
+    pub fn set_archive_dir(&self, dir: Option<PathBuf>) {
+        self.state.lock().archive_dir = dir;
+    }
+
+    // The segments still in the log directory.
+    pub fn segment_range(&self) -> std::ops::RangeInclusive<i32> {
+        let state = self.state.lock();
+        state.first_segment..=state.current_segment
     }
 
-    pub fn flush(&mut self, lsn: i32) -> Result<()> {
-        if lsn >= self.last_saved_lsn {
-            // flush
-            let fm = Arc::as_ptr(&self.fm) as *mut FileManager;
-            unsafe {
-                (*fm).write(&self.current_block, &self.log_page)?;
+    // Archive or delete the segments before the current one.
+    // Called right after a checkpoint, when recovery no longer needs the records in them.
+    pub fn remove_old_segments(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        // A backup still needs them; the next checkpoint removes them.
+        if state.backups > 0 {
+            return Ok(());
+        }
+        for segment in state.first_segment..state.current_segment {
+            let filename = Self::segment_file(&state.log_file, segment);
+            match &state.archive_dir {
+                Some(dir) => self.fm.archive(&filename, dir)?,
+                None => self.fm.remove(&filename)?,
             }
-            self.last_saved_lsn = self.latest_lsn;
         }
+        state.first_segment = state.current_segment;
         Ok(())
     }
 
-    pub fn iter(&mut self) -> Result<impl Iterator<Item = Vec<u8>> + '_> {
-        // flush
-        let fm = Arc::as_ptr(&self.fm) as *mut FileManager;
-        unsafe {
-            (*fm).write(&self.current_block, &self.log_page)?;
+    // Keep the segments in the log directory until the returned guard is dropped.
+    pub fn keep_segments(&self) -> KeptSegments<'_> {
+        self.state.lock().backups += 1;
+        KeptSegments { lm: self }
+    }
+
+    // Copy the segments in the log directory into `dir`, with every record appended so far.
+    // Appending waits for the copy, so that the last segment is copied whole.
+    pub fn copy_segments(&self, dir: &Path) -> Result<()> {
+        let state = self.flush_page(self.state.lock())?;
+        for segment in state.first_segment..=state.current_segment {
+            self.fm
+                .copy(&Self::segment_file(&state.log_file, segment), dir)?;
         }
-        self.last_saved_lsn = self.latest_lsn;
+        Ok(())
+    }
+
+    pub fn log_file(&self) -> String {
+        self.state.lock().log_file.clone()
+    }
+
+    pub fn set_max_flush_delay(&self, millis: u64) {
+        self.state.lock().max_flush_delay = Duration::from_millis(millis);
+    }
+
+    pub fn set_async_flush_interval(&self, millis: u64) {
+        self.state.lock().async_flush_interval = Duration::from_millis(millis);
+    }
+
+    pub fn latest_lsn(&self) -> i32 {
+        self.state.lock().latest_lsn
+    }
+
+    pub fn last_saved_lsn(&self) -> i32 {
+        self.state.lock().last_saved_lsn
+    }
 
-        unsafe { Ok(LogIterator::new(&mut *fm, self.current_block.clone())) }
+    // The number of times the log page has been written to disk.
+    pub fn flush_count(&self) -> u64 {
+        self.state.lock().flush_count
     }
 
-    pub fn append(&mut self, log_record: &[u8]) -> Result<i32> {
-        let _lock = self.m.lock().unwrap();
+    // Wait until the record `lsn` is on disk.
+    // The first waiter becomes the flusher, and its write covers every record appended before it.
+    pub fn flush(&self, lsn: i32) -> Result<()> {
+        let mut state = self.state.lock();
+        // Nothing past the latest record can be waited for.
+        let lsn = lsn.min(state.latest_lsn);
+        loop {
+            if lsn <= state.last_saved_lsn {
+                return Ok(());
+            }
+            if state.flushing {
+                state = self.state.wait_timeout(state, MAX_WAIT_TIME_MILLIS as u64);
+                continue;
+            }
 
-        let mut boundary = self.log_page.get_int(0);
-        let bytes_needed = log_record.len() as i32 + INTEGER_BYTES;
-        if boundary - bytes_needed < INTEGER_BYTES {
-            // flush
-            let fm = Arc::as_ptr(&self.fm) as *mut FileManager;
-            unsafe {
-                (*fm).write(&self.current_block, &self.log_page)?;
+            state.flushing = true;
+            let delay = state.max_flush_delay;
+            if !delay.is_zero() {
+                drop(state);
+                thread::sleep(delay);
+                state = self.state.lock();
             }
-            self.last_saved_lsn = self.latest_lsn;
-
-            unsafe {
-                self.current_block = Self::append_new_block(
-                    &mut *fm,
-                    self.current_block.filename(),
-                    &mut self.log_page,
-                )?;
+            let mut page = Page::from(state.log_page.buffer().to_vec());
+            Self::seal(&mut page);
+            let block = state.current_block.clone();
+            let saved_lsn = state.latest_lsn;
+            drop(state);
+
+            let res = self.fm.write(&block, &page);
+
+            state = self.state.lock();
+            state.flushing = false;
+            if res.is_ok() {
+                state.last_saved_lsn = state.last_saved_lsn.max(saved_lsn);
+                state.flush_count += 1;
             }
-            boundary = self.log_page.get_int(0);
+            self.state.notify_all();
+            res?;
+        }
+    }
+
+    // Wait until the record `lsn` is on disk, leaving the write to another flusher.
+    pub fn wait_until_saved(&self, lsn: i32) {
+        let mut state = self.state.lock();
+        while state.last_saved_lsn < lsn.min(state.latest_lsn) {
+            state = self.state.wait_timeout(state, MAX_WAIT_TIME_MILLIS as u64);
+        }
+    }
+
+    // Let the records appended so far reach the disk without waiting for them.
+    // A background flusher, started on first use, flushes the log at regular intervals
+    // and stops once the log manager is dropped.
+    pub fn flush_later(self: &Arc<Self>) {
+        let mut state = self.state.lock();
+        if state.flusher_started {
+            return;
+        }
+        state.flusher_started = true;
+        let lm = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            let Some(interval) = lm.upgrade().map(|lm| lm.state.lock().async_flush_interval) else {
+                return;
+            };
+            thread::sleep(interval);
+            let Some(lm) = lm.upgrade() else {
+                return;
+            };
+            if let Err(e) = lm.flush(lm.latest_lsn()) {
+                warn!(%e, "background log flush failed");
+            }
+        });
+    }
+
+    // The records from the newest to the oldest.
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<Vec<u8>>>> {
+        let records = self.records(Direction::Backward, LogPosition::default())?;
+        Ok(records.map(|res| res.map(|(_, record)| record)))
+    }
+
+    // The records with their LSNs, starting at `lsn`.
+    // Reading forward starts at the oldest block, so a reader that keeps up with the log should use `iter_at`.
+    pub fn iter_from(&self, lsn: i32, direction: Direction) -> Result<LogIterator> {
+        Ok(self
+            .records(direction, LogPosition::default())?
+            .starting_at(lsn))
+    }
+
+    // The records with their LSNs from `lsn` on, reading forward from the block at `position`,
+    // which is where a previous iterator stopped.
+    pub fn iter_at(&self, position: LogPosition, lsn: i32) -> Result<LogIterator> {
+        Ok(self.records(Direction::Forward, position)?.starting_at(lsn))
+    }
+
+    fn records(&self, direction: Direction, from: LogPosition) -> Result<LogIterator> {
+        let state = self.flush_page(self.state.lock())?;
+        let (first_segment, block) = match direction {
+            Direction::Forward => {
+                // A position in a removed segment starts at the oldest record left.
+                let from = Some(from)
+                    .filter(|from| from.segment >= state.first_segment)
+                    .unwrap_or(LogPosition {
+                        segment: state.first_segment,
+                        block: 0,
+                    });
+                let filename = Self::segment_file(&state.log_file, from.segment);
+                (from.segment, BlockId::new(filename, from.block))
+            }
+            Direction::Backward => (state.first_segment, state.current_block.clone()),
+        };
+        LogIterator::new(
+            self.fm.clone(),
+            state.log_file.clone(),
+            first_segment,
+            state.current_segment,
+            block,
+            direction,
+        )
+    }
+
+    pub fn append(&self, log_record: &[u8]) -> Result<i32> {
+        let mut state = self.state.lock();
+
+        let lsn = state.latest_lsn + 1;
+        let mut bytes = lsn.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&Self::record_checksum(lsn, log_record).to_be_bytes());
+        bytes.extend_from_slice(log_record);
+
+        let mut boundary = state.log_page.get_int(0);
+        let bytes_needed = bytes.len() as i32 + INTEGER_BYTES;
+        if boundary - bytes_needed < HEADER_BYTES {
+            state = self.flush_page(state)?;
+            let state = &mut *state;
+            if state.current_block.block_num() + 1 >= state.segment_blocks {
+                state.current_segment += 1;
+            }
+            let filename = Self::segment_file(&state.log_file, state.current_segment);
+            state.current_block = Self::append_new_block(&self.fm, &filename, &mut state.log_page)?;
+            boundary = state.log_page.get_int(0);
         }
 
         let rec_pos = boundary - bytes_needed;
-        self.log_page.set_bytes(rec_pos, log_record);
-        self.log_page.set_int(0, rec_pos);
+        state.log_page.set_bytes(rec_pos, &bytes);
+        state.log_page.set_int(0, rec_pos);
 
-        self.latest_lsn += 1;
-        Ok(self.latest_lsn)
+        state.latest_lsn = lsn;
+        Ok(lsn)
+    }
+
+    // Write the log page while holding the lock.
+    // A flusher writing an older copy of the page must finish first, or it could overwrite this write.
+    fn flush_page<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, LogState>,
+    ) -> Result<MutexGuard<'a, LogState>> {
+        while state.flushing {
+            state = self.state.wait_timeout(state, MAX_WAIT_TIME_MILLIS as u64);
+        }
+        // The page on disk is up to date, and readers leave it untouched.
+        if state.last_saved_lsn == state.latest_lsn {
+            return Ok(state);
+        }
+        Self::seal(&mut state.log_page);
+        self.fm.write(&state.current_block, &state.log_page)?;
+        state.last_saved_lsn = state.latest_lsn;
+        state.flush_count += 1;
+        self.state.notify_all();
+        Ok(state)
     }
 
-    fn append_new_block(
-        fm: &mut FileManager,
-        log_file: &str,
-        log_page: &mut Page,
-    ) -> Result<BlockId> {
+    fn append_new_block(fm: &FileManager, log_file: &str, log_page: &mut Page) -> Result<BlockId> {
         let block = fm.append(log_file)?;
         log_page.set_int(0, fm.block_size());
+        Self::seal(log_page);
         fm.write(&block, log_page)?;
         Ok(block)
     }
+
+    // Store the checksum of the boundary and the records of the block in its header.
+    fn seal(page: &mut Page) {
+        let checksum = Self::block_checksum(page).unwrap();
+        page.set_int(INTEGER_BYTES, checksum as i32);
+    }
+
+    // The checksum of a block, or `None` if its boundary is out of range.
+    pub(super) fn block_checksum(page: &Page) -> Option<u32> {
+        let buf = page.buffer();
+        let boundary = page.get_int(0);
+        if boundary < HEADER_BYTES || boundary as usize > buf.len() {
+            return None;
+        }
+        let mut bytes = buf[..INTEGER_BYTES as usize].to_vec();
+        bytes.extend_from_slice(&buf[boundary as usize..]);
+        Some(crc32(&bytes))
+    }
+
+    pub(super) fn record_checksum(lsn: i32, record: &[u8]) -> u32 {
+        let mut bytes = lsn.to_be_bytes().to_vec();
+        bytes.extend_from_slice(record);
+        crc32(&bytes)
+    }
+}
+
+pub struct KeptSegments<'a> {
+    lm: &'a LogManager,
+}
+
+impl Drop for KeptSegments<'_> {
+    fn drop(&mut self) {
+        self.lm.state.lock().backups -= 1;
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use std::{sync::Barrier, thread};
+
     use crate::server::simple_db::SimpleDB;
-    use std::sync::Arc;
+
+    use super::*;
 
     #[test]
     fn test() {
-        let db = SimpleDB::new("testdata/log/log_manager/test", 20, 8, "tempfile");
-        let lm = Arc::as_ptr(&db.log_manager()) as *mut LogManager;
-        let lm = unsafe { &mut *lm };
+        let db = SimpleDB::new("testdata/log/log_manager/test", 24, 8, "tempfile");
+        let lm = db.log_manager();
+        // a block starts with the boundary and the block crc,
+        // and a record with its length, its lsn and the record crc
         assert_eq!(
-            std::fs::read("testdata/log/log_manager/test/tempfile").unwrap(),
-            vec![0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] // append new block
+            std::fs::read("testdata/log/log_manager/test/tempfile.0").unwrap(),
+            vec![0, 0, 0, 24, 50, 40, 71, 74, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] // append new block
         );
 
         let lsn1 = lm.append(b"abc").unwrap();
         assert_eq!(
-            std::fs::read("testdata/log/log_manager/test/tempfile").unwrap(),
-            vec![0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] // not flushed yet
+            std::fs::read("testdata/log/log_manager/test/tempfile.0").unwrap(),
+            vec![0, 0, 0, 24, 50, 40, 71, 74, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] // not flushed yet
         );
 
         lm.flush(lsn1).unwrap();
         assert_eq!(
-            std::fs::read("testdata/log/log_manager/test/tempfile").unwrap(),
-            vec![0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 97, 98, 99] // flushed
+            std::fs::read("testdata/log/log_manager/test/tempfile.0").unwrap(),
+            vec![
+                0, 0, 0, 9, 175, 76, 233, 202, 0, 0, 0, 0, 11, 0, 0, 0, 1, 239, 181, 32, 203, 97,
+                98, 99
+            ] // flushed
         );
 
         let lsn2 = lm.append(b"def").unwrap();
         assert_eq!(
-            std::fs::read("testdata/log/log_manager/test/tempfile").unwrap(),
-            vec![0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 97, 98, 99] // not flushed yet
+            std::fs::read("testdata/log/log_manager/test/tempfile.0").unwrap(),
+            vec![
+                0, 0, 0, 9, 175, 76, 233, 202, 0, 0, 0, 0, 11, 0, 0, 0, 1, 239, 181, 32, 203, 97,
+                98, 99, 0, 0, 0, 24, 50, 40, 71, 74, 0, 0, 0, 0, 11, 0, 0, 0, 1, 239, 181, 32, 203,
+                97, 98, 99
+            ] // append new block
         );
 
         lm.flush(lsn2).unwrap();
         assert_eq!(
-            std::fs::read("testdata/log/log_manager/test/tempfile").unwrap(),
-            vec![0, 0, 0, 6, 0, 0, 0, 0, 0, 3, 100, 101, 102, 0, 0, 0, 3, 97, 98, 99] // flushed
-        );
-
-        let lsn3 = lm.append(b"ghi").unwrap();
-        assert_eq!(
-            std::fs::read("testdata/log/log_manager/test/tempfile").unwrap(),
+            std::fs::read("testdata/log/log_manager/test/tempfile.0").unwrap(),
             vec![
-                0, 0, 0, 6, 0, 0, 0, 0, 0, 3, 100, 101, 102, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 20,
-                0, 0, 0, 0, 0, 3, 100, 101, 102, 0, 0, 0, 3, 97, 98, 99
-            ] // append new block
+                0, 0, 0, 9, 175, 76, 233, 202, 0, 0, 0, 0, 11, 0, 0, 0, 1, 239, 181, 32, 203, 97,
+                98, 99, 0, 0, 0, 9, 98, 244, 173, 153, 0, 0, 0, 0, 11, 0, 0, 0, 2, 196, 224, 47,
+                134, 100, 101, 102
+            ] // flushed
         );
 
+        let lsn3 = lm.append(b"ghi").unwrap();
         lm.flush(lsn3).unwrap();
         assert_eq!(
-            std::fs::read("testdata/log/log_manager/test/tempfile").unwrap(),
+            &std::fs::read("testdata/log/log_manager/test/tempfile.0").unwrap()[48..],
             vec![
-                0, 0, 0, 6, 0, 0, 0, 0, 0, 3, 100, 101, 102, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 13,
-                0, 0, 0, 0, 0, 3, 100, 101, 102, 0, 0, 0, 3, 103, 104, 105
+                0, 0, 0, 9, 96, 2, 204, 155, 0, 0, 0, 0, 11, 0, 0, 0, 3, 91, 11, 149, 102, 103,
+                104, 105
             ] // flushed
         );
 
         // iterates in reverse order
-        let mut iter = lm.iter().unwrap();
+        let mut iter = lm.iter().unwrap().map(Result::unwrap);
         assert_eq!(iter.next().unwrap(), b"ghi".to_vec());
         assert_eq!(iter.next().unwrap(), b"def".to_vec());
         assert_eq!(iter.next().unwrap(), b"abc".to_vec());
         assert_eq!(iter.next(), None);
+
+        // a restart continues from the latest lsn
+        let lm = LogManager::new(db.file_manager(), "tempfile".to_string()).unwrap();
+        assert_eq!(lm.latest_lsn(), 3);
+        assert_eq!(lm.append(b"jkl").unwrap(), 4);
+    }
+
+    #[test]
+    fn group_commit() {
+        let db = SimpleDB::new("testdata/log/log_manager/group_commit", 400, 16, "templog")
+            .with_max_flush_delay(200);
+        let lm = db.log_manager();
+        let flush_count = lm.flush_count();
+
+        // each transaction changes a block of its own, which is flushed before its commit record
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let db = db.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let block = BlockId::new("tempfile".to_string(), i);
+                    let mut tx = db.new_tx();
+                    tx.pin(&block).unwrap();
+                    tx.set_int(&block, 0, i, true).unwrap();
+                    barrier.wait();
+                    tx.commit().unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // the commits share flushes, for both the pages and the commit records,
+        // instead of taking two each
+        assert!(lm.flush_count() - flush_count <= 4);
+    }
+
+    fn append_all(lm: &LogManager, n: i32) {
+        for i in 0..n {
+            lm.append(format!("r{}", i).as_bytes()).unwrap();
+        }
+    }
+
+    fn records(lm: &LogManager) -> Vec<String> {
+        lm.iter()
+            .unwrap()
+            .map(|bytes| String::from_utf8(bytes.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn iterates_across_segments() {
+        let db = SimpleDB::new(
+            "testdata/log/log_manager/iterates_across_segments",
+            36,
+            8,
+            "tempfile",
+        );
+        let lm = db.log_manager();
+        lm.set_segment_blocks(2);
+
+        // two records in a block, two blocks in a segment
+        append_all(&lm, 10);
+        assert_eq!(lm.segment_range(), 0..=2);
+        assert!(
+            std::fs::exists("testdata/log/log_manager/iterates_across_segments/tempfile.2")
+                .unwrap()
+        );
+
+        let expected: Vec<String> = (0..10).rev().map(|i| format!("r{}", i)).collect();
+        assert_eq!(records(&lm), expected);
+    }
+
+    #[test]
+    fn removes_old_segments() {
+        let dir = "testdata/log/log_manager/removes_old_segments";
+        let db = SimpleDB::new(dir, 36, 8, "tempfile");
+        let lm = db.log_manager();
+        lm.set_segment_blocks(2);
+        append_all(&lm, 10);
+
+        // kept while a backup is in progress
+        let kept = lm.keep_segments();
+        lm.remove_old_segments().unwrap();
+        assert_eq!(lm.segment_range(), 0..=2);
+        drop(kept);
+
+        lm.remove_old_segments().unwrap();
+        assert_eq!(lm.segment_range(), 2..=2);
+        assert!(!std::fs::exists(format!("{}/tempfile.0", dir)).unwrap());
+        assert!(!std::fs::exists(format!("{}/tempfile.1", dir)).unwrap());
+        assert_eq!(records(&lm), vec!["r9", "r8"]);
+
+        // a restart starts from the remaining segments
+        let lm = LogManager::new(db.file_manager(), "tempfile".to_string()).unwrap();
+        assert_eq!(lm.segment_range(), 2..=2);
+        assert_eq!(records(&lm), vec!["r9", "r8"]);
+    }
+
+    #[test]
+    fn refuses_unsegmented_log() {
+        let dir = "testdata/log/log_manager/refuses_unsegmented_log";
+        let db = SimpleDB::new(dir, 36, 8, "simpledb.log");
+        std::fs::write(format!("{}/old.log", dir), [0; 36]).unwrap();
+
+        let err = LogManager::new(db.file_manager(), "old.log".to_string()).err();
+        assert!(matches!(err, Some(DbError::UnsupportedFormat(1))));
+    }
+
+    #[test]
+    fn archives_old_segments() {
+        let dir = "testdata/log/log_manager/archives_old_segments";
+        let db = SimpleDB::new(dir, 36, 8, "tempfile");
+        let lm = db.log_manager();
+        lm.set_segment_blocks(2);
+        lm.set_archive_dir(Some(format!("{}/archive", dir).into()));
+        append_all(&lm, 10);
+
+        lm.remove_old_segments().unwrap();
+        assert!(!std::fs::exists(format!("{}/tempfile.0", dir)).unwrap());
+        assert!(std::fs::exists(format!("{}/archive/tempfile.0", dir)).unwrap());
+        assert!(std::fs::exists(format!("{}/archive/tempfile.1", dir)).unwrap());
+        assert_eq!(records(&lm), vec!["r9", "r8"]);
+    }
+
+    #[test]
+    fn iterates_from_lsn() {
+        let db = SimpleDB::new(
+            "testdata/log/log_manager/iterates_from_lsn",
+            36,
+            8,
+            "tempfile",
+        );
+        let lm = db.log_manager();
+        lm.set_segment_blocks(2);
+        append_all(&lm, 10);
+
+        let forward: Vec<(i32, String)> = lm
+            .iter_from(4, Direction::Forward)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(lsn, bytes)| (lsn, String::from_utf8(bytes).unwrap()))
+            .collect();
+        let expected: Vec<(i32, String)> =
+            (4..=10).map(|lsn| (lsn, format!("r{}", lsn - 1))).collect();
+        assert_eq!(forward, expected);
+
+        let backward: Vec<i32> = lm
+            .iter_from(6, Direction::Backward)
+            .unwrap()
+            .map(|record| record.unwrap().0)
+            .collect();
+        assert_eq!(backward, vec![6, 5, 4, 3, 2, 1]);
+
+        // past the ends
+        assert_eq!(lm.iter_from(11, Direction::Forward).unwrap().count(), 0);
+        assert_eq!(lm.iter_from(0, Direction::Backward).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn iter_at_resumes_at_position() {
+        let dir = "testdata/log/log_manager/iter_at_resumes_at_position";
+        let db = SimpleDB::new(dir, 36, 8, "tempfile");
+        let lm = db.log_manager();
+        lm.set_segment_blocks(2);
+        append_all(&lm, 7);
+
+        let mut iter = lm.iter_from(1, Direction::Forward).unwrap();
+        assert_eq!(iter.by_ref().count(), 7);
+        let position = iter.position();
+        assert_eq!(
+            position,
+            LogPosition {
+                segment: 1,
+                block: 1
+            }
+        );
+
+        // the blocks before the position are not read again
+        corrupt(&format!("{}/tempfile.0", dir), 36 - 1);
+        append_all(&lm, 3);
+        let mut iter = lm.iter_at(position, 8).unwrap();
+        let lsns: Vec<i32> = iter.by_ref().map(|record| record.unwrap().0).collect();
+        assert_eq!(lsns, vec![8, 9, 10]);
+        assert!(!iter.is_corrupt());
+    }
+
+    // Flip a byte of a log file on disk.
+    fn corrupt(path: &str, offset: usize) {
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[offset] ^= 0xff;
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn fails_at_corrupt_record() {
+        let dir = "testdata/log/log_manager/fails_at_corrupt_record";
+        let db = SimpleDB::new(dir, 36, 8, "tempfile");
+        let lm = db.log_manager();
+        // two records in a block, the oldest at the end
+        append_all(&lm, 6);
+        lm.flush(6).unwrap();
+        // the oldest record of the middle block
+        corrupt(&format!("{}/tempfile.0", dir), 2 * 36 - 1);
+
+        let lm = LogManager::new(db.file_manager(), "tempfile".to_string()).unwrap();
+        assert_eq!(lm.latest_lsn(), 6);
+        let mut backward = lm.iter_from(6, Direction::Backward).unwrap();
+        assert_eq!(backward.next().unwrap().unwrap().0, 6);
+        assert_eq!(backward.next().unwrap().unwrap().0, 5);
+        assert!(matches!(backward.next(), Some(Err(DbError::CorruptLog(_)))));
+        assert!(backward.next().is_none());
+        assert!(backward.is_corrupt());
+
+        let mut forward = lm.iter_from(1, Direction::Forward).unwrap();
+        assert_eq!(forward.next().unwrap().unwrap().0, 1);
+        assert_eq!(forward.next().unwrap().unwrap().0, 2);
+        assert!(matches!(forward.next(), Some(Err(DbError::CorruptLog(_)))));
+        assert!(forward.is_corrupt());
+    }
+
+    #[test]
+    fn fails_at_lsn_gap() {
+        let dir = "testdata/log/log_manager/fails_at_lsn_gap";
+        let db = SimpleDB::new(dir, 36, 8, "tempfile");
+        let lm = db.log_manager();
+        append_all(&lm, 6);
+        lm.flush(6).unwrap();
+        // the middle block is replaced by an intact block of other records
+        let path = format!("{}/tempfile.0", dir);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last: Vec<u8> = bytes[72..108].to_vec();
+        bytes[36..72].copy_from_slice(&last);
+        std::fs::write(&path, bytes).unwrap();
+
+        let lm = LogManager::new(db.file_manager(), "tempfile".to_string()).unwrap();
+        let mut backward = lm.iter_from(6, Direction::Backward).unwrap();
+        assert_eq!(backward.next().unwrap().unwrap().0, 6);
+        assert_eq!(backward.next().unwrap().unwrap().0, 5);
+        assert!(matches!(backward.next(), Some(Err(DbError::CorruptLog(_)))));
+    }
+
+    #[test]
+    fn truncates_torn_tail() {
+        let dir = "testdata/log/log_manager/truncates_torn_tail";
+        let db = SimpleDB::new(dir, 36, 8, "tempfile");
+        let lm = db.log_manager();
+        append_all(&lm, 6);
+        lm.flush(6).unwrap();
+        // the newest record, right after the header of the last block
+        corrupt(&format!("{}/tempfile.0", dir), 2 * 36 + 21);
+
+        let lm = LogManager::new(db.file_manager(), "tempfile".to_string()).unwrap();
+        assert_eq!(lm.latest_lsn(), 5);
+        assert_eq!(records(&lm), vec!["r4", "r3", "r2", "r1", "r0"]);
+
+        // the records appended next are not hidden by the torn one
+        let lsn = lm.append(b"r5").unwrap();
+        assert_eq!(lsn, 6);
+        lm.flush(lsn).unwrap();
+        let lm = LogManager::new(db.file_manager(), "tempfile".to_string()).unwrap();
+        assert_eq!(lm.latest_lsn(), 6);
+        assert_eq!(records(&lm), vec!["r5", "r4", "r3", "r2", "r1", "r0"]);
+    }
+
+    #[test]
+    fn reads_intact_records_of_torn_block() {
+        let dir = "testdata/log/log_manager/reads_intact_records_of_torn_block";
+        let db = SimpleDB::new(dir, 36, 8, "tempfile");
+        let lm = db.log_manager();
+        append_all(&lm, 2);
+        lm.flush(2).unwrap();
+        // the block crc no longer matches, but the record crcs do
+        corrupt(&format!("{}/tempfile.0", dir), 4);
+
+        let lm = LogManager::new(db.file_manager(), "tempfile".to_string()).unwrap();
+        assert_eq!(records(&lm), vec!["r1", "r0"]);
     }
 }
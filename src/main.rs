@@ -1,13 +1,3 @@
-mod buffer;
-mod file;
-mod log;
-mod query;
-mod record;
-mod server;
-mod sql;
-mod tx;
-mod util;
-
 fn main() {
     println!("Hello, world!");
 }
@@ -94,6 +94,21 @@ impl Constant {
             _ => None,
         }
     }
+
+    // The value as JSON, with dates and times as strings.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Constant::Integer(i) => serde_json::json!(i),
+            Constant::Double(d) => serde_json::json!(d),
+            Constant::Bytes(b) => serde_json::json!(b),
+            Constant::String(s) => serde_json::json!(s),
+            Constant::Bool(b) => serde_json::json!(b),
+            Constant::Date(d) => serde_json::json!(d.to_string()),
+            Constant::Time(t) => serde_json::json!(t.to_string()),
+            Constant::DateTime(dt) => serde_json::json!(dt.to_rfc3339()),
+            Constant::Json(j) => j.clone(),
+        }
+    }
 }
 
 impl From<i32> for Constant {
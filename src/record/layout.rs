@@ -11,6 +11,9 @@ use crate::{
 use super::schema::Schema;
 
 pub const IS_USED_FLAG_NAME: &str = "SECRET_INTERNALS_IS_USED_FLAG";
+// Each slot starts with the flags, followed by the transactions that created and deleted the record.
+pub const XMIN_OFFSET: i32 = INTEGER_BYTES;
+pub const XMAX_OFFSET: i32 = 2 * INTEGER_BYTES;
 
 pub struct Layout {
     schema: Schema,
@@ -71,14 +74,12 @@ impl From<Schema> for Layout {
         let mut flag_bit_location: HashMap<String, i32> = HashMap::new();
         flag_bit_location.insert(IS_USED_FLAG_NAME.to_string(), 0);
         let mut layout = Layout::new(schema.clone(), HashMap::new(), flag_bit_location, 0);
-        let mut pos = INTEGER_BYTES;
-        let mut loc = 1;
-        for field_name in schema.fields() {
+        let mut pos = XMAX_OFFSET + INTEGER_BYTES;
+        for (loc, field_name) in (1..).zip(schema.fields()) {
             layout.offsets.insert(field_name.clone(), pos);
             pos += layout.length_in_bytes(field_name).unwrap();
 
             layout.flag_bit_location.insert(field_name.clone(), loc);
-            loc += 1;
         }
         layout.slot_size = pos;
         layout
@@ -105,15 +106,15 @@ mod tests {
 
         let layout = Layout::from(schema);
 
-        assert_eq!(*layout.offset("int").unwrap(), 4);
-        assert_eq!(*layout.offset("double").unwrap(), 8);
-        assert_eq!(*layout.offset("bytes").unwrap(), 16);
-        assert_eq!(*layout.offset("string").unwrap(), 120);
-        assert_eq!(*layout.offset("boolean").unwrap(), 524);
-        assert_eq!(*layout.offset("date").unwrap(), 525);
-        assert_eq!(*layout.offset("time").unwrap(), 531);
-        assert_eq!(*layout.offset("datetime").unwrap(), 538);
-        assert_eq!(*layout.offset("json").unwrap(), 553);
-        assert_eq!(layout.slot_size(), 957);
+        assert_eq!(*layout.offset("int").unwrap(), 12);
+        assert_eq!(*layout.offset("double").unwrap(), 16);
+        assert_eq!(*layout.offset("bytes").unwrap(), 24);
+        assert_eq!(*layout.offset("string").unwrap(), 128);
+        assert_eq!(*layout.offset("boolean").unwrap(), 532);
+        assert_eq!(*layout.offset("date").unwrap(), 533);
+        assert_eq!(*layout.offset("time").unwrap(), 539);
+        assert_eq!(*layout.offset("datetime").unwrap(), 546);
+        assert_eq!(*layout.offset("json").unwrap(), 561);
+        assert_eq!(layout.slot_size(), 965);
     }
 }
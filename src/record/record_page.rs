@@ -1,125 +1,155 @@
 #![allow(dead_code)]
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use crate::{
+    error::DbError,
     file::{block_id::BlockId, page::Page},
     sql::ColumnType,
     tx::transaction::Transaction,
     util::Result,
 };
 
-use super::layout::{Layout, IS_USED_FLAG_NAME};
+use super::layout::{Layout, IS_USED_FLAG_NAME, XMAX_OFFSET, XMIN_OFFSET};
 
-const EMPTY: bool = false;
 const USED: bool = true;
 
-pub struct RecordPage<'a> {
-    tx: Arc<Transaction<'a>>,
+// The version of a record a transaction sees.
+enum Version {
+    // The one in the block.
+    Current,
+    // An older one, kept as an image of the slot.
+    Old(Page),
+    Invisible,
+}
+
+pub struct RecordPage {
+    tx: Arc<Mutex<Transaction>>,
     block: BlockId,
     layout: Arc<Layout>,
 }
 
-impl<'a> RecordPage<'a> {
-    pub fn new(tx: Arc<Transaction<'a>>, block: BlockId, layout: Arc<Layout>) -> Result<Self> {
+impl RecordPage {
+    pub fn new(tx: Arc<Mutex<Transaction>>, block: BlockId, layout: Arc<Layout>) -> Result<Self> {
         let record = Self { tx, block, layout };
-        let tx = Arc::as_ptr(&record.tx) as *mut Transaction;
-        unsafe {
-            (*tx).pin(&record.block)?;
-        }
+        record.tx().pin(&record.block)?;
         Ok(record)
     }
 
     pub fn get_int(&mut self, slot: i32, field_name: &str) -> Result<i32> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe { (*tx).get_int(&self.block, field_pos) }
+        let offset = self.field_offset(field_name)?;
+        self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_int(block, pos),
+            |page, pos| page.get_int(pos),
+        )
     }
     pub fn set_int(&mut self, slot: i32, field_name: &str, value: i32) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_int(&self.block, field_pos, value, true)?;
-        }
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
+        self.tx().set_int(&self.block, field_pos, value, true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
 
     pub fn get_double(&mut self, slot: i32, field_name: &str) -> Result<f64> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe { (*tx).get_double(&self.block, field_pos) }
+        let offset = self.field_offset(field_name)?;
+        self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_double(block, pos),
+            |page, pos| page.get_double(pos),
+        )
     }
     pub fn set_double(&mut self, slot: i32, field_name: &str, value: f64) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_double(&self.block, field_pos, value, true)?;
-        }
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
+        self.tx().set_double(&self.block, field_pos, value, true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
 
     pub fn get_bytes(&mut self, slot: i32, field_name: &str) -> Result<Vec<u8>> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe { (*tx).get_bytes(&self.block, field_pos) }
+        let offset = self.field_offset(field_name)?;
+        self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_bytes(block, pos),
+            |page, pos| page.get_bytes(pos),
+        )
     }
     pub fn set_bytes(&mut self, slot: i32, field_name: &str, value: &[u8]) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
 
         if Page::bytes_len(value) > self.layout.length_in_bytes(field_name).unwrap() {
-            return Err("bytes too long".into());
+            return Err(DbError::ValueTooLong(field_name.to_string()));
         }
 
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_bytes(&self.block, field_pos, value, true)?;
-        }
+        self.tx().set_bytes(&self.block, field_pos, value, true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
 
     pub fn get_string(&mut self, slot: i32, field_name: &str) -> Result<String> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe { (*tx).get_string(&self.block, field_pos) }
+        let offset = self.field_offset(field_name)?;
+        self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_string(block, pos),
+            |page, pos| page.get_string(pos),
+        )
     }
     pub fn set_string(&mut self, slot: i32, field_name: &str, value: &str) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
 
         if Page::str_len(value) > self.layout.length_in_bytes(field_name).unwrap() {
-            return Err("string too long".into());
+            return Err(DbError::ValueTooLong(field_name.to_string()));
         }
 
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_string(&self.block, field_pos, value, true)?;
-        }
+        self.tx().set_string(&self.block, field_pos, value, true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
 
     pub fn get_bool(&mut self, slot: i32, field_name: &str) -> Result<bool> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe { (*tx).get_bool(&self.block, field_pos) }
+        let offset = self.field_offset(field_name)?;
+        self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_bool(block, pos),
+            |page, pos| page.get_bool(pos),
+        )
     }
     pub fn set_bool(&mut self, slot: i32, field_name: &str, value: bool) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_bool(&self.block, field_pos, value, true)?;
-        }
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
+        self.tx().set_bool(&self.block, field_pos, value, true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
 
     pub fn get_date(&mut self, slot: i32, field_name: &str) -> Result<chrono::NaiveDate> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        let date = unsafe { (*tx).get_date(&self.block, field_pos) };
-        date.and_then(|d| d.ok_or("invalid date".into()))
+        let offset = self.field_offset(field_name)?;
+        let date = self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_date(block, pos),
+            |page, pos| page.get_date(pos),
+        );
+        date.and_then(|d| d.ok_or(DbError::InvalidValue("date")))
     }
     pub fn set_date(
         &mut self,
@@ -127,20 +157,25 @@ impl<'a> RecordPage<'a> {
         field_name: &str,
         value: chrono::NaiveDate,
     ) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_date(&self.block, field_pos, &Some(value), true)?;
-        }
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
+        self.tx()
+            .set_date(&self.block, field_pos, &Some(value), true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
 
     pub fn get_time(&mut self, slot: i32, field_name: &str) -> Result<chrono::NaiveTime> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        let time = unsafe { (*tx).get_time(&self.block, field_pos) };
-        time.and_then(|t| t.ok_or("invalid time".into()))
+        let offset = self.field_offset(field_name)?;
+        let time = self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_time(block, pos),
+            |page, pos| page.get_time(pos),
+        );
+        time.and_then(|t| t.ok_or(DbError::InvalidValue("time")))
     }
     pub fn set_time(
         &mut self,
@@ -148,11 +183,12 @@ impl<'a> RecordPage<'a> {
         field_name: &str,
         value: chrono::NaiveTime,
     ) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_time(&self.block, field_pos, &Some(value), true)?;
-        }
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
+        self.tx()
+            .set_time(&self.block, field_pos, &Some(value), true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
@@ -162,10 +198,14 @@ impl<'a> RecordPage<'a> {
         slot: i32,
         field_name: &str,
     ) -> Result<chrono::DateTime<chrono::FixedOffset>> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        let datetime = unsafe { (*tx).get_datetime(&self.block, field_pos) };
-        datetime.and_then(|dt| dt.ok_or("invalid datetime".into()))
+        let offset = self.field_offset(field_name)?;
+        let datetime = self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_datetime(block, pos),
+            |page, pos| page.get_datetime(pos),
+        );
+        datetime.and_then(|dt| dt.ok_or(DbError::InvalidValue("datetime")))
     }
     pub fn set_datetime(
         &mut self,
@@ -173,20 +213,25 @@ impl<'a> RecordPage<'a> {
         field_name: &str,
         value: chrono::DateTime<chrono::FixedOffset>,
     ) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_datetime(&self.block, field_pos, &Some(value), true)?;
-        }
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
+        self.tx()
+            .set_datetime(&self.block, field_pos, &Some(value), true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
 
     pub fn get_json(&mut self, slot: i32, field_name: &str) -> Result<serde_json::Value> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        let json = unsafe { (*tx).get_json(&self.block, field_pos) };
-        json.and_then(|j| j.ok_or("invalid json".into()))
+        let offset = self.field_offset(field_name)?;
+        let json = self.read(
+            slot,
+            offset,
+            |tx, block, pos| tx.get_json(block, pos),
+            |page, pos| page.get_json(pos),
+        );
+        json.and_then(|j| j.ok_or(DbError::InvalidValue("json")))
     }
     pub fn set_json(
         &mut self,
@@ -194,16 +239,17 @@ impl<'a> RecordPage<'a> {
         field_name: &str,
         value: &serde_json::Value,
     ) -> Result<()> {
-        let field_pos = self.field_pos(slot, field_name).ok_or("field not found")?;
+        let field_pos = self
+            .field_pos(slot, field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
+        self.prepare_update(slot)?;
 
         if Page::json_len(&Some(value.clone())) > self.layout.length_in_bytes(field_name).unwrap() {
-            return Err("json too long".into());
+            return Err(DbError::ValueTooLong(field_name.to_string()));
         }
 
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe {
-            (*tx).set_json(&self.block, field_pos, &Some(value.clone()), true)?;
-        }
+        self.tx()
+            .set_json(&self.block, field_pos, &Some(value.clone()), true)?;
         self._set_null(slot, field_name, false)?;
         Ok(())
     }
@@ -218,49 +264,55 @@ impl<'a> RecordPage<'a> {
         self.set_flag(slot, field_name, null)
     }
 
+    // The record is only marked as deleted, and stays visible to the snapshots that do not see the deletion.
+    // Its slot is reused once every transaction sees it.
     pub fn delete(&mut self, slot: i32) -> Result<()> {
-        self.set_is_used_flag(slot, EMPTY)
+        self.check_write(slot)?;
+        let offset = self.offset(slot);
+        let mut tx = self.tx();
+        let tx_num = tx.tx_num();
+        tx.set_int(&self.block, offset + XMAX_OFFSET, tx_num, true)
     }
 
     pub fn format(&mut self) -> Result<()> {
         let mut slot = 0;
         while self.is_valid_slot(slot) {
-            let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-            unsafe {
-                (*tx).set_int(&self.block, self.offset(slot), 0, false)?;
-            }
+            let mut tx = self.tx();
+            tx.set_int(&self.block, self.offset(slot), 0, false)?;
+            tx.set_int(&self.block, self.offset(slot) + XMIN_OFFSET, 0, false)?;
+            tx.set_int(&self.block, self.offset(slot) + XMAX_OFFSET, 0, false)?;
             let schema = self.layout.schema();
             for field_name in schema.fields() {
                 let field_pos = self.field_pos(slot, field_name).unwrap();
                 let column_type = schema.column_type(field_name).unwrap();
                 match column_type {
-                    ColumnType::Integer => unsafe {
-                        (*tx).set_int(&self.block, field_pos, 0, false)?;
-                    },
-                    ColumnType::Double => unsafe {
-                        (*tx).set_double(&self.block, field_pos, 0.0, false)?;
-                    },
-                    ColumnType::VarBit => unsafe {
-                        (*tx).set_bytes(&self.block, field_pos, &[], false)?;
-                    },
-                    ColumnType::VarChar => unsafe {
-                        (*tx).set_string(&self.block, field_pos, "", false)?;
-                    },
-                    ColumnType::Boolean => unsafe {
-                        (*tx).set_bool(&self.block, field_pos, false, false)?;
-                    },
-                    ColumnType::Date => unsafe {
-                        (*tx).set_date(&self.block, field_pos, &None, false)?;
-                    },
-                    ColumnType::Time => unsafe {
-                        (*tx).set_time(&self.block, field_pos, &None, false)?;
-                    },
-                    ColumnType::DateTime => unsafe {
-                        (*tx).set_datetime(&self.block, field_pos, &None, false)?;
-                    },
-                    ColumnType::Json => unsafe {
-                        (*tx).set_json(&self.block, field_pos, &None, false)?;
-                    },
+                    ColumnType::Integer => {
+                        tx.set_int(&self.block, field_pos, 0, false)?;
+                    }
+                    ColumnType::Double => {
+                        tx.set_double(&self.block, field_pos, 0.0, false)?;
+                    }
+                    ColumnType::VarBit => {
+                        tx.set_bytes(&self.block, field_pos, &[], false)?;
+                    }
+                    ColumnType::VarChar => {
+                        tx.set_string(&self.block, field_pos, "", false)?;
+                    }
+                    ColumnType::Boolean => {
+                        tx.set_bool(&self.block, field_pos, false, false)?;
+                    }
+                    ColumnType::Date => {
+                        tx.set_date(&self.block, field_pos, &None, false)?;
+                    }
+                    ColumnType::Time => {
+                        tx.set_time(&self.block, field_pos, &None, false)?;
+                    }
+                    ColumnType::DateTime => {
+                        tx.set_datetime(&self.block, field_pos, &None, false)?;
+                    }
+                    ColumnType::Json => {
+                        tx.set_json(&self.block, field_pos, &None, false)?;
+                    }
                 };
             }
             slot += 1;
@@ -268,16 +320,32 @@ impl<'a> RecordPage<'a> {
         Ok(())
     }
 
+    // The next slot holding a record visible to the transaction.
     pub fn next_after(&mut self, slot: i32) -> Result<i32> {
-        self.search_after(slot, USED)
+        let mut next_slot = slot + 1;
+        while self.is_valid_slot(next_slot) {
+            if !matches!(self.version(next_slot)?, Version::Invisible) {
+                return Ok(next_slot);
+            }
+            next_slot += 1;
+        }
+        Ok(-1)
     }
 
     pub fn insert_after(&mut self, slot: i32) -> Result<i32> {
-        let new_slot = self.search_after(slot, EMPTY)?;
-        if new_slot >= 0 {
-            self.set_is_used_flag(new_slot, USED)?;
+        let mut next_slot = slot + 1;
+        while self.is_valid_slot(next_slot) {
+            // Another transaction may take the slot before it is locked.
+            if self.is_free(next_slot)? {
+                self.x_lock(next_slot)?;
+                if self.is_free(next_slot)? {
+                    self.claim(next_slot)?;
+                    return Ok(next_slot);
+                }
+            }
+            next_slot += 1;
         }
-        Ok(new_slot)
+        Ok(-1)
     }
 
     pub fn block(&self) -> &BlockId {
@@ -288,55 +356,177 @@ impl<'a> RecordPage<'a> {
         Some(self.offset(slot) + self.layout.offset(field_name)?)
     }
 
-    fn set_is_used_flag(&mut self, slot: i32, flag: bool) -> Result<()> {
-        self.set_flag(slot, IS_USED_FLAG_NAME, flag)
+    fn field_offset(&self, field_name: &str) -> Result<i32> {
+        Ok(*self
+            .layout
+            .offset(field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?)
     }
 
-    fn search_after(&mut self, slot: i32, flag: bool) -> Result<i32> {
-        let mut next_slot = slot + 1;
-        while self.is_valid_slot(next_slot) {
-            if self.get_flag(next_slot, IS_USED_FLAG_NAME)? == flag {
-                return Ok(next_slot);
+    // Read a value at `offset` in a slot,
+    // from the block or from an older image of the slot, depending on which version the transaction sees.
+    fn read<T>(
+        &mut self,
+        slot: i32,
+        offset: i32,
+        current: impl FnOnce(&mut Transaction, &BlockId, i32) -> Result<T>,
+        old: impl FnOnce(&Page, i32) -> T,
+    ) -> Result<T> {
+        self.s_lock(slot)?;
+        if self.tx().is_snapshot_isolation() {
+            match self.version(slot)? {
+                Version::Current => {}
+                Version::Old(image) => return Ok(old(&image, offset)),
+                Version::Invisible => return Err(DbError::RecordNotVisible),
             }
-            next_slot += 1;
         }
-        Ok(-1)
+        let pos = self.offset(slot) + offset;
+        current(&mut self.tx(), &self.block, pos)
     }
 
-    pub fn get_flag(&mut self, slot: i32, flag_name: &str) -> Result<bool> {
+    // The version of the record in the slot that the transaction sees.
+    // Locking reads see the latest version, which is committed or their own once locked.
+    fn version(&mut self, slot: i32) -> Result<Version> {
+        self.s_lock(slot)?;
         let offset = self.offset(slot);
-        let null_bit_location = self
-            .layout
-            .flag_bit_location(flag_name)
-            .ok_or("flag not found")?;
+        let mut tx = self.tx();
+        tx.mark_read(&self.block, slot);
+        let flags = tx.get_int(&self.block, offset)?;
+        let xmin = tx.get_int(&self.block, offset + XMIN_OFFSET)?;
+        let xmax = tx.get_int(&self.block, offset + XMAX_OFFSET)?;
+        if !tx.is_snapshot_isolation() || tx.snapshot().is_visible(xmin) {
+            return Ok(if self.is_live(&tx, flags, xmax) {
+                Version::Current
+            } else {
+                Version::Invisible
+            });
+        }
+        match tx.find_version(&self.block, slot) {
+            Some(image) if self.is_live(&tx, image.get_int(0), image.get_int(XMAX_OFFSET)) => {
+                Ok(Version::Old(image))
+            }
+            _ => Ok(Version::Invisible),
+        }
+    }
+
+    fn is_live(&self, tx: &Transaction, flags: i32, xmax: i32) -> bool {
+        let used_bit = *self.layout.flag_bit_location(IS_USED_FLAG_NAME).unwrap();
+        let deleted = xmax != 0 && (!tx.is_snapshot_isolation() || tx.snapshot().is_visible(xmax));
+        flags & (1 << used_bit) != 0 && !deleted
+    }
+
+    // Whether a new record can be put in the slot:
+    // it is empty, or its record was deleted and no transaction sees it anymore.
+    fn is_free(&mut self, slot: i32) -> Result<bool> {
+        let offset = self.offset(slot);
+        let used = self.get_flag(slot, IS_USED_FLAG_NAME)?;
+        let mut tx = self.tx();
+        let xmax = tx.get_int(&self.block, offset + XMAX_OFFSET)?;
+        Ok(!used || (xmax != 0 && tx.is_settled(xmax)))
+    }
+
+    // Put a new record created by the transaction in a locked free slot.
+    // The creator is set before the slot is marked as used, so that no snapshot sees a half-made record.
+    fn claim(&mut self, slot: i32) -> Result<()> {
+        let offset = self.offset(slot);
+        {
+            let mut tx = self.tx();
+            tx.forget_versions(&self.block, slot);
+            tx.mark_write(&self.block, slot);
+            let tx_num = tx.tx_num();
+            tx.set_int(&self.block, offset + XMIN_OFFSET, tx_num, true)?;
+            tx.set_int(&self.block, offset + XMAX_OFFSET, 0, true)?;
+        }
+        self.write_flag(slot, IS_USED_FLAG_NAME, USED)
+    }
+
+    // Lock the record for writing.
+    // Under snapshot isolation, the first updater wins:
+    // a record changed by a transaction the snapshot does not see cannot be changed again.
+    fn check_write(&mut self, slot: i32) -> Result<()> {
+        self.x_lock(slot)?;
+        let offset = self.offset(slot);
+        let mut tx = self.tx();
+        tx.mark_write(&self.block, slot);
+        if !tx.is_snapshot_isolation() {
+            return Ok(());
+        }
+        let tx_num = tx.tx_num();
+        let xmin = tx.get_int(&self.block, offset + XMIN_OFFSET)?;
+        let xmax = tx.get_int(&self.block, offset + XMAX_OFFSET)?;
+        if !tx.snapshot().is_visible(xmin) || (xmax != 0 && xmax != tx_num) {
+            return Err(DbError::ConcurrentUpdate);
+        }
+        Ok(())
+    }
 
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        let flag_bits = unsafe { (*tx).get_int(&self.block, offset)? };
+    // Lock the record and make the transaction the creator of its current version,
+    // keeping the previous version for the snapshots that do not see the update.
+    fn prepare_update(&mut self, slot: i32) -> Result<()> {
+        self.check_write(slot)?;
+        let offset = self.offset(slot);
+        let mut tx = self.tx();
+        let tx_num = tx.tx_num();
+        if tx.get_int(&self.block, offset + XMIN_OFFSET)? == tx_num {
+            return Ok(());
+        }
+        tx.save_version(&self.block, slot, offset, self.layout.slot_size());
+        tx.set_int(&self.block, offset + XMIN_OFFSET, tx_num, true)
+    }
 
-        Ok((flag_bits & (1 << null_bit_location)) != 0)
+    pub fn get_flag(&mut self, slot: i32, flag_name: &str) -> Result<bool> {
+        let flag_bit_location = *self
+            .layout
+            .flag_bit_location(flag_name)
+            .ok_or_else(|| DbError::UnknownFlag(flag_name.to_string()))?;
+        let flag_bits = self.read(
+            slot,
+            0,
+            |tx, block, pos| tx.get_int(block, pos),
+            |page, pos| page.get_int(pos),
+        )?;
+        Ok((flag_bits & (1 << flag_bit_location)) != 0)
     }
     fn set_flag(&mut self, slot: i32, flag_name: &str, flag: bool) -> Result<()> {
+        self.prepare_update(slot)?;
+        self.write_flag(slot, flag_name, flag)
+    }
+    fn write_flag(&mut self, slot: i32, flag_name: &str, flag: bool) -> Result<()> {
         let offset = self.offset(slot);
         let flag_bit_location = self
             .layout
             .flag_bit_location(flag_name)
-            .ok_or("flag not found")?;
+            .ok_or_else(|| DbError::UnknownFlag(flag_name.to_string()))?;
 
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        let mut flag_bits = unsafe { (*tx).get_int(&self.block, offset)? };
+        let mut tx = self.tx();
+        let mut flag_bits = tx.get_int(&self.block, offset)?;
         if flag {
             flag_bits |= 1 << flag_bit_location;
         } else {
             flag_bits &= !(1 << flag_bit_location);
         }
-        unsafe { (*tx).set_int(&self.block, offset, flag_bits, true) }
+        tx.set_int(&self.block, offset, flag_bits, true)
+    }
+
+    fn s_lock(&mut self, slot: i32) -> Result<()> {
+        self.tx()
+            .s_lock_record(&self.block, slot, self.layout.slot_size())
+    }
+
+    fn x_lock(&mut self, slot: i32) -> Result<()> {
+        self.tx()
+            .x_lock_record(&self.block, slot, self.layout.slot_size())
     }
 
     fn is_valid_slot(&self, slot: i32) -> bool {
-        self.offset(slot + 1) <= self.tx.block_size()
+        self.offset(slot + 1) <= self.tx().block_size()
     }
 
     fn offset(&self, slot: i32) -> i32 {
         self.layout.slot_size() * slot
     }
+
+    fn tx(&self) -> MutexGuard<'_, Transaction> {
+        self.tx.lock().unwrap()
+    }
 }
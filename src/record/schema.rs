@@ -25,6 +25,12 @@ pub struct Schema {
     info: HashMap<String, FieldInfo>,
 }
 
+impl Default for Schema {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Schema {
     pub fn new() -> Self {
         Self {
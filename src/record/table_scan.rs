@@ -1,8 +1,9 @@
 #![allow(dead_code)]
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::{
+    error::DbError,
     file::block_id::BlockId,
     query::{constant::Constant, scan::Scan, update_scan::UpdateScan},
     sql::ColumnType,
@@ -12,16 +13,16 @@ use crate::{
 
 use super::{layout::Layout, record_page::RecordPage, rid::Rid};
 
-pub struct TableScan<'a> {
-    tx: Arc<Transaction<'a>>,
+pub struct TableScan {
+    tx: Arc<Mutex<Transaction>>,
     layout: Arc<Layout>,
-    rp: Option<RecordPage<'a>>,
+    rp: Option<RecordPage>,
     filename: String,
     current_slot: i32,
 }
 
-impl<'a> TableScan<'a> {
-    pub fn new(tx: Arc<Transaction<'a>>, table_name: &str, layout: Arc<Layout>) -> Result<Self> {
+impl TableScan {
+    pub fn new(tx: Arc<Mutex<Transaction>>, table_name: &str, layout: Arc<Layout>) -> Result<Self> {
         let mut scan = Self {
             tx,
             layout,
@@ -29,8 +30,8 @@ impl<'a> TableScan<'a> {
             filename: format!("{}.tbl", table_name),
             current_slot: 0,
         };
-        let tx = Arc::as_ptr(&scan.tx) as *mut Transaction;
-        if unsafe { (*tx).size(&scan.filename)? == 0 } {
+        let size = scan.tx.lock().unwrap().size(&scan.filename)?;
+        if size == 0 {
             scan.move_to_new_block()?;
         } else {
             scan.move_to_block(0)?;
@@ -39,7 +40,7 @@ impl<'a> TableScan<'a> {
     }
 }
 
-impl<'a> Scan for TableScan<'a> {
+impl Scan for TableScan {
     fn before_first(&mut self) -> Result<()> {
         self.move_to_block(0)
     }
@@ -120,13 +121,11 @@ impl<'a> Scan for TableScan<'a> {
     }
 
     fn get_value(&mut self, field_name: &str) -> Result<Constant> {
-        let layout = Arc::as_ptr(&self.layout);
-        let column_type = unsafe {
-            (*layout)
-                .schema()
-                .column_type(field_name)
-                .ok_or("field not found")?
-        };
+        let column_type = self
+            .layout
+            .schema()
+            .column_type(field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
         match column_type {
             ColumnType::Integer => Ok(Constant::from(self.get_int(field_name)?)),
             ColumnType::Double => Ok(Constant::from(self.get_double(field_name)?)),
@@ -148,47 +147,78 @@ impl<'a> Scan for TableScan<'a> {
     }
 
     fn has_field(&self, field_name: &str) -> bool {
-        let layout = Arc::as_ptr(&self.layout);
-        unsafe { (*layout).schema().has_field(field_name) }
+        self.layout.schema().has_field(field_name)
     }
 
     fn close(&self) {
-        if self.rp.is_some() {
-            let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-            unsafe { (*tx).unpin(self.rp.as_ref().unwrap().block()) };
+        if let Some(rp) = &self.rp {
+            self.tx.lock().unwrap().unpin(rp.block());
         }
     }
 }
 
-impl<'a> UpdateScan for TableScan<'a> {
+impl UpdateScan for TableScan {
     fn set_value(&mut self, field_name: &str, value: Constant) -> Result<()> {
-        let layout = Arc::as_ptr(&self.layout);
-        let column_type = unsafe {
-            (*layout)
-                .schema()
-                .column_type(field_name)
-                .ok_or("field not found")?
-        };
+        let column_type = self
+            .layout
+            .schema()
+            .column_type(field_name)
+            .ok_or_else(|| DbError::UnknownField(field_name.to_string()))?;
         match column_type {
-            ColumnType::Integer => self.set_int(field_name, value.as_int().ok_or("invalid value")?),
-            ColumnType::Double => {
-                self.set_double(field_name, value.as_double().ok_or("invalid value")?)
-            }
-            ColumnType::VarBit => {
-                self.set_bytes(field_name, value.as_bytes().ok_or("invalid value")?)
-            }
-            ColumnType::VarChar => {
-                self.set_string(field_name, value.as_string().ok_or("invalid value")?)
-            }
-            ColumnType::Boolean => {
-                self.set_boolean(field_name, value.as_boolean().ok_or("invalid value")?)
-            }
-            ColumnType::Date => self.set_date(field_name, value.as_date().ok_or("invalid value")?),
-            ColumnType::Time => self.set_time(field_name, value.as_time().ok_or("invalid value")?),
-            ColumnType::DateTime => {
-                self.set_datetime(field_name, value.as_datetime().ok_or("invalid value")?)
-            }
-            ColumnType::Json => self.set_json(field_name, value.as_json().ok_or("invalid value")?),
+            ColumnType::Integer => self.set_int(
+                field_name,
+                value
+                    .as_int()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
+            ColumnType::Double => self.set_double(
+                field_name,
+                value
+                    .as_double()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
+            ColumnType::VarBit => self.set_bytes(
+                field_name,
+                value
+                    .as_bytes()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
+            ColumnType::VarChar => self.set_string(
+                field_name,
+                value
+                    .as_string()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
+            ColumnType::Boolean => self.set_boolean(
+                field_name,
+                value
+                    .as_boolean()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
+            ColumnType::Date => self.set_date(
+                field_name,
+                value
+                    .as_date()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
+            ColumnType::Time => self.set_time(
+                field_name,
+                value
+                    .as_time()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
+            ColumnType::DateTime => self.set_datetime(
+                field_name,
+                value
+                    .as_datetime()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
+            ColumnType::Json => self.set_json(
+                field_name,
+                value
+                    .as_json()
+                    .ok_or_else(|| DbError::TypeMismatch(field_name.to_string()))?,
+            ),
         };
         Ok(())
     }
@@ -322,7 +352,7 @@ impl<'a> UpdateScan for TableScan<'a> {
     }
 }
 
-impl<'a> TableScan<'a> {
+impl TableScan {
     fn move_to_block(&mut self, block_num: i32) -> Result<()> {
         self.close();
         let block = BlockId::new(self.filename.clone(), block_num);
@@ -337,8 +367,7 @@ impl<'a> TableScan<'a> {
 
     fn move_to_new_block(&mut self) -> Result<()> {
         self.close();
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        let block = unsafe { (*tx).append(&self.filename)? };
+        let block = self.tx.lock().unwrap().append(&self.filename)?;
         self.rp = Some(RecordPage::new(
             self.tx.clone(),
             block,
@@ -351,21 +380,25 @@ impl<'a> TableScan<'a> {
 
     fn at_last_block(&mut self) -> Result<bool> {
         let block_num = self.rp.as_ref().unwrap().block().block_num();
-        let tx = Arc::as_ptr(&self.tx) as *mut Transaction;
-        unsafe { Ok(block_num == (*tx).size(&self.filename)? - 1) }
+        let size = self.tx.lock().unwrap().size(&self.filename)?;
+        Ok(block_num == size - 1)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{record::schema::Schema, server::simple_db::SimpleDB};
+    use crate::{
+        record::{record_page::RecordPage, schema::Schema},
+        server::simple_db::SimpleDB,
+        tx::tx_options::{IsolationLevel, TxOptions},
+    };
 
     use super::*;
 
     #[test]
     fn test() {
         let db = SimpleDB::new("testdata/record/table_scan/test", 256, 8, "templog");
-        let tx = Arc::new(db.new_tx());
+        let tx = Arc::new(Mutex::new(db.new_tx()));
         let mut schema = Schema::new();
         schema
             .add_int_field("int")
@@ -444,4 +477,258 @@ mod tests {
         }
         assert!(!scan.next().unwrap());
     }
+
+    mod snapshot {
+        use super::*;
+
+        fn layout() -> Arc<Layout> {
+            let mut schema = Schema::new();
+            schema.add_int_field("int");
+            Arc::new(Layout::from(schema))
+        }
+
+        fn snapshot_tx(db: &SimpleDB) -> Arc<Mutex<Transaction>> {
+            level_tx(db, IsolationLevel::Snapshot)
+        }
+
+        fn level_tx(db: &SimpleDB, isolation_level: IsolationLevel) -> Arc<Mutex<Transaction>> {
+            Arc::new(Mutex::new(db.new_tx_with(
+                TxOptions::new().with_isolation_level(isolation_level),
+            )))
+        }
+
+        // The scans and record pages of the transaction must be dropped first.
+        fn commit(tx: Arc<Mutex<Transaction>>) {
+            unwrap(tx).commit().unwrap();
+        }
+
+        fn rollback(tx: Arc<Mutex<Transaction>>) {
+            unwrap(tx).rollback().unwrap();
+        }
+
+        fn unwrap(tx: Arc<Mutex<Transaction>>) -> Transaction {
+            Arc::try_unwrap(tx).ok().unwrap().into_inner().unwrap()
+        }
+
+        fn insert(db: &SimpleDB, values: &[i32]) {
+            let tx = Arc::new(Mutex::new(db.new_tx()));
+            let mut scan = TableScan::new(tx.clone(), "temp", layout()).unwrap();
+            for &value in values {
+                scan.insert().unwrap();
+                scan.set_int("int", value);
+            }
+            drop(scan);
+            commit(tx);
+        }
+
+        fn values(tx: &Arc<Mutex<Transaction>>) -> Vec<i32> {
+            let mut scan = TableScan::new(tx.clone(), "temp", layout()).unwrap();
+            let mut values = vec![];
+            while scan.next().unwrap() {
+                values.push(scan.get_int("int").unwrap());
+            }
+            scan.close();
+            values
+        }
+
+        #[test]
+        fn reads_old_version() {
+            let db = SimpleDB::new(
+                "testdata/record/table_scan/snapshot/reads_old_version",
+                256,
+                8,
+                "templog",
+            );
+            insert(&db, &[1]);
+
+            let writer = Arc::new(Mutex::new(db.new_tx()));
+            let mut scan = TableScan::new(writer.clone(), "temp", layout()).unwrap();
+            assert!(scan.next().unwrap());
+            scan.set_int("int", 2);
+            assert_eq!(scan.get_int("int").unwrap(), 2);
+
+            // the reader does not wait for the writer
+            let reader = snapshot_tx(&db);
+            assert_eq!(values(&reader), vec![1]);
+
+            drop(scan);
+            commit(writer);
+            assert_eq!(values(&reader), vec![1]);
+            assert_eq!(values(&snapshot_tx(&db)), vec![2]);
+        }
+
+        #[test]
+        fn hides_later_inserts_and_deletes() {
+            let db = SimpleDB::new(
+                "testdata/record/table_scan/snapshot/hides_later_inserts_and_deletes",
+                256,
+                8,
+                "templog",
+            );
+            insert(&db, &[1]);
+
+            let reader = snapshot_tx(&db);
+            let writer = Arc::new(Mutex::new(db.new_tx()));
+            let mut scan = TableScan::new(writer.clone(), "temp", layout()).unwrap();
+            assert!(scan.next().unwrap());
+            scan.delete().unwrap();
+            scan.insert().unwrap();
+            scan.set_int("int", 2);
+            drop(scan);
+            commit(writer);
+
+            assert_eq!(values(&reader), vec![1]);
+            assert_eq!(values(&snapshot_tx(&db)), vec![2]);
+        }
+
+        #[test]
+        fn first_updater_wins() {
+            let db = SimpleDB::new(
+                "testdata/record/table_scan/snapshot/first_updater_wins",
+                256,
+                8,
+                "templog",
+            );
+            insert(&db, &[1]);
+
+            let loser = snapshot_tx(&db);
+            let winner = snapshot_tx(&db);
+            let block = BlockId::new("temp.tbl".to_string(), 0);
+            let mut rp = RecordPage::new(winner.clone(), block.clone(), layout()).unwrap();
+            rp.set_int(0, "int", 2).unwrap();
+            drop(rp);
+            commit(winner);
+
+            let mut rp = RecordPage::new(loser.clone(), block, layout()).unwrap();
+            assert_eq!(rp.get_int(0, "int").unwrap(), 1);
+            let err = rp.set_int(0, "int", 3).unwrap_err();
+            assert!(matches!(err, DbError::ConcurrentUpdate));
+        }
+
+        #[test]
+        fn rollback_restores_version() {
+            let db = SimpleDB::new(
+                "testdata/record/table_scan/snapshot/rollback_restores_version",
+                256,
+                8,
+                "templog",
+            );
+            insert(&db, &[1]);
+
+            let writer = snapshot_tx(&db);
+            let mut scan = TableScan::new(writer.clone(), "temp", layout()).unwrap();
+            assert!(scan.next().unwrap());
+            scan.set_int("int", 2);
+            drop(scan);
+            rollback(writer);
+
+            assert_eq!(values(&snapshot_tx(&db)), vec![1]);
+        }
+
+        #[test]
+        fn reuses_settled_slots() {
+            let db = SimpleDB::new(
+                "testdata/record/table_scan/snapshot/reuses_settled_slots",
+                256,
+                8,
+                "templog",
+            );
+            insert(&db, &[1]);
+
+            let reader = snapshot_tx(&db);
+            let writer = Arc::new(Mutex::new(db.new_tx()));
+            let mut scan = TableScan::new(writer.clone(), "temp", layout()).unwrap();
+            assert!(scan.next().unwrap());
+            scan.delete().unwrap();
+            drop(scan);
+            commit(writer);
+
+            // the reader still sees the deleted record
+            insert(&db, &[2]);
+            assert_eq!(values(&reader), vec![1]);
+            commit(reader);
+
+            insert(&db, &[3]);
+            let tx = snapshot_tx(&db);
+            assert_eq!(values(&tx), vec![3, 2]);
+        }
+
+        // Each transaction reads both records and updates one of them.
+        fn write_skew(
+            db: &SimpleDB,
+            isolation_level: IsolationLevel,
+        ) -> (Arc<Mutex<Transaction>>, Arc<Mutex<Transaction>>) {
+            insert(db, &[1, 1]);
+            let block = BlockId::new("temp.tbl".to_string(), 0);
+            let tx1 = level_tx(db, isolation_level);
+            let tx2 = level_tx(db, isolation_level);
+            let mut rp1 = RecordPage::new(tx1.clone(), block.clone(), layout()).unwrap();
+            let mut rp2 = RecordPage::new(tx2.clone(), block, layout()).unwrap();
+            for rp in [&mut rp1, &mut rp2] {
+                assert_eq!(rp.get_int(0, "int").unwrap(), 1);
+                assert_eq!(rp.get_int(1, "int").unwrap(), 1);
+            }
+            rp1.set_int(0, "int", 0).unwrap();
+            rp2.set_int(1, "int", 0).unwrap();
+            (tx1, tx2)
+        }
+
+        #[test]
+        fn write_skew_is_allowed() {
+            let db = SimpleDB::new(
+                "testdata/record/table_scan/snapshot/write_skew_is_allowed",
+                256,
+                8,
+                "templog",
+            );
+            let (tx1, tx2) = write_skew(&db, IsolationLevel::Snapshot);
+            commit(tx1);
+            commit(tx2);
+
+            assert_eq!(values(&snapshot_tx(&db)), vec![0, 0]);
+        }
+
+        #[test]
+        fn serializable_snapshot_prevents_write_skew() {
+            let db = SimpleDB::new(
+                "testdata/record/table_scan/snapshot/serializable_snapshot_prevents_write_skew",
+                256,
+                8,
+                "templog",
+            );
+            let (tx1, tx2) = write_skew(&db, IsolationLevel::SerializableSnapshot);
+            let err = unwrap(tx1).commit().unwrap_err();
+            assert!(matches!(err, DbError::SerializationFailure));
+            assert!(err.is_retryable());
+            commit(tx2);
+
+            assert_eq!(values(&snapshot_tx(&db)), vec![1, 0]);
+        }
+
+        #[test]
+        fn read_only_tx_does_not_block_writers() {
+            let db = SimpleDB::new(
+                "testdata/record/table_scan/snapshot/read_only_tx_does_not_block_writers",
+                256,
+                8,
+                "templog",
+            );
+            insert(&db, &[1]);
+
+            let reader = Arc::new(Mutex::new(db.new_read_only_tx()));
+            assert_eq!(values(&reader), vec![1]);
+
+            let writer = Arc::new(Mutex::new(db.new_tx()));
+            let mut scan = TableScan::new(writer.clone(), "temp", layout()).unwrap();
+            assert!(scan.next().unwrap());
+            scan.set_int("int", 2);
+            assert_eq!(scan.get_int("int").unwrap(), 2);
+            drop(scan);
+
+            assert_eq!(values(&reader), vec![1]);
+            commit(writer);
+            assert_eq!(values(&reader), vec![1]);
+            commit(reader);
+        }
+    }
 }
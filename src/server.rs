@@ -1 +1,3 @@
+pub mod restore;
 pub mod simple_db;
+pub mod standby;
@@ -0,0 +1,291 @@
+#![allow(dead_code)]
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    error::DbError,
+    file::file_manager::FileManager,
+    log::log_manager::LogManager,
+    tx::{
+        recovery::{
+            checkpoint_record::CheckpointRecord,
+            log_replay::{replay, RestoreTarget},
+            recovery_manager::RecoveryManager,
+        },
+        transaction::Transaction,
+    },
+    util::{create_empty_dir, Result},
+};
+
+use super::simple_db::{SimpleDB, BLOCK_SIZE, BUFFER_SIZE, LOG_FILE};
+
+// Where the log to replay is gathered, inside the restored directory.
+const STAGING_DIR: &str = "restore_log";
+
+// Point-in-time recovery: a base backup of a database directory is copied to a new directory,
+// and the log, from the backup and from the archived segments, is replayed on it up to a target.
+// The restored database starts with a log of its own,
+// which goes on numbering transactions after the ones in the replayed log.
+pub struct Restore {
+    backup_dir: PathBuf,
+    archive_dir: Option<PathBuf>,
+    block_size: i32,
+    log_file: String,
+    target: RestoreTarget,
+}
+
+impl Restore {
+    pub fn new(backup_dir: &str) -> Self {
+        Self {
+            backup_dir: PathBuf::from(backup_dir),
+            archive_dir: None,
+            block_size: BLOCK_SIZE,
+            log_file: LOG_FILE.to_string(),
+            target: RestoreTarget::End,
+        }
+    }
+
+    // The archived segments, and any segments copied from the database after the backup.
+    // A segment found in both places is taken from the archive, where it is complete.
+    pub fn with_archive(mut self, dir: &str) -> Self {
+        self.archive_dir = Some(PathBuf::from(dir));
+        self
+    }
+
+    pub fn with_block_size(mut self, block_size: i32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn with_log_file(mut self, log_file: &str) -> Self {
+        self.log_file = log_file.to_string();
+        self
+    }
+
+    pub fn with_target(mut self, target: RestoreTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    // Restore into `db_dir`, which must be new or empty, and return the LSN of the last replayed record.
+    pub fn run(&self, db_dir: &str) -> Result<i32> {
+        let db_path = PathBuf::from(db_dir);
+        create_empty_dir(&db_path)?;
+        let staging = db_path.join(STAGING_DIR);
+        std::fs::create_dir_all(&staging)?;
+
+        let segment_prefix = format!("{}.", self.log_file);
+        self.copy_files(&self.backup_dir, &db_path, &staging, &segment_prefix)?;
+        if let Some(archive_dir) = &self.archive_dir {
+            self.copy_files(archive_dir, &db_path, &staging, &segment_prefix)?;
+        }
+
+        let fm = Arc::new(FileManager::open(staging.clone(), self.block_size)?);
+        let segments = LogManager::segments(&fm, &self.log_file)?;
+        if segments.is_empty() {
+            return Err(DbError::MissingLogSegment(0));
+        }
+        if let Some(pair) = segments.windows(2).find(|pair| pair[1] != pair[0] + 1) {
+            return Err(DbError::MissingLogSegment(pair[0] + 1));
+        }
+        // The database is opened first, so that a backup in an older format is refused.
+        let db = SimpleDB::open(db_dir, self.block_size, BUFFER_SIZE, &self.log_file)?;
+        let history = LogManager::new(fm, self.log_file.clone())?;
+        let mut tx = db.new_tx();
+        let lsn = replay(&mut tx, &history, self.target)?;
+        let last_tx_num = RecoveryManager::max_tx_num(&history)?.max(tx.tx_num());
+        tx.commit()?;
+        // The restored records carry the numbers of the transactions that wrote them,
+        // so newer transactions must not reuse them after a restart.
+        let lm = db.log_manager();
+        lm.flush(CheckpointRecord::new(last_tx_num).write_to_log(&lm)?)?;
+        Transaction::init_tx_number(lm)?;
+
+        drop(history);
+        std::fs::remove_dir_all(&staging)?;
+        Ok(lsn)
+    }
+
+    // Copy the log segments in `from` to `staging`, and, from the backup, the other files to `db_path`.
+    fn copy_files(
+        &self,
+        from: &Path,
+        db_path: &Path,
+        staging: &Path,
+        segment_prefix: &str,
+    ) -> Result<()> {
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name();
+            if name.to_string_lossy().starts_with(segment_prefix) {
+                std::fs::copy(entry.path(), staging.join(&name))?;
+            } else if from == self.backup_dir {
+                std::fs::copy(entry.path(), db_path.join(&name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use chrono::{DateTime, FixedOffset, Utc};
+
+    use crate::{
+        file::{block_id::BlockId, page::Page},
+        tx::recovery::log_record::{create_log_record, CHECKPOINT},
+    };
+
+    use super::*;
+
+    const DIR: &str = "testdata/server/restore/restores_to_target";
+    const LOG: &str = "simpledb.log";
+
+    fn copy_dir(from: &str, to: &str) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_file() {
+                std::fs::copy(entry.path(), Path::new(to).join(entry.file_name())).unwrap();
+            }
+        }
+    }
+
+    fn set_ints(db: &SimpleDB, values: &[(i32, i32)]) -> i32 {
+        let block = BlockId::new("data".to_string(), 0);
+        let mut tx = db.new_tx();
+        let tx_num = tx.tx_num();
+        tx.pin(&block).unwrap();
+        for (offset, value) in values {
+            tx.set_int(&block, *offset, *value, true).unwrap();
+        }
+        tx.commit().unwrap();
+        tx_num
+    }
+
+    fn restored_ints(target: RestoreTarget, name: &str) -> Vec<i32> {
+        let db_dir = format!("{}/{}", DIR, name);
+        Restore::new(&format!("{}/backup", DIR))
+            .with_archive(&format!("{}/archive", DIR))
+            .with_log_file(LOG)
+            .with_target(target)
+            .run(&db_dir)
+            .unwrap();
+
+        let db = SimpleDB::new(&db_dir, BLOCK_SIZE, BUFFER_SIZE, LOG);
+        let mut tx = db.new_tx();
+        let ints = [(0, 0), (0, 4), (1, 0)]
+            .iter()
+            .map(|(block_num, offset)| {
+                let block = BlockId::new("data".to_string(), *block_num);
+                tx.pin(&block).unwrap();
+                tx.get_int(&block, *offset).unwrap()
+            })
+            .collect();
+        tx.commit().unwrap();
+        ints
+    }
+
+    #[test]
+    fn restores_to_target() {
+        let _ = std::fs::remove_dir_all(DIR);
+        let live_dir = format!("{}/live", DIR);
+        let db = SimpleDB::new(&live_dir, BLOCK_SIZE, BUFFER_SIZE, LOG);
+        db.log_manager().set_segment_blocks(1);
+        let mut tx = db.new_tx();
+        tx.append("data").unwrap();
+        tx.append("data").unwrap();
+        tx.commit().unwrap();
+        set_ints(&db, &[(0, 1)]);
+
+        copy_dir(&live_dir, &format!("{}/backup", DIR));
+
+        let tx2 = set_ints(&db, &[(0, 2), (4, 20)]);
+        thread::sleep(Duration::from_millis(10));
+        let time: DateTime<FixedOffset> = Utc::now().fixed_offset();
+        thread::sleep(Duration::from_millis(10));
+
+        // rolled back
+        let block = BlockId::new("data".to_string(), 0);
+        let mut tx3 = db.new_tx();
+        tx3.pin(&block).unwrap();
+        tx3.set_int(&block, 0, 3, true).unwrap();
+        tx3.rollback().unwrap();
+
+        // rolled back to a savepoint
+        let mut tx4 = db.new_tx();
+        let tx4_num = tx4.tx_num();
+        tx4.pin(&block).unwrap();
+        tx4.set_int(&block, 0, 4, true).unwrap();
+        tx4.savepoint("sp").unwrap();
+        tx4.set_int(&block, 4, 40, true).unwrap();
+        tx4.rollback_to("sp").unwrap();
+        tx4.commit().unwrap();
+
+        // unfinished, and still holding its lock on block 0
+        let mut tx5 = db.new_tx();
+        tx5.pin(&block).unwrap();
+        tx5.set_int(&block, 0, 5, true).unwrap();
+        let tx5_lsn = db.log_manager().latest_lsn();
+        drop(tx5);
+        let block1 = BlockId::new("data".to_string(), 1);
+        let mut tx6 = db.new_tx();
+        let tx6_num = tx6.tx_num();
+        tx6.pin(&block1).unwrap();
+        tx6.set_int(&block1, 0, 6, true).unwrap();
+        tx6.commit().unwrap();
+
+        // the segments still in the database are archived along with the others
+        assert!(db.log_manager().segment_range().count() > 1);
+        copy_dir(&live_dir, &format!("{}/archive", DIR));
+
+        assert_eq!(restored_ints(RestoreTarget::Tx(tx2), "to_tx"), [2, 20, 0]);
+        assert_eq!(
+            restored_ints(RestoreTarget::Time(time), "to_time"),
+            [2, 20, 0]
+        );
+        assert_eq!(
+            restored_ints(RestoreTarget::Tx(tx4_num), "to_tx4"),
+            [4, 20, 0]
+        );
+        assert_eq!(
+            restored_ints(RestoreTarget::Lsn(tx5_lsn), "to_lsn"),
+            [4, 20, 0]
+        );
+        assert_eq!(restored_ints(RestoreTarget::End, "to_end"), [4, 20, 6]);
+
+        // the restored log knows of the replayed transactions after a restart
+        let db = SimpleDB::new(&format!("{}/to_end", DIR), BLOCK_SIZE, BUFFER_SIZE, LOG);
+        let checkpoint = db
+            .log_manager()
+            .iter()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|bytes| create_log_record(bytes.clone()).unwrap().op() == CHECKPOINT)
+            .unwrap();
+        assert!(CheckpointRecord::from(Page::from(checkpoint)).last_tx_num() >= tx6_num);
+        assert!(RecoveryManager::max_tx_num(&db.log_manager()).unwrap() >= tx6_num);
+    }
+
+    #[test]
+    fn missing_segment() {
+        let dir = "testdata/server/restore/missing_segment";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(format!("{}/backup", dir)).unwrap();
+        std::fs::write(format!("{}/backup/{}.0", dir, LOG), []).unwrap();
+        std::fs::write(format!("{}/backup/{}.2", dir, LOG), []).unwrap();
+
+        let res = Restore::new(&format!("{}/backup", dir))
+            .with_log_file(LOG)
+            .run(&format!("{}/db", dir));
+        assert!(matches!(res, Err(DbError::MissingLogSegment(1))));
+    }
+}
@@ -1,12 +1,22 @@
 #![allow(dead_code)]
 
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use crate::{
     buffer::buffer_manager::BufferManager,
     file::file_manager::FileManager,
     log::log_manager::LogManager,
-    tx::{concurrency::lock_table::LockTable, transaction::Transaction},
+    tx::{
+        concurrency::{lock_table::LockTable, occ_validator::OccValidator},
+        siread_table::SireadTable,
+        transaction::Transaction,
+        tx_options::{IsolationLevel, TxOptions},
+        version_store::VersionStore,
+    },
+    util::{create_empty_dir, Result},
 };
 
 pub const BLOCK_SIZE: i32 = 400;
@@ -19,31 +29,114 @@ pub struct SimpleDB {
     lm: Arc<LogManager>,
     bm: Arc<BufferManager>,
     lock_table: Arc<LockTable>,
+    versions: Arc<VersionStore>,
+    sireads: Arc<SireadTable>,
+    validator: Arc<OccValidator>,
+    async_commit: bool,
 }
 
 impl SimpleDB {
     pub fn new(dir_name: &str, block_size: i32, buffer_size: i32, log_file: &str) -> Self {
+        Self::open(dir_name, block_size, buffer_size, log_file).unwrap()
+    }
+
+    // Open the database in `dir_name`, failing if its files are in an older format.
+    pub fn open(dir_name: &str, block_size: i32, buffer_size: i32, log_file: &str) -> Result<Self> {
         let fm = Arc::new(FileManager::new(PathBuf::from(dir_name), block_size));
-        let lm = Arc::new(LogManager::new(fm.clone(), log_file.to_string()));
+        fm.check_format(log_file)?;
+        let lm = Arc::new(LogManager::new(fm.clone(), log_file.to_string())?);
+        Transaction::init_tx_number(lm.clone())?;
         let bm = Arc::new(BufferManager::new(fm.clone(), lm.clone(), buffer_size));
         let lock_table = Arc::new(LockTable::new());
-        Self {
+        let versions = Arc::new(VersionStore::new());
+        let sireads = Arc::new(SireadTable::new());
+        let validator = Arc::new(OccValidator::new());
+        Ok(Self {
             fm,
             lm,
             bm,
             lock_table,
-        }
+            versions,
+            sireads,
+            validator,
+            async_commit: false,
+        })
+    }
+
+    // Replace the lock table, e.g. to configure the lock wait limit.
+    pub fn with_lock_table(mut self, lock_table: LockTable) -> Self {
+        self.lock_table = Arc::new(lock_table);
+        self
+    }
+
+    // Let a commit wait up to `millis` for other commits to share its log flush.
+    pub fn with_max_flush_delay(self, millis: u64) -> Self {
+        self.lm.set_max_flush_delay(millis);
+        self
+    }
+
+    // Move the log segments before a checkpoint to `dir` instead of deleting them.
+    pub fn with_log_archive(self, dir: &str) -> Self {
+        self.lm.set_archive_dir(Some(PathBuf::from(dir)));
+        self
+    }
+
+    // Commit the transactions made by `new_tx` asynchronously.
+    pub fn with_async_commit(mut self, async_commit: bool) -> Self {
+        self.async_commit = async_commit;
+        self
     }
 
     pub fn new_tx(&self) -> Transaction {
+        self.new_tx_with(TxOptions::new().with_async_commit(self.async_commit))
+    }
+
+    pub fn new_tx_with(&self, options: TxOptions) -> Transaction {
         Transaction::new(
             self.fm.clone(),
             self.lm.clone(),
             self.bm.clone(),
             self.lock_table.clone(),
+            self.versions.clone(),
+            self.sireads.clone(),
+            self.validator.clone(),
+            options,
         )
     }
 
+    // A transaction that only reads, from a snapshot taken when it starts.
+    // It neither waits for writers nor logs anything.
+    pub fn new_read_only_tx(&self) -> Transaction {
+        self.new_tx_with(
+            TxOptions::new()
+                .with_isolation_level(IsolationLevel::Snapshot)
+                .with_read_only(true),
+        )
+    }
+
+    // Copy the database into `dest_dir`, which must be new or empty, while transactions keep running.
+    // A nonquiescent checkpoint first flushes every change; the table files are then copied,
+    // and the log last, so that it covers every change found in the copied files.
+    // Restoring the copy with `Restore` replays the log over it to a consistent state.
+    pub fn backup(&self, dest_dir: &str) -> Result<()> {
+        let dest = Path::new(dest_dir);
+        create_empty_dir(dest)?;
+
+        // A checkpoint during the backup must not remove the segments the copied files need.
+        let _kept = self.lm.keep_segments();
+        Transaction::nq_ckpt(self.bm.clone(), self.lm.clone())?;
+
+        let segment_prefix = format!("{}.", self.lm.log_file());
+        for name in self.fm.file_names()? {
+            // Temporary tables are not part of the database.
+            if name.starts_with(&segment_prefix) || name.starts_with("temp") {
+                continue;
+            }
+            self.fm.copy(&name, dest)?;
+        }
+        self.lm.copy_segments(dest)
+    }
+
     pub fn file_manager(&self) -> Arc<FileManager> {
         self.fm.clone()
     }
@@ -56,3 +149,84 @@ impl SimpleDB {
         self.bm.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use crate::{
+        error::DbError,
+        file::{block_id::BlockId, file_manager::FORMAT_FILE},
+        server::restore::Restore,
+    };
+
+    use super::*;
+
+    #[test]
+    fn backup_while_writing() {
+        let dir = "testdata/server/simple_db/backup_while_writing";
+        let _ = std::fs::remove_dir_all(dir);
+        let db = SimpleDB::new(&format!("{}/live", dir), BLOCK_SIZE, BUFFER_SIZE, LOG_FILE);
+        let block0 = BlockId::new("data".to_string(), 0);
+        let block1 = BlockId::new("data".to_string(), 1);
+        let mut tx = db.new_tx();
+        tx.append("data").unwrap();
+        tx.append("data").unwrap();
+        tx.commit().unwrap();
+
+        // unfinished during the backup, so its change is flushed by the checkpoint
+        let mut unfinished = db.new_tx();
+        unfinished.pin(&block1).unwrap();
+        unfinished.set_int(&block1, 0, 9, true).unwrap();
+
+        // every transaction keeps both values equal
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut i = 1;
+                while !done.load(Ordering::SeqCst) {
+                    let mut tx = db.new_tx();
+                    tx.pin(&block0).unwrap();
+                    tx.set_int(&block0, 0, i, true).unwrap();
+                    tx.set_int(&block0, 4, i, true).unwrap();
+                    tx.commit().unwrap();
+                    i += 1;
+                }
+            });
+            thread::sleep(std::time::Duration::from_millis(20));
+            db.backup(&format!("{}/backup", dir)).unwrap();
+            done.store(true, Ordering::SeqCst);
+        });
+        unfinished.rollback().unwrap();
+
+        let restored_dir = format!("{}/restored", dir);
+        Restore::new(&format!("{}/backup", dir))
+            .run(&restored_dir)
+            .unwrap();
+        let db = SimpleDB::new(&restored_dir, BLOCK_SIZE, BUFFER_SIZE, LOG_FILE);
+        let mut tx = db.new_tx();
+        tx.pin(&block0).unwrap();
+        tx.pin(&block1).unwrap();
+        let a = tx.get_int(&block0, 0).unwrap();
+        assert!(a > 0);
+        assert_eq!(tx.get_int(&block0, 4).unwrap(), a);
+        assert_eq!(tx.get_int(&block1, 0).unwrap(), 0);
+        tx.commit().unwrap();
+
+        // a backup is only made into an empty directory
+        assert!(db.backup(&restored_dir).is_err());
+    }
+
+    #[test]
+    fn refuses_older_format() {
+        let dir = "testdata/server/simple_db/refuses_older_format";
+        SimpleDB::new(dir, 400, 8, "simpledb.log");
+        std::fs::remove_file(format!("{}/{}", dir, FORMAT_FILE)).unwrap();
+
+        let err = SimpleDB::open(dir, 400, 8, "simpledb.log").err().unwrap();
+        assert!(matches!(err, DbError::UnsupportedFormat(1)));
+    }
+}
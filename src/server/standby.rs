@@ -0,0 +1,425 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use tracing::warn;
+
+use crate::{
+    error::DbError,
+    file::{block_id::BlockId, file_manager::FileManager, page::Page},
+    log::{
+        log_iterator::{Direction, LogIterator, LogPosition},
+        log_manager::LogManager,
+    },
+    tx::{
+        recovery::{
+            checkpoint_record::CheckpointRecord,
+            commit_tracker::{CommitTracker, Settled},
+            log_record::{create_log_record, LogRecord, CHECKPOINT},
+            log_replay::extend_to,
+        },
+        transaction::Transaction,
+        tx_options::TxOptions,
+    },
+    util::{create_empty_dir, Result},
+};
+
+use super::simple_db::{SimpleDB, BLOCK_SIZE, BUFFER_SIZE, LOG_FILE};
+
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
+// A standby follows a primary database through its log: the primary's directory is shared,
+// and the standby reads the log blocks the primary's log manager writes there.
+// It starts from a base backup of the primary, and applies the changes of each transaction
+// once the transaction commits, so that its read-only transactions only see committed data.
+// The applied changes are logged, so that the standby recovers like any database.
+pub struct Standby {
+    primary_dir: PathBuf,
+    block_size: i32,
+    log_file: String,
+    poll_interval: Duration,
+}
+
+impl Standby {
+    pub fn new(primary_dir: &str) -> Self {
+        Self {
+            primary_dir: PathBuf::from(primary_dir),
+            block_size: BLOCK_SIZE,
+            log_file: LOG_FILE.to_string(),
+            poll_interval: Duration::from_millis(POLL_INTERVAL_MILLIS),
+        }
+    }
+
+    pub fn with_block_size(mut self, block_size: i32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn with_log_file(mut self, log_file: &str) -> Self {
+        self.log_file = log_file.to_string();
+        self
+    }
+
+    // How often the primary's log is read for new records.
+    pub fn with_poll_interval(mut self, millis: u64) -> Self {
+        self.poll_interval = Duration::from_millis(millis);
+        self
+    }
+
+    // Start a standby in `standby_dir`, which must be new or empty, from a backup made by `SimpleDB::backup`.
+    // The standby keeps a log of its own, and follows the primary in the background.
+    pub fn start(&self, backup_dir: &str, standby_dir: &str) -> Result<StandbyDB> {
+        let backup_path = PathBuf::from(backup_dir);
+        let standby_path = PathBuf::from(standby_dir);
+        create_empty_dir(&standby_path)?;
+        let backup_fm = FileManager::open(backup_path.clone(), self.block_size)?;
+        let segment_prefix = format!("{}.", self.log_file);
+        for name in backup_fm.file_names()? {
+            if !name.starts_with(&segment_prefix) {
+                std::fs::copy(backup_path.join(&name), standby_path.join(&name))?;
+            }
+        }
+        let segments = LogManager::segments(&backup_fm, &self.log_file)?;
+        let first_segment = *segments.first().ok_or(DbError::MissingLogSegment(0))?;
+
+        let db = SimpleDB::open(standby_dir, self.block_size, BUFFER_SIZE, &self.log_file)?;
+        let mut follower = Follower {
+            db: db.clone(),
+            primary_dir: self.primary_dir.clone(),
+            block_size: self.block_size,
+            log_file: self.log_file.clone(),
+            tracker: CommitTracker::new(),
+            settled: VecDeque::new(),
+            position: LogPosition {
+                segment: first_segment,
+                block: 0,
+            },
+            last_lsn: 0,
+            last_tx_num: 0,
+        };
+        follower.receive(&backup_path)?;
+        // The checkpoint of the backup flushed the changes of the unfinished transactions.
+        // They are undone until the transactions commit.
+        apply(&db, &follower.tracker.pending(), false)?;
+
+        let follower = Arc::new(Mutex::new(follower));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let follower = follower.clone();
+            let stop = stop.clone();
+            let poll_interval = self.poll_interval;
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    if let Err(e) = follower.lock().unwrap().receive_from_primary() {
+                        warn!(%e, "standby failed to apply the primary's log");
+                    }
+                    thread::sleep(poll_interval);
+                }
+            })
+        };
+        Ok(StandbyDB {
+            db,
+            follower,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+// A running standby.
+pub struct StandbyDB {
+    db: SimpleDB,
+    follower: Arc<Mutex<Follower>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StandbyDB {
+    // Apply what the primary has logged so far, and return the LSN of the last record read.
+    pub fn catch_up(&self) -> Result<i32> {
+        self.follower.lock().unwrap().receive_from_primary()
+    }
+
+    pub fn last_lsn(&self) -> i32 {
+        self.follower.lock().unwrap().last_lsn
+    }
+
+    // Writes are rejected; reads wait for the transaction applying a commit of the primary.
+    pub fn new_read_only_tx(&self) -> Transaction {
+        self.db.new_tx_with(TxOptions::new().with_read_only(true))
+    }
+
+    // Stop following the primary and return the standby as a database of its own.
+    // The changes of the transactions unfinished on the primary are left out.
+    // New transactions are numbered after the primary's, whose numbers the applied records carry.
+    pub fn promote(mut self) -> Result<SimpleDB> {
+        self.stop();
+        // The primary may be gone.
+        if let Err(e) = self.catch_up() {
+            warn!(%e, "standby promoted without the end of the primary's log");
+        }
+        let last_tx_num = self.follower.lock().unwrap().last_tx_num;
+        let lm = self.db.log_manager();
+        lm.flush(CheckpointRecord::new(last_tx_num).write_to_log(&lm)?)?;
+        Transaction::init_tx_number(lm)?;
+        Ok(self.db.clone())
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for StandbyDB {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct Follower {
+    db: SimpleDB,
+    primary_dir: PathBuf,
+    block_size: i32,
+    log_file: String,
+    tracker: CommitTracker,
+    // Settled changes not applied yet, because their blocks could not be locked.
+    settled: VecDeque<Settled>,
+    // The block of the last record read, where reading resumes.
+    position: LogPosition,
+    last_lsn: i32,
+    // The largest transaction number of the primary seen so far.
+    last_tx_num: i32,
+}
+
+impl Follower {
+    fn receive_from_primary(&mut self) -> Result<i32> {
+        let primary_dir = self.primary_dir.clone();
+        self.receive(&primary_dir)
+    }
+
+    // Read the records of the log in `dir` past the last one read, and apply the changes they settle.
+    fn receive(&mut self, dir: &Path) -> Result<i32> {
+        let fm = Arc::new(FileManager::open(dir.to_path_buf(), self.block_size)?);
+        let segments = LogManager::segments(&fm, &self.log_file)?;
+        if let Some(&last_segment) = segments.last() {
+            // The primary removed the segment after a checkpoint, before it was read.
+            let segment = self.position.segment;
+            if !segments.contains(&segment) {
+                return Err(DbError::MissingLogSegment(segment));
+            }
+            let filename = LogManager::segment_file(&self.log_file, segment);
+            let block = BlockId::new(filename, self.position.block);
+            let mut iter = LogIterator::new(
+                fm,
+                self.log_file.clone(),
+                segment,
+                last_segment,
+                block,
+                Direction::Forward,
+            )?
+            .starting_at(self.last_lsn + 1);
+            // A block being written by the primary may be read torn: the iteration stops there,
+            // and the next call reads it again.
+            while let Some(record) = iter.next() {
+                let (lsn, bytes) = record?;
+                self.last_tx_num = self.last_tx_num.max(last_tx_num(&bytes));
+                if let Some(settled) = self.tracker.track(lsn, bytes) {
+                    self.settled.push_back(settled);
+                }
+                self.position = iter.position();
+                self.last_lsn = lsn;
+            }
+        }
+
+        while let Some(settled) = self.settled.front() {
+            match settled {
+                Settled::Committed(_, changes) | Settled::RolledBack(changes) => {
+                    let records: Vec<&dyn LogRecord> =
+                        changes.iter().map(|(_, record)| record.as_ref()).collect();
+                    apply(
+                        &self.db,
+                        &records,
+                        matches!(settled, Settled::Committed(..)),
+                    )?;
+                }
+            }
+            self.settled.pop_front();
+        }
+        Ok(self.last_lsn)
+    }
+}
+
+// Redo or undo changes in a transaction of the standby, logging them.
+// Their blocks are all locked first, so that readers see either none or all of them,
+// and nothing is written if a lock cannot be had.
+fn apply(db: &SimpleDB, changes: &[&dyn LogRecord], redo: bool) -> Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let mut tx = db.new_tx();
+    if let Err(e) = lock_blocks(&mut tx, changes) {
+        tx.rollback()?;
+        return Err(e);
+    }
+    for record in changes {
+        if redo {
+            record.redo(&mut tx, true);
+        } else {
+            record.undo(&mut tx, true);
+        }
+    }
+    tx.commit()
+}
+
+// The number of the transaction of a record, or the last one a checkpoint saw.
+fn last_tx_num(bytes: &[u8]) -> i32 {
+    match create_log_record(bytes.to_vec()) {
+        Some(record) if record.op() == CHECKPOINT => {
+            CheckpointRecord::from(Page::from(bytes.to_vec())).last_tx_num()
+        }
+        Some(record) => record.tx_num(),
+        None => 0,
+    }
+}
+
+fn lock_blocks(tx: &mut Transaction, changes: &[&dyn LogRecord]) -> Result<()> {
+    for record in changes {
+        let block = record.block().unwrap();
+        extend_to(tx, block)?;
+        tx.pin(block)?;
+        tx.x_lock(block)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIR: &str = "testdata/server/standby/follows_and_promotes";
+
+    fn set_int(db: &SimpleDB, block: &BlockId, offset: i32, value: i32) {
+        let mut tx = db.new_tx();
+        tx.pin(block).unwrap();
+        tx.set_int(block, offset, value, true).unwrap();
+        tx.commit().unwrap();
+    }
+
+    fn get_ints(standby: &StandbyDB, block: &BlockId) -> [i32; 2] {
+        let mut tx = standby.new_read_only_tx();
+        tx.pin(block).unwrap();
+        let ints = [tx.get_int(block, 0).unwrap(), tx.get_int(block, 4).unwrap()];
+        tx.commit().unwrap();
+        ints
+    }
+
+    #[test]
+    fn follows_and_promotes() {
+        let _ = std::fs::remove_dir_all(DIR);
+        let primary_dir = format!("{}/primary", DIR);
+        let primary = SimpleDB::new(&primary_dir, BLOCK_SIZE, BUFFER_SIZE, LOG_FILE);
+        primary.log_manager().set_segment_blocks(2);
+        let block = BlockId::new("data".to_string(), 0);
+        let mut tx = primary.new_tx();
+        tx.append("data").unwrap();
+        tx.commit().unwrap();
+        set_int(&primary, &block, 0, 1);
+
+        // unfinished during the backup, committed afterwards
+        let mut tx = primary.new_tx();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 4, 10, true).unwrap();
+        primary.backup(&format!("{}/backup", DIR)).unwrap();
+
+        let standby = Standby::new(&primary_dir)
+            .with_poll_interval(10)
+            .start(&format!("{}/backup", DIR), &format!("{}/standby", DIR))
+            .unwrap();
+        assert_eq!(get_ints(&standby, &block), [1, 0]);
+
+        tx.commit().unwrap();
+        // rolled back, so never seen by the standby
+        let mut tx = primary.new_tx();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 0, 99, true).unwrap();
+        tx.rollback().unwrap();
+        // a new block, and enough records to move on to new segments
+        let block1 = BlockId::new("data".to_string(), 1);
+        for i in 0..20 {
+            set_int(&primary, &block1, 0, i);
+        }
+        assert!(primary.log_manager().segment_range().count() > 1);
+
+        standby.catch_up().unwrap();
+        assert_eq!(standby.last_lsn(), primary.log_manager().latest_lsn());
+        assert_eq!(get_ints(&standby, &block), [1, 10]);
+        assert_eq!(get_ints(&standby, &block1), [19, 0]);
+        let mut tx = standby.new_read_only_tx();
+        tx.pin(&block).unwrap();
+        assert!(matches!(
+            tx.set_int(&block, 0, 5, true),
+            Err(DbError::ReadOnly)
+        ));
+        tx.rollback().unwrap();
+
+        // followed in the background
+        set_int(&primary, &block, 0, 2);
+        let lsn = primary.log_manager().latest_lsn();
+        for _ in 0..100 {
+            if standby.last_lsn() >= lsn {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(get_ints(&standby, &block), [2, 10]);
+
+        // unfinished on the primary when it fails over
+        let mut tx = primary.new_tx();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 0, 3, true).unwrap();
+        primary
+            .log_manager()
+            .flush(primary.log_manager().latest_lsn())
+            .unwrap();
+
+        let primary_tx_num = tx.tx_num();
+        let promoted = standby.promote().unwrap();
+        // the applied changes are in the promoted log, followed by the primary's transaction numbers
+        let records: Vec<_> = promoted
+            .log_manager()
+            .iter()
+            .unwrap()
+            .filter_map(|bytes| create_log_record(bytes.unwrap()))
+            .collect();
+        assert!(records
+            .iter()
+            .any(|record| record.block() == Some(&block1) && record.to_json()["new_value"] == 19));
+        let checkpoint = promoted
+            .log_manager()
+            .iter()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert!(CheckpointRecord::from(Page::from(checkpoint)).last_tx_num() >= primary_tx_num);
+        set_int(&promoted, &block, 4, 11);
+        let mut check = promoted.new_tx();
+        check.pin(&block).unwrap();
+        assert_eq!(check.get_int(&block, 0).unwrap(), 2);
+        assert_eq!(check.get_int(&block, 4).unwrap(), 11);
+        check.commit().unwrap();
+        tx.rollback().unwrap();
+    }
+}
@@ -1,4 +1,8 @@
 pub mod buffer_list;
 pub mod concurrency;
 pub mod recovery;
+pub mod siread_table;
+pub mod snapshot;
 pub mod transaction;
+pub mod tx_options;
+pub mod version_store;
@@ -1,74 +1,63 @@
 #![allow(dead_code)]
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLockReadGuard, RwLockWriteGuard},
+};
 
 use crate::{
-    buffer::{buffer::Buffer, buffer_manager::BufferManager},
+    buffer::{buffer::Buffer, buffer_manager::BufferManager, pinned_buffer::PinnedBuffer},
     file::block_id::BlockId,
     util::Result,
 };
 
+// The pins held by a transaction, one guard for each time a block was pinned.
 pub(super) struct BufferList {
-    buffers: HashMap<BlockId, i32>,
-    pins: Vec<BlockId>,
+    pins: HashMap<BlockId, Vec<PinnedBuffer>>,
     bm: Arc<BufferManager>,
 }
 
 impl BufferList {
     pub fn new(bm: Arc<BufferManager>) -> Self {
         Self {
-            buffers: HashMap::new(),
-            pins: Vec::new(),
+            pins: HashMap::new(),
             bm,
         }
     }
 
-    pub fn buffer(&self, block: &BlockId) -> &Buffer {
-        let i = self.buffers.get(block).unwrap();
-        self.bm.get(*i)
+    pub fn read(&self, block: &BlockId) -> RwLockReadGuard<'_, Buffer> {
+        self.pins[block][0].read()
     }
 
-    pub fn buffer_mut(&mut self, block: &BlockId) -> &mut Buffer {
-        let i = self.buffers.get(block).unwrap();
-        let bm = Arc::as_ptr(&self.bm) as *mut BufferManager;
-        unsafe { return (*bm).get_mut(*i) }
+    pub fn write(&self, block: &BlockId) -> RwLockWriteGuard<'_, Buffer> {
+        self.pins[block][0].write()
     }
 
     pub fn pin(&mut self, block: BlockId) -> Result<()> {
-        let bm = Arc::as_ptr(&self.bm) as *mut BufferManager;
-        let buffer = unsafe { (*bm).pin(&block)? };
-        self.buffers.insert(block.clone(), buffer);
-        self.pins.push(block);
+        let buffer = self.bm.pin(&block)?;
+        self.pins.entry(block).or_default().push(buffer);
         Ok(())
     }
 
     pub fn unpin(&mut self, block: BlockId) {
-        let i = self.buffers.get(&block);
-        if i.is_none() {
+        let Some(buffers) = self.pins.get_mut(&block) else {
             return;
+        };
+        buffers.pop();
+        if buffers.is_empty() {
+            self.pins.remove(&block);
         }
-        let i = *i.unwrap();
+    }
 
-        let bm = Arc::as_ptr(&self.bm) as *mut BufferManager;
-        unsafe {
-            (*bm).unpin(i);
-        }
-        let pos = self.pins.iter().position(|b| b == &block).unwrap();
-        self.pins.remove(pos);
-        if !self.pins.contains(&block) {
-            self.buffers.remove(&block);
-        }
+    // The blocks still pinned, once for each pin.
+    pub fn pinned_blocks(&self) -> Vec<BlockId> {
+        self.pins
+            .iter()
+            .flat_map(|(block, buffers)| std::iter::repeat_n(block.clone(), buffers.len()))
+            .collect()
     }
 
     pub fn unpin_all(&mut self) {
-        for block in &self.pins {
-            let i = self.buffers.get(block).unwrap();
-            let bm = Arc::as_ptr(&self.bm) as *mut BufferManager;
-            unsafe {
-                (*bm).unpin(*i);
-            }
-        }
         self.pins.clear();
-        self.buffers.clear();
     }
 }
@@ -1,2 +1,4 @@
 pub mod concurrency_manager;
 pub mod lock_table;
+pub mod occ_validator;
+pub mod wait_for_graph;
@@ -1,59 +1,362 @@
 #![allow(dead_code)]
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
-use crate::{file::block_id::BlockId, util::Result};
+use crate::{
+    error::DbError, file::block_id::BlockId, tx::tx_options::IsolationLevel, util::Result,
+};
 
-use super::lock_table::LockTable;
+use super::lock_table::{LockMode, LockTable, LockTarget};
+
+// Record locks held in a block before they are replaced by a lock on the block.
+pub const RECORD_ESCALATION_THRESHOLD: usize = 8;
+// Block locks held in a file before they are replaced by a lock on the file.
+pub const BLOCK_ESCALATION_THRESHOLD: usize = 32;
 
 pub struct ConcurrencyManager {
-    locks: HashMap<BlockId, char>,
+    tx_num: i32,
+    start_time: u128,
+    // Set while the transaction rolls back, which must not fail for being wounded.
+    rolling_back: bool,
+    isolation_level: IsolationLevel,
+    locks: HashMap<LockTarget, LockMode>,
+    // The size of the record slots of each file locked record by record,
+    // which tells the slot a value at an offset belongs to.
+    slot_sizes: HashMap<String, i32>,
     lock_table: Arc<LockTable>,
 }
 
 impl ConcurrencyManager {
-    pub fn new(lock_table: Arc<LockTable>) -> Self {
+    pub fn new(
+        tx_num: i32,
+        start_time: u128,
+        isolation_level: IsolationLevel,
+        lock_table: Arc<LockTable>,
+    ) -> Self {
         Self {
+            tx_num,
+            start_time,
+            rolling_back: false,
+            isolation_level,
             locks: HashMap::new(),
+            slot_sizes: HashMap::new(),
             lock_table,
         }
     }
 
     pub fn s_lock(&mut self, block: &BlockId) -> Result<()> {
-        if !self.locks.contains_key(block) {
-            let lock_table = Arc::as_ptr(&self.lock_table) as *mut LockTable;
-            unsafe {
-                (*lock_table).s_lock(block)?;
+        self.check_wounded()?;
+        self.lock(&LockTarget::Block(block.clone()), LockMode::Shared)
+    }
+
+    pub fn x_lock(&mut self, block: &BlockId) -> Result<()> {
+        self.check_wounded()?;
+        self.lock(&LockTarget::Block(block.clone()), LockMode::Exclusive)
+    }
+
+    // Lock a block for writing at `offset`.
+    // The record lock on the slot of the offset is enough, otherwise the whole block is locked.
+    pub fn write_lock(&mut self, block: &BlockId, offset: i32) -> Result<()> {
+        self.check_wounded()?;
+        if self.holds_record(block, offset, LockMode::Exclusive) {
+            return Ok(());
+        }
+        self.x_lock(block)
+    }
+
+    // Set the size of the record slots of a file, before locking its records.
+    pub fn set_slot_size(&mut self, filename: &str, slot_size: i32) {
+        self.slot_sizes.insert(filename.to_string(), slot_size);
+    }
+
+    pub fn s_lock_record(&mut self, block: &BlockId, slot: i32) -> Result<()> {
+        self.check_wounded()?;
+        // Reads below REPEATABLE READ are protected by the short lock taken on the block,
+        // and snapshot reads need no lock.
+        if matches!(
+            self.isolation_level,
+            IsolationLevel::ReadUncommitted
+                | IsolationLevel::ReadCommitted
+                | IsolationLevel::Snapshot
+                | IsolationLevel::SerializableSnapshot
+        ) {
+            return Ok(());
+        }
+        self.lock(&LockTarget::Record(block.clone(), slot), LockMode::Shared)
+    }
+
+    pub fn x_lock_record(&mut self, block: &BlockId, slot: i32) -> Result<()> {
+        self.check_wounded()?;
+        self.lock(
+            &LockTarget::Record(block.clone(), slot),
+            LockMode::Exclusive,
+        )
+    }
+
+    pub fn s_lock_file(&mut self, filename: &str) -> Result<()> {
+        self.check_wounded()?;
+        self.lock(&LockTarget::File(filename.to_string()), LockMode::Shared)
+    }
+
+    pub fn x_lock_file(&mut self, filename: &str) -> Result<()> {
+        self.check_wounded()?;
+        self.lock(&LockTarget::File(filename.to_string()), LockMode::Exclusive)
+    }
+
+    // Lock a block without waiting, and return whether the lock was granted.
+    pub fn try_s_lock(&mut self, block: &BlockId) -> bool {
+        self.try_lock(&LockTarget::Block(block.clone()), LockMode::Shared)
+    }
+
+    pub fn try_x_lock(&mut self, block: &BlockId) -> bool {
+        self.try_lock(&LockTarget::Block(block.clone()), LockMode::Exclusive)
+    }
+
+    // Lock a block for reading at `offset`, as the isolation level requires.
+    // Returns the locks that must be released right after the read.
+    pub fn read_lock(&mut self, block: &BlockId, offset: i32) -> Result<Vec<LockTarget>> {
+        match self.isolation_level {
+            IsolationLevel::ReadUncommitted
+            | IsolationLevel::Snapshot
+            | IsolationLevel::SerializableSnapshot => Ok(Vec::new()),
+            IsolationLevel::ReadCommitted => self.short_lock(block),
+            IsolationLevel::RepeatableRead | IsolationLevel::Serializable => {
+                self.check_wounded()?;
+                if !self.holds_record(block, offset, LockMode::Shared) {
+                    self.s_lock(block)?;
+                }
+                Ok(Vec::new())
             }
-            self.locks.insert(block.clone(), 'S');
         }
-        Ok(())
     }
 
-    pub fn x_lock(&mut self, block: &BlockId) -> Result<()> {
-        if !self.has_x_lock(block) {
-            self.s_lock(block)?;
-            let lock_table = Arc::as_ptr(&self.lock_table) as *mut LockTable;
-            unsafe {
-                (*lock_table).x_lock(block)?;
+    // Lock the end of a file for reading its size, as the isolation level requires.
+    // Returns the locks that must be released right after the read.
+    pub fn size_lock(&mut self, dummy: &BlockId) -> Result<Vec<LockTarget>> {
+        match self.isolation_level {
+            IsolationLevel::ReadUncommitted
+            | IsolationLevel::Snapshot
+            | IsolationLevel::SerializableSnapshot => Ok(Vec::new()),
+            IsolationLevel::ReadCommitted | IsolationLevel::RepeatableRead => {
+                self.short_lock(dummy)
+            }
+            IsolationLevel::Serializable => {
+                self.s_lock(dummy)?;
+                Ok(Vec::new())
             }
-            self.locks.insert(block.clone(), 'X');
+        }
+    }
+
+    // A short lock taken over a weaker lock held until the end of the transaction
+    // gives the target back in the held mode.
+    pub fn release_short(&mut self, targets: Vec<LockTarget>) {
+        for target in targets.iter().rev() {
+            match self.locks.get(target) {
+                Some(&held) => self.lock_table.downgrade(target, self.tx_num, held),
+                None => self.lock_table.unlock(target, self.tx_num),
+            }
+        }
+    }
+
+    // X-lock blocks on behalf of an in-doubt transaction left by recovery,
+    // which holds them until a transaction resolving it takes them over.
+    pub fn x_lock_in_doubt(&self, tx_num: i32, blocks: &HashSet<BlockId>) -> Result<()> {
+        for block in blocks {
+            let target = LockTarget::Block(block.clone());
+            let parent = target.parent().unwrap();
+            // The oldest of all, so that no transaction wounds it.
+            self.lock_table
+                .lock(&parent, tx_num, 0, LockMode::IntentionExclusive)?;
+            self.lock_table
+                .lock(&target, tx_num, 0, LockMode::Exclusive)?;
         }
         Ok(())
     }
 
+    // Take over the locks of an in-doubt transaction.
+    pub fn take_over(&mut self, tx_num: i32) {
+        for (target, mode) in self.lock_table.transfer(tx_num, self.tx_num) {
+            self.locks.insert(target, mode);
+        }
+    }
+
     pub fn release(&mut self) {
-        for block in self.locks.keys() {
-            let lock_table = Arc::as_ptr(&self.lock_table) as *mut LockTable;
-            unsafe {
-                (*lock_table).unlock(block);
-            }
+        for target in self.locks.keys() {
+            self.lock_table.unlock(target, self.tx_num);
         }
         self.locks.clear();
+        self.lock_table.heal(self.tx_num);
+    }
+
+    // Fail if an older transaction has wounded this one.
+    pub fn check_wounded(&self) -> Result<()> {
+        if !self.rolling_back && self.lock_table.is_wounded(self.tx_num) {
+            return Err(DbError::Wounded);
+        }
+        Ok(())
+    }
+
+    pub fn start_rollback(&mut self) {
+        self.rolling_back = true;
+    }
+
+    pub fn locked_targets(&self) -> HashSet<LockTarget> {
+        self.locks.keys().cloned().collect()
+    }
+
+    // Release the locks on targets that are not in `targets`.
+    // Locks that are in `targets` are kept as they are, even if they were upgraded,
+    // and so are the locks they were escalated to.
+    pub fn release_except(&mut self, targets: &HashSet<LockTarget>) {
+        self.locks.retain(|target, _| {
+            if targets.contains(target) || targets.iter().any(|t| t.is_descendant_of(target)) {
+                return true;
+            }
+            self.lock_table.unlock(target, self.tx_num);
+            false
+        });
+    }
+
+    // S-lock a block without recording it, unless the transaction already holds a lock on it.
+    // A block the transaction locked record by record is read under its own locks.
+    fn short_lock(&mut self, block: &BlockId) -> Result<Vec<LockTarget>> {
+        self.check_wounded()?;
+        let target = LockTarget::Block(block.clone());
+        let parent = target.parent().unwrap();
+        let mut acquired = Vec::new();
+        for (target, mode) in [
+            (parent, LockMode::IntentionShared),
+            (target, LockMode::Shared),
+        ] {
+            if self.is_covered(&target, mode) {
+                continue;
+            }
+            let res = self
+                .lock_table
+                .lock(&target, self.tx_num, self.start_time, mode);
+            if let Err(e) = res {
+                self.release_short(acquired);
+                return Err(e);
+            }
+            acquired.push(target);
+        }
+        Ok(acquired)
     }
 
-    fn has_x_lock(&self, block: &BlockId) -> bool {
-        self.locks.get(block).map_or(false, |&lock| lock == 'X')
+    fn lock(&mut self, target: &LockTarget, mode: LockMode) -> Result<()> {
+        if self.is_covered(target, mode) {
+            return Ok(());
+        }
+        if let Some(parent) = target.parent() {
+            self.lock(&parent, mode.intention())?;
+        }
+        self.lock_table
+            .lock(target, self.tx_num, self.start_time, mode)?;
+        let held = self.locks.entry(target.clone()).or_insert(mode);
+        *held = held.supremum(mode);
+        self.escalate(target);
+        Ok(())
+    }
+
+    fn try_lock(&mut self, target: &LockTarget, mode: LockMode) -> bool {
+        if self.is_covered(target, mode) {
+            return true;
+        }
+        if let Some(parent) = target.parent() {
+            if !self.try_lock(&parent, mode.intention()) {
+                return false;
+            }
+        }
+        if !self
+            .lock_table
+            .try_lock(target, self.tx_num, self.start_time, mode)
+        {
+            return false;
+        }
+        let held = self.locks.entry(target.clone()).or_insert(mode);
+        *held = held.supremum(mode);
+        true
+    }
+
+    // Whether the transaction holds a lock in `mode` on the record slot of `offset` in the block,
+    // or on the block or the file.
+    fn holds_record(&self, block: &BlockId, offset: i32, mode: LockMode) -> bool {
+        match self.slot_sizes.get(block.filename()) {
+            Some(&slot_size) => {
+                let target = LockTarget::Record(block.clone(), offset / slot_size);
+                self.is_covered(&target, mode)
+            }
+            None => self.is_covered(&LockTarget::Block(block.clone()), mode),
+        }
+    }
+
+    // Whether the lock on `target` or one of its ancestors already grants `mode`.
+    fn is_covered(&self, target: &LockTarget, mode: LockMode) -> bool {
+        if self.locks.get(target).is_some_and(|held| held.covers(mode)) {
+            return true;
+        }
+        // S, SIX and X locks implicitly lock everything below them.
+        let implied = match mode {
+            LockMode::IntentionShared | LockMode::Shared => LockMode::Shared,
+            _ => LockMode::Exclusive,
+        };
+        let mut ancestor = target.parent();
+        while let Some(a) = ancestor {
+            if self.locks.get(&a).is_some_and(|held| held.covers(implied)) {
+                return true;
+            }
+            ancestor = a.parent();
+        }
+        false
+    }
+
+    // Replace the locks on the siblings of `target` with a lock on their parent
+    // when there are too many of them.
+    // The fine-grained locks are kept if the parent cannot be locked right away.
+    fn escalate(&mut self, target: &LockTarget) {
+        let Some(parent) = target.parent() else {
+            return;
+        };
+        let threshold = match target {
+            LockTarget::Record(..) => RECORD_ESCALATION_THRESHOLD,
+            _ => BLOCK_ESCALATION_THRESHOLD,
+        };
+        let siblings: Vec<LockMode> = self
+            .locks
+            .iter()
+            .filter(|(t, _)| t.parent().as_ref() == Some(&parent))
+            .map(|(_, &mode)| mode)
+            .collect();
+        if siblings.len() <= threshold {
+            return;
+        }
+        let mode = if siblings
+            .iter()
+            .all(|&m| m == LockMode::IntentionShared || m == LockMode::Shared)
+        {
+            LockMode::Shared
+        } else {
+            LockMode::Exclusive
+        };
+
+        if !self
+            .lock_table
+            .try_lock(&parent, self.tx_num, self.start_time, mode)
+        {
+            return;
+        }
+        let held = self.locks.entry(parent.clone()).or_insert(mode);
+        *held = held.supremum(mode);
+        self.locks.retain(|t, _| {
+            if !t.is_descendant_of(&parent) {
+                return true;
+            }
+            self.lock_table.unlock(t, self.tx_num);
+            false
+        });
     }
 }
 
@@ -64,8 +367,10 @@ mod tests {
     #[test]
     fn xlock_then_xlock() {
         let lock_table = Arc::new(LockTable::new());
-        let mut cm1 = ConcurrencyManager::new(lock_table.clone());
-        let mut cm2 = ConcurrencyManager::new(lock_table.clone());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
         let block = BlockId::new("file".to_string(), 0);
 
         cm1.x_lock(&block).unwrap();
@@ -73,4 +378,159 @@ mod tests {
         let res = cm2.x_lock(&block);
         assert!(res.is_err());
     }
+
+    #[test]
+    fn release_except() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
+        let block1 = BlockId::new("file".to_string(), 0);
+        let block2 = BlockId::new("file".to_string(), 1);
+
+        cm1.x_lock(&block1).unwrap();
+        let blocks = cm1.locked_targets();
+        cm1.x_lock(&block2).unwrap();
+        cm1.release_except(&blocks);
+
+        assert!(cm2.x_lock(&block1).is_err());
+        assert!(cm2.x_lock(&block2).is_ok());
+    }
+
+    #[test]
+    fn slock_then_xlock() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
+        let block = BlockId::new("file".to_string(), 0);
+
+        cm1.s_lock(&block).unwrap();
+        cm1.x_lock(&block).unwrap();
+
+        assert!(cm2.s_lock(&block).is_err());
+
+        cm1.release();
+        assert!(cm2.x_lock(&block).is_ok());
+    }
+
+    #[test]
+    fn short_lock_over_intention_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::ReadCommitted, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm3 =
+            ConcurrencyManager::new(3, 3, IsolationLevel::Serializable, lock_table.clone());
+        let block = BlockId::new("file".to_string(), 0);
+
+        // an intention lock on the block does not let a read skip the short lock
+        cm2.x_lock_record(&block, 1).unwrap();
+        cm1.x_lock_record(&block, 0).unwrap();
+        assert!(cm1.read_lock(&block, 40).is_err());
+
+        // the block goes back to the intention lock after the read
+        cm2.release();
+        let short_locks = cm1.read_lock(&block, 40).unwrap();
+        cm1.release_short(short_locks);
+        assert!(cm3.x_lock_record(&block, 0).is_err());
+        assert!(cm3.x_lock_record(&block, 1).is_ok());
+    }
+
+    #[test]
+    fn record_locks() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm3 =
+            ConcurrencyManager::new(3, 3, IsolationLevel::Serializable, lock_table.clone());
+        let block = BlockId::new("file".to_string(), 0);
+
+        cm1.x_lock_record(&block, 0).unwrap();
+
+        assert!(cm2.x_lock_record(&block, 1).is_ok());
+        assert!(cm2.s_lock_record(&block, 0).is_err());
+        assert!(cm3.s_lock(&block).is_err());
+    }
+
+    #[test]
+    fn file_lock_covers_blocks() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
+        let block = BlockId::new("file".to_string(), 0);
+
+        cm1.s_lock_file("file").unwrap();
+        cm1.s_lock(&block).unwrap();
+        assert_eq!(cm1.locks.len(), 1);
+
+        assert!(cm2.s_lock(&block).is_ok());
+        assert!(cm2.x_lock(&block).is_err());
+    }
+
+    #[test]
+    fn record_escalation() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
+        let block = BlockId::new("file".to_string(), 0);
+
+        for slot in 0..=RECORD_ESCALATION_THRESHOLD as i32 {
+            cm1.s_lock_record(&block, slot).unwrap();
+        }
+
+        let target = LockTarget::Block(block.clone());
+        assert_eq!(cm1.locks.get(&target), Some(&LockMode::Shared));
+        assert_eq!(cm1.locks.len(), 2);
+        assert!(cm2.s_lock_record(&block, 100).is_ok());
+        assert!(cm2.x_lock_record(&block, 101).is_err());
+    }
+
+    #[test]
+    fn escalation_keeps_records_if_blocked() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
+        let block = BlockId::new("file".to_string(), 0);
+
+        cm2.x_lock_record(&block, 100).unwrap();
+        for slot in 0..=RECORD_ESCALATION_THRESHOLD as i32 {
+            cm1.s_lock_record(&block, slot).unwrap();
+        }
+
+        let target = LockTarget::Block(block.clone());
+        assert_eq!(cm1.locks.get(&target), Some(&LockMode::IntentionShared));
+        assert_eq!(cm1.locks.len(), RECORD_ESCALATION_THRESHOLD + 3);
+    }
+
+    #[test]
+    fn block_escalation() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut cm1 =
+            ConcurrencyManager::new(1, 1, IsolationLevel::Serializable, lock_table.clone());
+        let mut cm2 =
+            ConcurrencyManager::new(2, 2, IsolationLevel::Serializable, lock_table.clone());
+
+        for block_num in 0..=BLOCK_ESCALATION_THRESHOLD as i32 {
+            let block = BlockId::new("file".to_string(), block_num);
+            cm1.x_lock(&block).unwrap();
+        }
+
+        let target = LockTarget::File("file".to_string());
+        assert_eq!(cm1.locks.get(&target), Some(&LockMode::Exclusive));
+        assert_eq!(cm1.locks.len(), 1);
+        let block = BlockId::new("file".to_string(), 100);
+        assert!(cm2.s_lock(&block).is_err());
+    }
 }
@@ -1,153 +1,726 @@
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use crate::{
+    error::DbError,
     file::block_id::BlockId,
-    util::{
-        current_time_millis, waiting_too_long, ConcurrentHashMap, CondMutex, Result,
-        MAX_WAIT_TIME_MILLIS,
-    },
+    util::{current_time_millis, CondMutex, Result, MAX_WAIT_TIME_MILLIS},
 };
 
+use super::wait_for_graph::WaitForGraph;
+
+// How conflicts between waiting transactions are kept from turning into deadlocks.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DeadlockPolicy {
+    // Wait, and abort the youngest transaction when a cycle appears.
+    Detect,
+    // An older requester waits, a younger one aborts.
+    WaitDie,
+    // An older requester wounds the younger holders, a younger one waits.
+    WoundWait,
+}
+
+// What a lock protects: a whole file, a block of it, or a record slot in a block.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum LockTarget {
+    File(String),
+    Block(BlockId),
+    Record(BlockId, i32),
+}
+
+impl LockTarget {
+    pub fn parent(&self) -> Option<LockTarget> {
+        match self {
+            LockTarget::File(_) => None,
+            LockTarget::Block(block) => Some(LockTarget::File(block.filename().to_string())),
+            LockTarget::Record(block, _) => Some(LockTarget::Block(block.clone())),
+        }
+    }
+
+    pub fn is_descendant_of(&self, other: &LockTarget) -> bool {
+        let mut ancestor = self.parent();
+        while let Some(a) = ancestor {
+            if a == *other {
+                return true;
+            }
+            ancestor = a.parent();
+        }
+        false
+    }
+}
+
+// Intention modes are taken on the ancestors of a target locked in the corresponding mode.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    SharedIntentionExclusive,
+    Exclusive,
+}
+
+impl LockMode {
+    pub fn is_compatible(&self, other: LockMode) -> bool {
+        use LockMode::*;
+        matches!(
+            (self, other),
+            (IntentionShared, IntentionShared)
+                | (IntentionShared, IntentionExclusive)
+                | (IntentionShared, Shared)
+                | (IntentionShared, SharedIntentionExclusive)
+                | (IntentionExclusive, IntentionShared)
+                | (IntentionExclusive, IntentionExclusive)
+                | (Shared, IntentionShared)
+                | (Shared, Shared)
+                | (SharedIntentionExclusive, IntentionShared)
+        )
+    }
+
+    // Whether holding this mode grants everything `other` does.
+    pub fn covers(&self, other: LockMode) -> bool {
+        use LockMode::*;
+        match self {
+            IntentionShared => other == IntentionShared,
+            IntentionExclusive => matches!(other, IntentionShared | IntentionExclusive),
+            Shared => matches!(other, IntentionShared | Shared),
+            SharedIntentionExclusive => other != Exclusive,
+            Exclusive => true,
+        }
+    }
+
+    // The weakest mode covering both.
+    pub fn supremum(&self, other: LockMode) -> LockMode {
+        if self.covers(other) {
+            *self
+        } else if other.covers(*self) {
+            other
+        } else {
+            LockMode::SharedIntentionExclusive
+        }
+    }
+
+    // The mode to take on the parent of a target locked in this mode.
+    pub fn intention(&self) -> LockMode {
+        match self {
+            LockMode::IntentionShared | LockMode::Shared => LockMode::IntentionShared,
+            _ => LockMode::IntentionExclusive,
+        }
+    }
+}
+
+// A request for a lock, made by a transaction started at `start_time`.
+#[derive(Clone, Copy)]
+struct Request {
+    tx_num: i32,
+    mode: LockMode,
+    start_time: u128,
+}
+
+#[derive(Default)]
+struct LockEntry {
+    holders: HashMap<i32, Request>,
+    // Waiting requests, granted in order.
+    queue: VecDeque<Request>,
+}
+
+impl LockEntry {
+    fn can_grant(&self, req: &Request) -> bool {
+        self.queue.front().is_some_and(|r| r.tx_num == req.tx_num) && self.is_compatible(req)
+    }
+
+    fn is_compatible(&self, req: &Request) -> bool {
+        self.holders
+            .values()
+            .all(|h| h.tx_num == req.tx_num || h.mode.is_compatible(req.mode))
+    }
+
+    // The transactions `req` has to wait for:
+    // holders of conflicting locks and the requests queued before it.
+    fn blockers(&self, req: &Request) -> HashMap<i32, u128> {
+        let holders = self
+            .holders
+            .values()
+            .filter(|h| h.tx_num != req.tx_num && !h.mode.is_compatible(req.mode));
+        let queued = self.queue.iter().take_while(|r| r.tx_num != req.tx_num);
+        holders
+            .chain(queued)
+            .map(|r| (r.tx_num, r.start_time))
+            .collect()
+    }
+
+    fn dequeue(&mut self, tx_num: i32) {
+        self.queue.retain(|r| r.tx_num != tx_num);
+    }
+}
+
 pub struct LockTable {
-    ms: ConcurrentHashMap<BlockId, CondMutex<()>>,
-    locks: HashMap<BlockId, i32>,
+    m: CondMutex<LockState>,
+}
+
+struct LockState {
+    locks: HashMap<LockTarget, LockEntry>,
+    waits: Waits,
+}
+
+impl Default for LockTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LockTable {
     pub fn new() -> Self {
         Self {
-            ms: ConcurrentHashMap::new(),
-            locks: HashMap::new(),
+            m: CondMutex::new(LockState {
+                locks: HashMap::new(),
+                waits: Waits {
+                    policy: DeadlockPolicy::Detect,
+                    max_wait_time: MAX_WAIT_TIME_MILLIS,
+                    graph: WaitForGraph::new(),
+                    wounded: HashSet::new(),
+                },
+            }),
         }
     }
 
-    pub(super) fn s_lock(&mut self, block: &BlockId) -> Result<()> {
-        let m = self.ms.get_or_insert(block, CondMutex::new(()));
-        let mut lock = m.lock();
-        let start_time = current_time_millis();
-        while Self::has_x_lock(&self.locks, block) && !waiting_too_long(start_time) {
-            lock = m.wait_timeout(lock, MAX_WAIT_TIME_MILLIS as u64)
+    // Set how long a transaction may wait for a lock before giving up.
+    pub fn with_max_wait_time(self, millis: u128) -> Self {
+        self.m.lock().waits.max_wait_time = millis;
+        self
+    }
+
+    pub fn with_policy(self, policy: DeadlockPolicy) -> Self {
+        self.m.lock().waits.policy = policy;
+        self
+    }
+
+    pub(super) fn s_lock(&self, block: &BlockId, tx_num: i32, tx_start: u128) -> Result<()> {
+        let target = LockTarget::Block(block.clone());
+        self.lock(&target, tx_num, tx_start, LockMode::Shared)
+    }
+
+    pub(super) fn x_lock(&self, block: &BlockId, tx_num: i32, tx_start: u128) -> Result<()> {
+        let target = LockTarget::Block(block.clone());
+        self.lock(&target, tx_num, tx_start, LockMode::Exclusive)
+    }
+
+    // Grant the lock only if it is available right away.
+    pub(super) fn try_lock(
+        &self,
+        target: &LockTarget,
+        tx_num: i32,
+        tx_start: u128,
+        mode: LockMode,
+    ) -> bool {
+        let mut state = self.m.lock();
+        let entry = state.locks.entry(target.clone()).or_default();
+        let mode = match entry.holders.get(&tx_num) {
+            Some(held) if held.mode.covers(mode) => return true,
+            Some(held) => held.mode.supremum(mode),
+            None => mode,
+        };
+        let req = Request {
+            tx_num,
+            mode,
+            start_time: tx_start,
+        };
+        if !entry.queue.is_empty() || !entry.is_compatible(&req) {
+            if entry.holders.is_empty() {
+                state.locks.remove(target);
+            }
+            return false;
         }
-        if Self::has_x_lock(&self.locks, block) {
-            return Err("deadlock".into());
+        entry.holders.insert(tx_num, req);
+        true
+    }
+
+    pub(super) fn unlock(&self, target: &LockTarget, tx_num: i32) {
+        let mut state = self.m.lock();
+        if let Some(entry) = state.locks.get_mut(target) {
+            entry.holders.remove(&tx_num);
+            if entry.holders.is_empty() && entry.queue.is_empty() {
+                state.locks.remove(target);
+            }
         }
-        let value = Self::lock_value(&self.locks, block);
-        self.locks.insert(block.clone(), value + 1);
-        Ok(())
+        self.m.notify_all();
     }
 
-    pub(super) fn x_lock(&mut self, block: &BlockId) -> Result<()> {
-        let m = self.ms.get_or_insert(block, CondMutex::new(()));
-        let mut lock = m.lock();
-        let start_time = current_time_millis();
-        while Self::has_other_s_locks(&self.locks, block) && !waiting_too_long(start_time) {
-            lock = m.wait_timeout(lock, MAX_WAIT_TIME_MILLIS as u64);
+    // Go back to a weaker mode, e.g. once a short lock taken over an intention lock is done.
+    pub(super) fn downgrade(&self, target: &LockTarget, tx_num: i32, mode: LockMode) {
+        let mut state = self.m.lock();
+        if let Some(holder) = state
+            .locks
+            .get_mut(target)
+            .and_then(|entry| entry.holders.get_mut(&tx_num))
+        {
+            holder.mode = mode;
         }
-        if Self::has_other_s_locks(&self.locks, block) {
-            return Err("deadlock".into());
+        self.m.notify_all();
+    }
+
+    // Hand the locks of `from` over to `to`, and return them.
+    pub(super) fn transfer(&self, from: i32, to: i32) -> Vec<(LockTarget, LockMode)> {
+        let mut state = self.m.lock();
+        let mut transferred = vec![];
+        for (target, entry) in state.locks.iter_mut() {
+            let Some(mut req) = entry.holders.remove(&from) else {
+                continue;
+            };
+            req.tx_num = to;
+            if let Some(held) = entry.holders.get(&to) {
+                req.mode = held.mode.supremum(req.mode);
+                req.start_time = held.start_time;
+            }
+            entry.holders.insert(to, req);
+            transferred.push((target.clone(), req.mode));
         }
-        self.locks.insert(block.clone(), -1);
+        transferred
+    }
 
-        Ok(())
+    pub(super) fn is_wounded(&self, tx_num: i32) -> bool {
+        self.m.lock().waits.wounded.contains(&tx_num)
     }
 
-    pub(super) fn unlock(&mut self, block: &BlockId) {
-        let m = self.ms.get_or_insert(block, CondMutex::new(()));
-        let _lock = m.lock();
-        let value = Self::lock_value(&self.locks, block);
-        if value > 1 {
-            self.locks.insert(block.clone(), value - 1);
+    // Forget that the transaction was wounded, once it has released its locks.
+    pub(super) fn heal(&self, tx_num: i32) {
+        self.m.lock().waits.wounded.remove(&tx_num);
+    }
+
+    pub(super) fn lock(
+        &self,
+        target: &LockTarget,
+        tx_num: i32,
+        tx_start: u128,
+        mode: LockMode,
+    ) -> Result<()> {
+        let mut state = self.m.lock();
+        let entry = state.locks.entry(target.clone()).or_default();
+        let held = entry.holders.get(&tx_num).map(|h| h.mode);
+        if held.is_some_and(|held| held.covers(mode)) {
+            return Ok(());
+        }
+        let req = Request {
+            tx_num,
+            mode: held.map_or(mode, |held| held.supremum(mode)),
+            start_time: tx_start,
+        };
+        if held.is_some() {
+            // An upgrade goes first, since the holders queued behind it would wait for it anyway.
+            entry.queue.push_front(req);
         } else {
-            self.locks.remove(block);
-            m.notify_all();
+            entry.queue.push_back(req);
         }
-    }
 
-    fn has_x_lock(locks: &HashMap<BlockId, i32>, block: &BlockId) -> bool {
-        Self::lock_value(locks, block) < 0
+        let start_time = current_time_millis();
+        loop {
+            let LockState { locks, waits } = &mut *state;
+            let entry = locks.get_mut(target).unwrap();
+            if entry.can_grant(&req) {
+                entry.dequeue(tx_num);
+                entry.holders.insert(tx_num, req);
+                break;
+            }
+            match waits.before_wait(&entry.blockers(&req), tx_num, tx_start, start_time) {
+                Ok(true) => self.m.notify_all(),
+                Ok(false) => {}
+                Err(e) => {
+                    entry.dequeue(tx_num);
+                    if entry.holders.is_empty() && entry.queue.is_empty() {
+                        locks.remove(target);
+                    }
+                    // The requests queued behind this one may be granted now.
+                    self.m.notify_all();
+                    return Err(e);
+                }
+            }
+            let max_wait_time = waits.max_wait_time as u64;
+            state = self.m.wait_timeout(state, max_wait_time);
+        }
+        state.waits.graph.remove(tx_num);
+        // The requests queued behind this one may be compatible with it.
+        self.m.notify_all();
+        Ok(())
     }
+}
 
-    fn has_other_s_locks(locks: &HashMap<BlockId, i32>, block: &BlockId) -> bool {
-        Self::lock_value(locks, block) > 1
-    }
+struct Waits {
+    policy: DeadlockPolicy,
+    max_wait_time: u128,
+    graph: WaitForGraph,
+    wounded: HashSet<i32>,
+}
 
-    fn lock_value(locks: &HashMap<BlockId, i32>, block: &BlockId) -> i32 {
-        locks.get(block).map_or(0, |v| *v)
+impl Waits {
+    // Register that `tx_num` is about to wait for `blockers`.
+    // Fails if `tx_num` has to abort instead of waiting,
+    // and returns whether other waiters must be woken to notice they have to abort.
+    fn before_wait(
+        &mut self,
+        blockers: &HashMap<i32, u128>,
+        tx_num: i32,
+        tx_start: u128,
+        start_time: u128,
+    ) -> Result<bool> {
+        if self.wounded.contains(&tx_num) {
+            self.graph.remove(tx_num);
+            return Err(DbError::Wounded);
+        }
+        if self.graph.is_victim(tx_num) {
+            self.graph.remove(tx_num);
+            return Err(DbError::Deadlock);
+        }
+        if current_time_millis() - start_time > self.max_wait_time {
+            self.graph.remove(tx_num);
+            return Err(DbError::LockTimeout);
+        }
+        // Ties on the start time are broken by the tx number.
+        let age = (tx_start, tx_num);
+        match self.policy {
+            DeadlockPolicy::Detect => {
+                let blockers = blockers.keys().cloned().collect();
+                match self.graph.add(tx_num, blockers) {
+                    Some(victim) if victim == tx_num => {
+                        self.graph.remove(tx_num);
+                        Err(DbError::Deadlock)
+                    }
+                    Some(_) => Ok(true),
+                    None => Ok(false),
+                }
+            }
+            DeadlockPolicy::WaitDie => {
+                if blockers.iter().any(|(&t, &start)| (start, t) < age) {
+                    return Err(DbError::Deadlock);
+                }
+                Ok(false)
+            }
+            DeadlockPolicy::WoundWait => {
+                let mut notify = false;
+                for (&t, &start) in blockers {
+                    if (start, t) > age {
+                        notify |= self.wounded.insert(t);
+                    }
+                }
+                Ok(notify)
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
     use super::*;
 
+    fn held_mode(lock_table: &LockTable, block: &BlockId, tx_num: i32) -> Option<LockMode> {
+        let state = lock_table.m.lock();
+        let entry = state.locks.get(&LockTarget::Block(block.clone()))?;
+        entry.holders.get(&tx_num).map(|h| h.mode)
+    }
+
     #[test]
     fn slock_then_slock() {
-        let mut lock_table = LockTable::new();
+        let lock_table = LockTable::new();
         let block = BlockId::new("file".to_string(), 0);
 
-        lock_table.s_lock(&block).unwrap();
+        lock_table.s_lock(&block, 1, 1).unwrap();
 
-        let res = lock_table.s_lock(&block);
+        let res = lock_table.s_lock(&block, 2, 2);
         assert!(res.is_ok());
     }
 
     #[test]
     fn slock_then_xlock() {
-        let mut lock_table = LockTable::new();
+        let lock_table = LockTable::new();
         let block = BlockId::new("file".to_string(), 0);
 
-        lock_table.x_lock(&block).unwrap();
+        lock_table.x_lock(&block, 1, 1).unwrap();
 
-        let res = lock_table.s_lock(&block);
+        let res = lock_table.s_lock(&block, 2, 2);
         assert!(res.is_err());
     }
 
     #[test]
     fn xlock_then_slock() {
-        let mut lock_table = LockTable::new();
+        let lock_table = LockTable::new();
         let block = BlockId::new("file".to_string(), 0);
 
-        lock_table.x_lock(&block).unwrap();
+        lock_table.x_lock(&block, 1, 1).unwrap();
 
-        let res = lock_table.s_lock(&block);
+        let res = lock_table.s_lock(&block, 2, 2);
         assert!(res.is_err());
     }
 
     #[test]
     fn xlock_then_xlock() {
-        let mut lock_table = LockTable::new();
+        let lock_table = LockTable::new();
         let block = BlockId::new("file".to_string(), 0);
 
-        lock_table.x_lock(&block).unwrap();
+        lock_table.x_lock(&block, 1, 1).unwrap();
 
-        let res = lock_table.x_lock(&block);
-        assert!(res.is_ok()); // x_lock同士の競合はConcurrencyManagerレベルで解決される
+        let res = lock_table.x_lock(&block, 2, 2);
+        assert!(res.is_err());
     }
 
     #[test]
     fn slock_then_unlock() {
-        let mut lock_table = LockTable::new();
+        let lock_table = LockTable::new();
         let block = BlockId::new("file".to_string(), 0);
 
-        lock_table.s_lock(&block).unwrap();
-        assert_eq!(LockTable::lock_value(&lock_table.locks, &block), 1);
+        lock_table.s_lock(&block, 1, 1).unwrap();
+        assert_eq!(held_mode(&lock_table, &block, 1), Some(LockMode::Shared));
 
-        lock_table.unlock(&block);
+        lock_table.unlock(&LockTarget::Block(block.clone()), 1);
 
-        assert_eq!(LockTable::lock_value(&lock_table.locks, &block), 0);
+        assert!(lock_table.m.lock().locks.is_empty());
     }
 
     #[test]
     fn xlock_then_unlock() {
-        let mut lock_table = LockTable::new();
+        let lock_table = LockTable::new();
         let block = BlockId::new("file".to_string(), 0);
 
-        lock_table.x_lock(&block).unwrap();
-        assert_eq!(LockTable::lock_value(&lock_table.locks, &block), -1);
+        lock_table.x_lock(&block, 1, 1).unwrap();
+        assert_eq!(held_mode(&lock_table, &block, 1), Some(LockMode::Exclusive));
+
+        lock_table.unlock(&LockTarget::Block(block.clone()), 1);
+        assert!(lock_table.m.lock().locks.is_empty());
+    }
+
+    #[test]
+    fn lock_wait_timeout() {
+        let lock_table = LockTable::new();
+        let block = BlockId::new("file".to_string(), 0);
+
+        lock_table.x_lock(&block, 1, 1).unwrap();
+
+        let res = lock_table.s_lock(&block, 2, 2);
+        assert_eq!(res.unwrap_err().to_string(), "lock wait timeout");
+    }
+
+    #[test]
+    fn deadlock_requester_is_victim() {
+        let lock_table = Arc::new(LockTable::new().with_max_wait_time(10_000));
+        let block1 = BlockId::new("file".to_string(), 0);
+        let block2 = BlockId::new("file".to_string(), 1);
+        lock_table.x_lock(&block1, 1, 1).unwrap();
+        lock_table.x_lock(&block2, 2, 2).unwrap();
+
+        let handle = {
+            let lock_table = lock_table.clone();
+            let block2 = block2.clone();
+            thread::spawn(move || lock_table.s_lock(&block2, 1, 1).is_ok())
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        let start_time = current_time_millis();
+        let res = lock_table.s_lock(&block1, 2, 2);
+        assert_eq!(res.unwrap_err().to_string(), "deadlock");
+        assert!(current_time_millis() - start_time < 10_000);
+
+        lock_table.unlock(&LockTarget::Block(block2.clone()), 2);
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn deadlock_waiter_is_victim() {
+        let lock_table = Arc::new(LockTable::new().with_max_wait_time(10_000));
+        let block1 = BlockId::new("file".to_string(), 0);
+        let block2 = BlockId::new("file".to_string(), 1);
+        lock_table.x_lock(&block1, 1, 1).unwrap();
+        lock_table.x_lock(&block2, 2, 2).unwrap();
+
+        // The younger transaction waits first and is chosen when the older one closes the cycle.
+        let handle = {
+            let lock_table = lock_table.clone();
+            let block1 = block1.clone();
+            let block2 = block2.clone();
+            thread::spawn(move || {
+                let res = lock_table.s_lock(&block1, 2, 2);
+                lock_table.unlock(&LockTarget::Block(block2.clone()), 2);
+                res.unwrap_err().to_string()
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        let res = lock_table.s_lock(&block2, 1, 1);
+        assert!(res.is_ok());
+        assert_eq!(handle.join().unwrap(), "deadlock");
+    }
+
+    #[test]
+    fn wait_die_younger_dies() {
+        let lock_table = LockTable::new()
+            .with_policy(DeadlockPolicy::WaitDie)
+            .with_max_wait_time(10_000);
+        let block = BlockId::new("file".to_string(), 0);
+
+        lock_table.x_lock(&block, 1, 1).unwrap();
+
+        let start_time = current_time_millis();
+        let res = lock_table.s_lock(&block, 2, 2);
+        assert_eq!(res.unwrap_err().to_string(), "deadlock");
+        assert!(current_time_millis() - start_time < 10_000);
+    }
+
+    #[test]
+    fn wait_die_older_waits() {
+        let lock_table = LockTable::new().with_policy(DeadlockPolicy::WaitDie);
+        let block = BlockId::new("file".to_string(), 0);
+
+        lock_table.x_lock(&block, 2, 2).unwrap();
+
+        let res = lock_table.s_lock(&block, 1, 1);
+        assert_eq!(res.unwrap_err().to_string(), "lock wait timeout");
+    }
+
+    #[test]
+    fn wound_wait_older_wounds() {
+        let lock_table = LockTable::new().with_policy(DeadlockPolicy::WoundWait);
+        let block = BlockId::new("file".to_string(), 0);
+
+        lock_table.x_lock(&block, 2, 2).unwrap();
+
+        let res = lock_table.s_lock(&block, 1, 1);
+        assert_eq!(res.unwrap_err().to_string(), "lock wait timeout");
+        assert!(lock_table.is_wounded(2));
+
+        lock_table.unlock(&LockTarget::Block(block.clone()), 2);
+        lock_table.heal(2);
+        assert!(!lock_table.is_wounded(2));
+    }
+
+    #[test]
+    fn wound_wait_younger_waits() {
+        let lock_table = LockTable::new().with_policy(DeadlockPolicy::WoundWait);
+        let block = BlockId::new("file".to_string(), 0);
+
+        lock_table.x_lock(&block, 1, 1).unwrap();
+
+        let res = lock_table.s_lock(&block, 2, 2);
+        assert_eq!(res.unwrap_err().to_string(), "lock wait timeout");
+        assert!(!lock_table.is_wounded(1));
+    }
+
+    #[test]
+    fn start_time_decides_age() {
+        let lock_table = LockTable::new()
+            .with_policy(DeadlockPolicy::WaitDie)
+            .with_max_wait_time(10_000);
+        let block = BlockId::new("file".to_string(), 0);
+
+        // tx 2 started before tx 1, so tx 1 is younger
+        lock_table.x_lock(&block, 2, 1).unwrap();
+
+        let res = lock_table.s_lock(&block, 1, 2);
+        assert_eq!(res.unwrap_err().to_string(), "deadlock");
+    }
+
+    #[test]
+    fn upgrade() {
+        let lock_table = LockTable::new();
+        let block = BlockId::new("file".to_string(), 0);
+
+        lock_table.s_lock(&block, 1, 1).unwrap();
+        lock_table.x_lock(&block, 1, 1).unwrap();
+        assert_eq!(held_mode(&lock_table, &block, 1), Some(LockMode::Exclusive));
+
+        // a weaker lock is already covered
+        lock_table.s_lock(&block, 1, 1).unwrap();
+        assert_eq!(held_mode(&lock_table, &block, 1), Some(LockMode::Exclusive));
+    }
+
+    #[test]
+    fn upgrade_waits_for_other_readers() {
+        let lock_table = LockTable::new();
+        let block = BlockId::new("file".to_string(), 0);
+
+        lock_table.s_lock(&block, 1, 1).unwrap();
+        lock_table.s_lock(&block, 2, 2).unwrap();
+
+        let res = lock_table.x_lock(&block, 1, 1);
+        assert_eq!(res.unwrap_err().to_string(), "lock wait timeout");
+        assert_eq!(held_mode(&lock_table, &block, 1), Some(LockMode::Shared));
+
+        lock_table.unlock(&LockTarget::Block(block.clone()), 2);
+        assert!(lock_table.x_lock(&block, 1, 1).is_ok());
+    }
+
+    #[test]
+    fn fifo() {
+        let lock_table = Arc::new(LockTable::new().with_max_wait_time(10_000));
+        let block = BlockId::new("file".to_string(), 0);
+        let granted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        lock_table.s_lock(&block, 1, 1).unwrap();
+
+        let spawn = |tx_num: i32, mode: LockMode| {
+            let lock_table = lock_table.clone();
+            let target = LockTarget::Block(block.clone());
+            let granted = granted.clone();
+            thread::spawn(move || {
+                lock_table
+                    .lock(&target, tx_num, tx_num as u128, mode)
+                    .unwrap();
+                granted.lock().unwrap().push(tx_num);
+                thread::sleep(Duration::from_millis(50));
+                lock_table.unlock(&target, tx_num);
+            })
+        };
+        let writer = spawn(2, LockMode::Exclusive);
+        thread::sleep(Duration::from_millis(50));
+        // compatible with the held lock, but queued behind the writer
+        let reader = spawn(3, LockMode::Shared);
+        thread::sleep(Duration::from_millis(50));
+        assert!(granted.lock().unwrap().is_empty());
+
+        lock_table.unlock(&LockTarget::Block(block.clone()), 1);
+        writer.join().unwrap();
+        reader.join().unwrap();
+        assert_eq!(*granted.lock().unwrap(), vec![2, 3]);
+    }
+
+    #[test]
+    fn modes() {
+        use LockMode::*;
+        let modes = [
+            IntentionShared,
+            IntentionExclusive,
+            Shared,
+            SharedIntentionExclusive,
+            Exclusive,
+        ];
+        for a in modes {
+            for b in modes {
+                assert_eq!(a.is_compatible(b), b.is_compatible(a));
+                let sup = a.supremum(b);
+                assert!(sup.covers(a) && sup.covers(b));
+            }
+        }
+        assert_eq!(
+            Shared.supremum(IntentionExclusive),
+            SharedIntentionExclusive
+        );
+        assert_eq!(IntentionShared.supremum(Shared), Shared);
+        assert!(!Shared.is_compatible(IntentionExclusive));
+        assert!(SharedIntentionExclusive.is_compatible(IntentionShared));
+    }
+
+    #[test]
+    fn intention_locks() {
+        let lock_table = LockTable::new();
+        let file = LockTarget::File("file".to_string());
+
+        lock_table
+            .lock(&file, 1, 1, LockMode::IntentionShared)
+            .unwrap();
+        lock_table
+            .lock(&file, 2, 2, LockMode::IntentionExclusive)
+            .unwrap();
 
-        lock_table.unlock(&block);
-        assert_eq!(LockTable::lock_value(&lock_table.locks, &block), 0);
+        let res = lock_table.lock(&file, 3, 3, LockMode::Shared);
+        assert_eq!(res.unwrap_err().to_string(), "lock wait timeout");
+        assert!(!lock_table.try_lock(&file, 3, 3, LockMode::Shared));
+        assert!(lock_table.try_lock(&file, 3, 3, LockMode::IntentionShared));
     }
 }
@@ -0,0 +1,256 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use crate::{error::DbError, file::block_id::BlockId, util::Result};
+
+// The byte ranges read or written in each block.
+#[derive(Default, Clone, Debug)]
+pub struct RangeSet {
+    ranges: HashMap<BlockId, Vec<(i32, i32)>>,
+}
+
+impl RangeSet {
+    pub fn insert(&mut self, block: &BlockId, offset: i32, len: i32) {
+        let ranges = self.ranges.entry(block.clone()).or_default();
+        ranges.push((offset, offset + len));
+    }
+
+    // The whole block, for values that have no offset like the size of a file.
+    pub fn insert_block(&mut self, block: &BlockId) {
+        self.insert(block, 0, i32::MAX);
+    }
+
+    pub fn overlaps(&self, block: &BlockId, offset: i32, len: i32) -> bool {
+        self.ranges.get(block).is_some_and(|ranges| {
+            ranges
+                .iter()
+                .any(|&(start, end)| start < offset + len && offset < end)
+        })
+    }
+
+    pub fn intersects(&self, other: &RangeSet) -> bool {
+        other.ranges.iter().any(|(block, ranges)| {
+            ranges
+                .iter()
+                .any(|&(start, end)| self.overlaps(block, start, end - start))
+        })
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &BlockId> {
+        self.ranges.keys()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    commit_count: u64,
+    // The commit count when each running optimistic transaction started.
+    started: HashMap<i32, u64>,
+    // The write sets of committed transactions, in commit order.
+    committed: VecDeque<(u64, RangeSet)>,
+}
+
+// Backward validation of optimistic transactions:
+// a transaction may commit only if no transaction that committed after it started
+// wrote something it read, whether that transaction was optimistic or used locks.
+// Validation and the write phase run one transaction at a time,
+// so the write phase must not wait for locks.
+pub struct OccValidator {
+    state: Mutex<State>,
+}
+
+impl Default for OccValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OccValidator {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn begin(&self, tx_num: i32) {
+        let mut state = self.state.lock().unwrap();
+        let commit_count = state.commit_count;
+        state.started.insert(tx_num, commit_count);
+    }
+
+    // Validate the read set, then apply the writes with `write_phase`.
+    pub fn validate(
+        &self,
+        tx_num: i32,
+        read_set: &RangeSet,
+        write_set: RangeSet,
+        write_phase: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let start = *state.started.get(&tx_num).ok_or(DbError::NotOptimistic)?;
+        let conflict = state
+            .committed
+            .iter()
+            .filter(|(count, _)| *count > start)
+            .any(|(_, writes)| writes.intersects(read_set));
+        if conflict {
+            return Err(DbError::ValidationFailure);
+        }
+        write_phase()?;
+        Self::push(&mut state, write_set);
+        Ok(())
+    }
+
+    // Record the writes of a transaction committing or rolling back under locks.
+    // It must be called before the locks are released.
+    pub fn record(&self, write_set: RangeSet) {
+        let mut state = self.state.lock().unwrap();
+        // A transaction starting later sees the writes, so only running ones need them.
+        if state.started.is_empty() || write_set.is_empty() {
+            return;
+        }
+        Self::push(&mut state, write_set);
+    }
+
+    fn push(state: &mut State, write_set: RangeSet) {
+        state.commit_count += 1;
+        let commit_count = state.commit_count;
+        state.committed.push_back((commit_count, write_set));
+    }
+
+    // Forget a finished transaction, and the write sets no running transaction needs to validate against.
+    pub fn finish(&self, tx_num: i32) {
+        let mut state = self.state.lock().unwrap();
+        state.started.remove(&tx_num);
+        let oldest = state
+            .started
+            .values()
+            .min()
+            .cloned()
+            .unwrap_or(state.commit_count);
+        while state
+            .committed
+            .front()
+            .is_some_and(|(count, _)| *count <= oldest)
+        {
+            state.committed.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locations(offsets: &[i32]) -> RangeSet {
+        let block = BlockId::new("file".to_string(), 0);
+        let mut set = RangeSet::default();
+        for &offset in offsets {
+            set.insert(&block, offset, 4);
+        }
+        set
+    }
+
+    #[test]
+    fn conflict() {
+        let validator = OccValidator::new();
+        validator.begin(1);
+        validator.begin(2);
+
+        assert!(validator
+            .validate(1, &locations(&[0]), locations(&[0]), || Ok(()))
+            .is_ok());
+        validator.finish(1);
+        let err = validator
+            .validate(2, &locations(&[0, 4]), locations(&[4]), || Ok(()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "optimistic validation failed");
+    }
+
+    #[test]
+    fn no_conflict() {
+        let validator = OccValidator::new();
+        validator.begin(1);
+        validator.begin(2);
+
+        assert!(validator
+            .validate(1, &locations(&[0]), locations(&[0]), || Ok(()))
+            .is_ok());
+        assert!(validator
+            .validate(2, &locations(&[4]), locations(&[4]), || Ok(()))
+            .is_ok());
+    }
+
+    #[test]
+    fn committed_before_start() {
+        let validator = OccValidator::new();
+        validator.begin(1);
+        assert!(validator
+            .validate(1, &locations(&[]), locations(&[0]), || Ok(()))
+            .is_ok());
+        validator.finish(1);
+
+        validator.begin(2);
+        assert!(validator
+            .validate(2, &locations(&[0]), locations(&[0]), || Ok(()))
+            .is_ok());
+        validator.finish(2);
+        assert!(validator.state.lock().unwrap().committed.is_empty());
+    }
+
+    #[test]
+    fn failed_write_phase() {
+        let validator = OccValidator::new();
+        validator.begin(1);
+        validator.begin(2);
+
+        assert!(validator
+            .validate(1, &locations(&[0]), locations(&[0]), || Err(
+                DbError::BufferAbort
+            ))
+            .is_err());
+        assert!(validator
+            .validate(2, &locations(&[0]), locations(&[0]), || Ok(()))
+            .is_ok());
+    }
+
+    #[test]
+    fn overlapping_ranges_conflict() {
+        let validator = OccValidator::new();
+        validator.begin(1);
+        validator.begin(2);
+
+        let block = BlockId::new("file".to_string(), 0);
+        let mut string = RangeSet::default();
+        string.insert(&block, 0, 12);
+        assert!(validator
+            .validate(1, &RangeSet::default(), string, || Ok(()))
+            .is_ok());
+        let err = validator
+            .validate(2, &locations(&[8]), locations(&[8]), || Ok(()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "optimistic validation failed");
+    }
+
+    #[test]
+    fn lock_based_writes_conflict() {
+        let validator = OccValidator::new();
+        validator.record(locations(&[0]));
+        assert!(validator.state.lock().unwrap().committed.is_empty());
+
+        validator.begin(1);
+        validator.record(locations(&[0]));
+        let err = validator
+            .validate(1, &locations(&[0]), locations(&[4]), || Ok(()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "optimistic validation failed");
+    }
+}
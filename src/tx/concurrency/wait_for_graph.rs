@@ -0,0 +1,101 @@
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+
+// Edges from waiting transactions to the transactions holding the locks they wait for.
+pub struct WaitForGraph {
+    edges: HashMap<i32, HashSet<i32>>,
+    victims: HashSet<i32>,
+}
+
+impl Default for WaitForGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WaitForGraph {
+    pub fn new() -> Self {
+        Self {
+            edges: HashMap::new(),
+            victims: HashSet::new(),
+        }
+    }
+
+    // Record that `tx_num` waits for `blockers`.
+    // If this closes a cycle, the youngest transaction on it is chosen as the victim and returned.
+    pub fn add(&mut self, tx_num: i32, blockers: HashSet<i32>) -> Option<i32> {
+        self.edges.insert(tx_num, blockers);
+        let cycle = self.find_cycle(tx_num)?;
+        let victim = *cycle.iter().max().unwrap();
+        self.victims.insert(victim);
+        Some(victim)
+    }
+
+    // Stop waiting, either because the lock was granted or the wait was given up.
+    pub fn remove(&mut self, tx_num: i32) {
+        self.edges.remove(&tx_num);
+        self.victims.remove(&tx_num);
+    }
+
+    pub fn is_victim(&self, tx_num: i32) -> bool {
+        self.victims.contains(&tx_num)
+    }
+
+    fn find_cycle(&self, start: i32) -> Option<Vec<i32>> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![vec![start]];
+        while let Some(path) = stack.pop() {
+            let last = *path.last().unwrap();
+            for &next in self.edges.get(&last).into_iter().flatten() {
+                if next == start {
+                    return Some(path);
+                }
+                if visited.insert(next) {
+                    let mut path = path.clone();
+                    path.push(next);
+                    stack.push(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_cycle() {
+        let mut graph = WaitForGraph::new();
+
+        assert_eq!(graph.add(1, HashSet::from([2])), None);
+        assert_eq!(graph.add(2, HashSet::from([3])), None);
+        assert_eq!(graph.add(4, HashSet::from([1, 3])), None);
+    }
+
+    #[test]
+    fn cycle() {
+        let mut graph = WaitForGraph::new();
+
+        assert_eq!(graph.add(3, HashSet::from([1])), None);
+        assert_eq!(graph.add(1, HashSet::from([2])), None);
+        assert_eq!(graph.add(2, HashSet::from([3])), Some(3)); // the youngest is the victim
+
+        assert!(graph.is_victim(3));
+        assert!(!graph.is_victim(2));
+    }
+
+    #[test]
+    fn remove() {
+        let mut graph = WaitForGraph::new();
+
+        graph.add(1, HashSet::from([2]));
+        assert_eq!(graph.add(2, HashSet::from([1])), Some(2));
+
+        graph.remove(2);
+        assert!(!graph.is_victim(2));
+        assert_eq!(graph.add(3, HashSet::from([1])), None);
+    }
+}
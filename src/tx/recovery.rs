@@ -1,9 +1,17 @@
+pub mod change_stream;
 pub mod checkpoint_record;
 pub mod commit_record;
+pub mod commit_tracker;
+pub mod log_dump;
+pub mod log_reader;
 pub mod log_record;
+pub mod log_replay;
 pub mod nq_ckpt_record;
+pub mod prepare_record;
 pub mod recovery_manager;
 pub mod rollback_record;
+pub mod rollback_to_record;
+pub mod savepoint_record;
 pub mod set_bool_record;
 pub mod set_bytes_record;
 pub mod set_date_record;
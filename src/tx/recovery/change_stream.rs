@@ -0,0 +1,432 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    sync::Arc,
+};
+
+use serde_json::{Map, Value};
+
+use crate::{
+    error::DbError,
+    file::page::Page,
+    log::{
+        log_iterator::{Direction, LogPosition},
+        log_manager::LogManager,
+    },
+    query::constant::Constant,
+    record::{
+        layout::{Layout, IS_USED_FLAG_NAME, XMAX_OFFSET},
+        rid::Rid,
+    },
+    util::Result,
+};
+
+use super::{
+    commit_tracker::{Changes, CommitTracker, Settled},
+    log_record::{create_log_record, LogRecord, CHECKPOINT, COMMIT, NQCKPT, ROLLBACK, START},
+    nq_ckpt_record::NqCkptRecord,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl ChangeKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ChangeKind::Insert => "insert",
+            ChangeKind::Update => "update",
+            ChangeKind::Delete => "delete",
+        }
+    }
+}
+
+// The change of a record by a committed transaction.
+pub struct ChangeEvent {
+    // The LSN of the COMMIT record.
+    pub lsn: i32,
+    pub tx_num: i32,
+    pub table: String,
+    pub rid: Rid,
+    pub kind: ChangeKind,
+    // The new values of the fields written by the transaction, null for the fields set to null.
+    pub values: Map<String, Value>,
+}
+
+impl ChangeEvent {
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "lsn": self.lsn,
+            "tx": self.tx_num,
+            "table": self.table,
+            "rid": {"block": self.rid.block_num(), "slot": self.rid.slot()},
+            "op": self.kind.name(),
+            "values": self.values,
+        })
+    }
+}
+
+// Tails the committed changes of the tables it knows the layouts of.
+// The changes of a transaction are held back until its COMMIT record,
+// then turned into one event per record it changed, in the order the records were first changed.
+// Writes to other files, such as indexes and temporary tables, are left out.
+pub struct ChangeStream {
+    lm: Arc<LogManager>,
+    // The layouts of the tables, by file name.
+    tables: HashMap<String, (String, Arc<Layout>)>,
+    tracker: CommitTracker,
+    // The events of the commits up to this LSN are not emitted.
+    start_lsn: i32,
+    last_lsn: i32,
+    // The block of the last record read, where the next poll starts reading.
+    position: LogPosition,
+}
+
+impl ChangeStream {
+    pub fn new(lm: Arc<LogManager>) -> Self {
+        Self {
+            lm,
+            tables: HashMap::new(),
+            tracker: CommitTracker::new(),
+            start_lsn: 0,
+            last_lsn: 0,
+            position: LogPosition::default(),
+        }
+    }
+
+    pub fn with_table(mut self, table_name: &str, layout: Arc<Layout>) -> Self {
+        self.tables.insert(
+            format!("{}.tbl", table_name),
+            (table_name.to_string(), layout),
+        );
+        self
+    }
+
+    // Resume after the commit `lsn`, e.g. the last one a consumer saw.
+    // The first poll reads from the start of the oldest transaction unfinished at `lsn`,
+    // to find the earlier changes of the later commits.
+    pub fn starting_after(mut self, lsn: i32) -> Self {
+        self.start_lsn = lsn;
+        self
+    }
+
+    // The events of the transactions committed since the last call.
+    // A read error after some commits is returned by the next call, so that no event is lost.
+    // Records removed from the log by a checkpoint before they were read are reported
+    // as a missing segment, since their events are lost.
+    pub fn poll(&mut self) -> Result<Vec<ChangeEvent>> {
+        if self.last_lsn == 0 && self.start_lsn > 0 {
+            self.seek_start()?;
+        }
+        let mut events = vec![];
+        let mut records = self.lm.iter_at(self.position, self.last_lsn + 1)?;
+        while let Some(record) = records.next() {
+            let (lsn, bytes) = match record {
+                Ok(record) => record,
+                Err(_) if !events.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            // LSNs are given one after another, so a gap is the records of a removed segment.
+            if self.last_lsn > 0 && lsn != self.last_lsn + 1 {
+                return Err(DbError::MissingLogSegment(self.position.segment));
+            }
+            self.position = records.position();
+            self.last_lsn = lsn;
+            if let Some(Settled::Committed(tx_num, changes)) = self.tracker.track(lsn, bytes) {
+                if lsn > self.start_lsn {
+                    events.extend(self.events(lsn, tx_num, &changes));
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    // Move to the START record of the oldest transaction unfinished at the commit `start_lsn`,
+    // reading back from it no further than needed past the last checkpoint.
+    fn seek_start(&mut self) -> Result<()> {
+        let mut finished: HashSet<i32> = HashSet::new();
+        let mut unstarted: HashSet<i32> = HashSet::new();
+        let mut checkpointed = false;
+        let mut records = self.lm.iter_from(self.start_lsn, Direction::Backward)?;
+        while let Some(record) = records.next() {
+            let (lsn, bytes) = record?;
+            self.position = records.position();
+            self.last_lsn = lsn - 1;
+            let Some(rec) = create_log_record(bytes.clone()) else {
+                continue;
+            };
+            let tx_num = rec.tx_num();
+            match rec.op() {
+                // No transaction is unfinished at a checkpoint.
+                CHECKPOINT => break,
+                // The transactions it lists were unfinished, and the others started after it.
+                NQCKPT => {
+                    let listed = NqCkptRecord::from(Page::from(bytes)).tx_nums();
+                    unstarted.extend(listed.difference(&finished));
+                    checkpointed = true;
+                }
+                COMMIT | ROLLBACK => {
+                    finished.insert(tx_num);
+                }
+                START => {
+                    unstarted.remove(&tx_num);
+                }
+                _ if !finished.contains(&tx_num) => {
+                    unstarted.insert(tx_num);
+                }
+                _ => {}
+            }
+            if checkpointed && unstarted.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Write the events of the transactions committed since the last call as JSON Lines,
+    // and return how many were written.
+    pub fn poll_json_lines(&mut self, out: &mut impl Write) -> Result<usize> {
+        let events = self.poll()?;
+        for event in &events {
+            writeln!(out, "{}", event.to_json())?;
+        }
+        out.flush()?;
+        Ok(events.len())
+    }
+
+    fn events(&self, lsn: i32, tx_num: i32, changes: &Changes) -> Vec<ChangeEvent> {
+        let mut events: Vec<ChangeEvent> = vec![];
+        // The index of the event of each changed record, by file, block and slot.
+        let mut indexes: HashMap<(String, i32, i32), usize> = HashMap::new();
+        for (_, record) in changes {
+            let block = record.block().unwrap();
+            let Some((table, layout)) = self.tables.get(block.filename()) else {
+                continue;
+            };
+            let Some(offset) = record.offset() else {
+                continue;
+            };
+            let slot = offset / layout.slot_size();
+            let key = (block.filename().to_string(), block.block_num(), slot);
+            let index = *indexes.entry(key).or_insert_with(|| {
+                events.push(ChangeEvent {
+                    lsn,
+                    tx_num,
+                    table: table.clone(),
+                    rid: Rid::new(block.block_num(), slot),
+                    kind: ChangeKind::Update,
+                    values: Map::new(),
+                });
+                events.len() - 1
+            });
+            apply_change(
+                &mut events[index],
+                layout,
+                offset % layout.slot_size(),
+                record.as_ref(),
+            );
+        }
+        events
+    }
+}
+
+// Fold the change of a value at `offset` in a slot into the event of its record.
+fn apply_change(event: &mut ChangeEvent, layout: &Layout, offset: i32, record: &dyn LogRecord) {
+    let int_value = |value: Option<Constant>| value.and_then(|value| value.as_int());
+    match offset {
+        // The flags: whether the slot is used, and which fields are null.
+        0 => {
+            let old_flags = int_value(record.old_value()).unwrap_or(0);
+            let new_flags = int_value(record.new_value()).unwrap_or(0);
+            let is_set = |flags: i32, name: &str| {
+                layout
+                    .flag_bit_location(name)
+                    .is_some_and(|bit| flags & (1 << bit) != 0)
+            };
+            if !is_set(old_flags, IS_USED_FLAG_NAME) && is_set(new_flags, IS_USED_FLAG_NAME) {
+                event.kind = ChangeKind::Insert;
+            }
+            for field_name in layout.schema().fields() {
+                if !is_set(old_flags, field_name) && is_set(new_flags, field_name) {
+                    event.values.insert(field_name.clone(), Value::Null);
+                }
+            }
+        }
+        // Deleting marks the record with the deleting transaction.
+        XMAX_OFFSET => {
+            if int_value(record.new_value()).is_some_and(|xmax| xmax != 0) {
+                event.kind = ChangeKind::Delete;
+            }
+        }
+        _ => {
+            let field = layout
+                .schema()
+                .fields()
+                .iter()
+                .find(|name| layout.offset(name) == Some(&offset));
+            if let Some(field_name) = field {
+                let value = record
+                    .new_value()
+                    .map_or(Value::Null, |value| value.to_json());
+                event.values.insert(field_name.clone(), value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::{
+        query::{scan::Scan, update_scan::UpdateScan},
+        record::{schema::Schema, table_scan::TableScan},
+        server::simple_db::SimpleDB,
+        tx::transaction::Transaction,
+    };
+
+    use super::*;
+
+    fn layout() -> Arc<Layout> {
+        let mut schema = Schema::new();
+        schema.add_int_field("id").add_string_field("name", 10);
+        Arc::new(Layout::from(schema))
+    }
+
+    fn finish(tx: Arc<Mutex<Transaction>>, commit: bool) {
+        let tx = Arc::try_unwrap(tx).ok().unwrap().into_inner().unwrap();
+        if commit {
+            tx.commit().unwrap();
+        } else {
+            tx.rollback().unwrap();
+        }
+    }
+
+    // Run `f` on a scan of the table in a new transaction.
+    fn with_scan(db: &SimpleDB, commit: bool, f: impl FnOnce(&mut TableScan)) {
+        let tx = Arc::new(Mutex::new(db.new_tx()));
+        let mut scan = TableScan::new(tx.clone(), "people", layout()).unwrap();
+        f(&mut scan);
+        drop(scan);
+        finish(tx, commit);
+    }
+
+    #[test]
+    fn emits_committed_row_changes() {
+        let db = SimpleDB::new(
+            "testdata/tx/recovery/change_stream/emits_committed_row_changes",
+            400,
+            8,
+            "templog",
+        );
+        let mut stream = ChangeStream::new(db.log_manager()).with_table("people", layout());
+
+        with_scan(&db, true, |scan| {
+            scan.insert().unwrap();
+            scan.set_int("id", 1).set_string("name", "ann");
+            scan.insert().unwrap();
+            scan.set_int("id", 2).set_null("name");
+        });
+        with_scan(&db, false, |scan| {
+            scan.next().unwrap();
+            scan.set_string("name", "bob");
+        });
+        // committed, but changing no table the stream knows
+        let mut tx = db.new_tx();
+        let block = tx.append("other.tbl").unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 0, 1, true).unwrap();
+        tx.commit().unwrap();
+
+        let mut out = vec![];
+        assert_eq!(stream.poll_json_lines(&mut out).unwrap(), 2);
+        let events: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events[0]["op"], "insert");
+        assert_eq!(events[0]["table"], "people");
+        assert_eq!(events[0]["rid"], serde_json::json!({"block": 0, "slot": 0}));
+        assert_eq!(
+            events[0]["values"],
+            serde_json::json!({"id": 1, "name": "ann"})
+        );
+        assert_eq!(events[1]["rid"], serde_json::json!({"block": 0, "slot": 1}));
+        assert_eq!(
+            events[1]["values"],
+            serde_json::json!({"id": 2, "name": null})
+        );
+        assert_eq!(events[0]["lsn"], events[1]["lsn"]);
+
+        // held back until the commit
+        let tx = Arc::new(Mutex::new(db.new_tx()));
+        let mut scan = TableScan::new(tx.clone(), "people", layout()).unwrap();
+        scan.next().unwrap();
+        scan.set_string("name", "amy");
+        scan.next().unwrap();
+        scan.delete().unwrap();
+        drop(scan);
+        assert!(stream.poll().unwrap().is_empty());
+        finish(tx, true);
+
+        let events = stream.poll().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, ChangeKind::Update);
+        assert!(events[0].rid == Rid::new(0, 0));
+        assert_eq!(events[0].values["name"], "amy");
+        assert_eq!(events[1].kind, ChangeKind::Delete);
+        assert!(events[1].rid == Rid::new(0, 1));
+
+        // a consumer resuming after the first commit
+        let commit_lsn = events[0].lsn;
+        let mut stream = ChangeStream::new(db.log_manager())
+            .with_table("people", layout())
+            .starting_after(commit_lsn - 1);
+        assert_eq!(stream.poll().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reports_records_removed_before_read() {
+        let db = SimpleDB::new(
+            "testdata/tx/recovery/change_stream/reports_records_removed_before_read",
+            400,
+            8,
+            "templog",
+        );
+        let lm = db.log_manager();
+        lm.set_segment_blocks(1);
+        let insert = |id: i32| {
+            with_scan(&db, true, |scan| {
+                scan.insert().unwrap();
+                scan.set_int("id", id).set_string("name", "ann");
+            })
+        };
+        let mut behind = ChangeStream::new(lm.clone()).with_table("people", layout());
+        let mut current = ChangeStream::new(lm.clone()).with_table("people", layout());
+        insert(0);
+        assert_eq!(behind.poll().unwrap().len(), 1);
+        for id in 1..10 {
+            insert(id);
+        }
+        let events = current.poll().unwrap();
+        assert_eq!(events.len(), 10);
+        assert!(lm.segment_range().count() > 2);
+
+        // the checkpoint of a recovery removes the segments before the current one
+        db.new_tx().recover().unwrap();
+        insert(10);
+        assert_eq!(current.poll().unwrap().len(), 1);
+        // a consumer resuming after the last commit before it reads on from the checkpoint
+        let mut resumed = ChangeStream::new(lm.clone())
+            .with_table("people", layout())
+            .starting_after(events[9].lsn);
+        assert_eq!(resumed.poll().unwrap().len(), 1);
+        assert!(matches!(behind.poll(), Err(DbError::MissingLogSegment(_))));
+    }
+}
@@ -9,25 +9,42 @@ use crate::{
 
 use super::log_record::{LogRecord, CHECKPOINT};
 
-pub struct CheckpointRecord {}
+// The largest transaction number given out so far is kept in the record,
+// so that numbering goes on from it once the log before the checkpoint is removed.
+#[derive(PartialEq, Debug)]
+pub struct CheckpointRecord {
+    last_tx_num: i32,
+}
 
 impl CheckpointRecord {
-    pub fn new() -> CheckpointRecord {
-        CheckpointRecord {}
+    pub fn new(last_tx_num: i32) -> CheckpointRecord {
+        CheckpointRecord { last_tx_num }
     }
 
     pub fn page(&self) -> Page {
-        let mut page = Page::new(INTEGER_BYTES);
+        let mut page = Page::new(2 * INTEGER_BYTES);
         page.set_int(0, CHECKPOINT);
+        page.set_int(INTEGER_BYTES, self.last_tx_num);
         page
     }
 
-    pub fn write_to_log(&self, lm: &mut LogManager) -> Result<i32> {
+    pub fn last_tx_num(&self) -> i32 {
+        self.last_tx_num
+    }
+
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
         let page = self.page();
         lm.append(page.buffer())
     }
 }
 
+impl From<Page> for CheckpointRecord {
+    fn from(page: Page) -> Self {
+        let last_tx_num = page.get_int(INTEGER_BYTES);
+        CheckpointRecord { last_tx_num }
+    }
+}
+
 impl LogRecord for CheckpointRecord {
     fn op(&self) -> i32 {
         CHECKPOINT
@@ -37,7 +54,11 @@ impl LogRecord for CheckpointRecord {
         -1
     }
 
-    fn undo(&self, _tx: &mut Transaction) {}
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({"type": "CHECKPOINT", "last_tx": self.last_tx_num})
+    }
+
+    fn undo(&self, _tx: &mut Transaction, _log: bool) {}
 }
 
 impl std::fmt::Display for CheckpointRecord {
@@ -52,14 +73,15 @@ mod tests {
 
     #[test]
     fn page() {
-        let record = CheckpointRecord::new();
+        let record = CheckpointRecord::new(5);
         let page = record.page();
-        assert_eq!(page.buffer(), vec![0, 0, 0, 0]);
+        assert_eq!(page.buffer(), vec![0, 0, 0, 0, 0, 0, 0, 5]);
+        assert_eq!(CheckpointRecord::from(page), record);
     }
 
     #[test]
     fn to_string() {
-        let record = CheckpointRecord::new();
+        let record = CheckpointRecord::new(5);
         assert_eq!(record.to_string(), "<CHECKPOINT>");
     }
 }
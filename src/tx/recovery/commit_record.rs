@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use chrono::{DateTime, FixedOffset, Utc};
+
 use crate::{
     file::page::Page,
     log::log_manager::LogManager,
@@ -12,14 +14,23 @@ use super::log_record::{LogRecord, COMMIT};
 #[derive(PartialEq, Debug)]
 pub struct CommitRecord {
     tx_num: i32,
+    // When the transaction committed, to restore the database as of a point in time.
+    time: DateTime<FixedOffset>,
 }
 
 impl CommitRecord {
     pub fn new(tx_num: i32) -> Self {
-        Self { tx_num }
+        Self {
+            tx_num,
+            time: Utc::now().fixed_offset(),
+        }
+    }
+
+    pub fn time(&self) -> DateTime<FixedOffset> {
+        self.time
     }
 
-    pub fn write_to_log(&self, lm: &mut LogManager) -> Result<i32> {
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
         let page = Page::from(self);
         lm.append(page.buffer())
     }
@@ -28,14 +39,17 @@ impl CommitRecord {
 impl From<Page> for CommitRecord {
     fn from(page: Page) -> Self {
         let tx_num = page.get_int(INTEGER_BYTES);
-        CommitRecord { tx_num }
+        let time = page.get_datetime(2 * INTEGER_BYTES).unwrap_or_default();
+        CommitRecord { tx_num, time }
     }
 }
 impl From<&CommitRecord> for Page {
     fn from(record: &CommitRecord) -> Self {
-        let mut page = Page::new(2 * INTEGER_BYTES);
+        let time = Some(record.time);
+        let mut page = Page::new(2 * INTEGER_BYTES + Page::datetime_len(&time));
         page.set_int(0, COMMIT);
         page.set_int(INTEGER_BYTES, record.tx_num);
+        page.set_datetime(2 * INTEGER_BYTES, &time);
         page
     }
 }
@@ -49,7 +63,11 @@ impl LogRecord for CommitRecord {
         self.tx_num
     }
 
-    fn undo(&self, _tx: &mut Transaction) {}
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({"type": "COMMIT", "tx": self.tx_num, "time": self.time.to_rfc3339()})
+    }
+
+    fn undo(&self, _tx: &mut Transaction, _log: bool) {}
 }
 
 impl std::fmt::Display for CommitRecord {
@@ -0,0 +1,166 @@
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+
+use crate::file::page::Page;
+
+use super::{
+    log_record::{
+        create_log_record, LogRecord, CHECKPOINT, COMMIT, NQCKPT, ROLLBACK, ROLLBACK_TO, SAVEPOINT,
+    },
+    nq_ckpt_record::NqCkptRecord,
+    rollback_to_record::RollbackToRecord,
+    savepoint_record::SavepointRecord,
+};
+
+// Changes with their LSNs.
+pub type Changes = Vec<(i32, Box<dyn LogRecord>)>;
+
+// What a log record settles about the changes held back so far.
+pub enum Settled {
+    // The changes of a transaction that committed, from the oldest to the newest.
+    Committed(i32, Changes),
+    // Changes that were rolled back, from the newest to the oldest.
+    RolledBack(Changes),
+}
+
+// Holds back the changes of each transaction, read from the log in order,
+// until its COMMIT record shows they were committed, or a record shows they were rolled back.
+// Rollbacks and recovery undos are not logged as changes: ROLLBACK, ROLLBACK_TO,
+// and the checkpoints written after a recovery tell which changes were undone.
+#[derive(Default)]
+pub struct CommitTracker {
+    pending: HashMap<i32, Changes>,
+    // The LSNs of the savepoints, by transaction and name.
+    savepoints: HashMap<(i32, String), i32>,
+}
+
+impl CommitTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Track the record `lsn`, returning the changes it settles, if any.
+    pub fn track(&mut self, lsn: i32, bytes: Vec<u8>) -> Option<Settled> {
+        let record = create_log_record(bytes.clone())?;
+        let tx_num = record.tx_num();
+        match record.op() {
+            COMMIT => {
+                self.forget_savepoints(tx_num);
+                let changes = self.pending.remove(&tx_num)?;
+                Some(Settled::Committed(tx_num, changes))
+            }
+            ROLLBACK => {
+                self.forget_savepoints(tx_num);
+                let changes = self.pending.remove(&tx_num)?;
+                Some(Settled::RolledBack(newest_first(changes)))
+            }
+            // A checkpoint follows either no transaction or a recovery.
+            CHECKPOINT => {
+                self.savepoints.clear();
+                let changes = self.pending.drain().flat_map(|(_, c)| c).collect();
+                Some(Settled::RolledBack(newest_first(changes)))
+            }
+            // The transactions not listed were rolled back by the recovery that wrote it.
+            NQCKPT => {
+                let listed: HashSet<i32> = NqCkptRecord::from(Page::from(bytes)).tx_nums();
+                let rolled_back: Vec<i32> = self
+                    .pending
+                    .keys()
+                    .filter(|tx_num| !listed.contains(tx_num))
+                    .copied()
+                    .collect();
+                let changes = rolled_back
+                    .iter()
+                    .flat_map(|tx_num| self.pending.remove(tx_num).unwrap())
+                    .collect();
+                Some(Settled::RolledBack(newest_first(changes)))
+            }
+            SAVEPOINT => {
+                let name = SavepointRecord::from(Page::from(bytes)).name().to_string();
+                self.savepoints.insert((tx_num, name), lsn);
+                None
+            }
+            ROLLBACK_TO => {
+                let name = RollbackToRecord::from(Page::from(bytes)).name().to_string();
+                // A savepoint older than the log predates every change seen.
+                let savepoint = self.savepoints.get(&(tx_num, name)).copied().unwrap_or(0);
+                let changes = self.pending.get_mut(&tx_num)?;
+                let at = changes.partition_point(|(lsn, _)| *lsn < savepoint);
+                Some(Settled::RolledBack(newest_first(changes.split_off(at))))
+            }
+            _ if record.block().is_some() => {
+                self.pending.entry(tx_num).or_default().push((lsn, record));
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // The changes still held back, from the newest to the oldest.
+    pub fn pending(&self) -> Vec<&dyn LogRecord> {
+        let mut changes: Vec<_> = self.pending.values().flatten().collect();
+        changes.sort_by_key(|(lsn, _)| -lsn);
+        changes
+            .into_iter()
+            .map(|(_, record)| record.as_ref())
+            .collect()
+    }
+
+    fn forget_savepoints(&mut self, tx_num: i32) {
+        self.savepoints.retain(|(t, _), _| *t != tx_num);
+    }
+}
+
+fn newest_first(mut changes: Changes) -> Changes {
+    changes.sort_by_key(|(lsn, _)| -lsn);
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        file::block_id::BlockId,
+        tx::recovery::{
+            commit_record::CommitRecord, rollback_record::RollbackRecord,
+            set_int_record::SetIntRecord,
+        },
+    };
+
+    use super::*;
+
+    fn set_int(tx_num: i32, offset: i32, value: i32) -> Vec<u8> {
+        let block = BlockId::new("tbl".to_string(), 0);
+        let record = SetIntRecord::new(tx_num, block, offset, 0, value);
+        Page::from(&record).buffer().to_vec()
+    }
+
+    fn new_values(changes: &Changes) -> Vec<i32> {
+        changes
+            .iter()
+            .map(|(_, record)| record.to_json()["new_value"].as_i64().unwrap() as i32)
+            .collect()
+    }
+
+    #[test]
+    fn holds_back_until_settled() {
+        let mut tracker = CommitTracker::new();
+        assert!(tracker.track(1, set_int(1, 0, 10)).is_none());
+        assert!(tracker.track(2, set_int(2, 4, 20)).is_none());
+        assert!(tracker.track(3, set_int(1, 8, 11)).is_none());
+        assert_eq!(tracker.pending().len(), 3);
+
+        let commit = Page::from(&CommitRecord::new(1)).buffer().to_vec();
+        let Some(Settled::Committed(1, changes)) = tracker.track(4, commit) else {
+            panic!("tx 1 not committed");
+        };
+        assert_eq!(new_values(&changes), [10, 11]);
+
+        let rollback = Page::from(&RollbackRecord::new(2)).buffer().to_vec();
+        let Some(Settled::RolledBack(changes)) = tracker.track(5, rollback) else {
+            panic!("tx 2 not rolled back");
+        };
+        assert_eq!(new_values(&changes), [20]);
+        assert!(tracker.pending().is_empty());
+    }
+}
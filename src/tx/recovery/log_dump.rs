@@ -0,0 +1,118 @@
+#![allow(dead_code)]
+
+use super::log_record::LogRecord;
+
+// Which records a log dump prints. Every record matches an empty filter.
+#[derive(Debug, Default, Clone)]
+pub struct LogFilter {
+    tx_num: Option<i32>,
+    op: Option<i32>,
+    file: Option<String>,
+}
+
+impl LogFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_tx_num(mut self, tx_num: i32) -> Self {
+        self.tx_num = Some(tx_num);
+        self
+    }
+
+    pub fn with_op(mut self, op: i32) -> Self {
+        self.op = Some(op);
+        self
+    }
+
+    // Only the records saving a value of a block of `file`.
+    pub fn with_file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    pub fn matches(&self, record: &dyn LogRecord) -> bool {
+        self.tx_num.is_none_or(|tx_num| record.tx_num() == tx_num)
+            && self.op.is_none_or(|op| record.op() == op)
+            && self
+                .file
+                .as_ref()
+                .is_none_or(|file| record.block().is_some_and(|block| block.filename() == file))
+    }
+}
+
+// One line of a log dump: the LSN followed by the record, or a JSON object.
+pub fn format_record(lsn: i32, record: &dyn LogRecord, json: bool) -> String {
+    if json {
+        let mut value = record.to_json();
+        value["lsn"] = lsn.into();
+        value.to_string()
+    } else {
+        format!("{:>8} {}", lsn, record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        file::block_id::BlockId,
+        tx::recovery::{
+            commit_record::CommitRecord,
+            log_record::{op_from_name, COMMIT, SET_INT},
+            nq_ckpt_record::NqCkptRecord,
+            set_int_record::SetIntRecord,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn filters() {
+        let set = SetIntRecord::new(1, BlockId::new("tbl".to_string(), 2), 8, 5, 6);
+        let commit = CommitRecord::new(1);
+
+        assert!(LogFilter::new().matches(&set));
+        assert!(LogFilter::new().with_tx_num(1).matches(&commit));
+        assert!(!LogFilter::new().with_tx_num(2).matches(&commit));
+        assert!(LogFilter::new().with_op(SET_INT).matches(&set));
+        assert!(!LogFilter::new().with_op(SET_INT).matches(&commit));
+        assert!(LogFilter::new().with_file("tbl").matches(&set));
+        assert!(!LogFilter::new().with_file("other").matches(&set));
+        // records without a block never match a file
+        assert!(!LogFilter::new().with_file("tbl").matches(&commit));
+
+        assert_eq!(op_from_name("commit"), Some(COMMIT));
+        assert_eq!(op_from_name("nothing"), None);
+    }
+
+    #[test]
+    fn formats() {
+        let set = SetIntRecord::new(1, BlockId::new("tbl".to_string(), 2), 8, 5, 6);
+        assert_eq!(
+            format_record(7, &set, false),
+            "       7 <SETINT 1 [file tbl, block 2] 8 5>"
+        );
+        let json: serde_json::Value = serde_json::from_str(&format_record(7, &set, true)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "lsn": 7,
+                "type": "SET_INT",
+                "tx": 1,
+                "file": "tbl",
+                "block": 2,
+                "offset": 8,
+                "old_value": 5,
+                "new_value": 6,
+            })
+        );
+
+        let nq_ckpt = NqCkptRecord::new(vec![3, 4]);
+        let json: serde_json::Value =
+            serde_json::from_str(&format_record(9, &nq_ckpt, true)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"lsn": 9, "type": "NQCKPT", "txs": [3, 4]})
+        );
+    }
+}
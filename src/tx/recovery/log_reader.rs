@@ -0,0 +1,110 @@
+#![allow(dead_code)]
+
+use crate::{
+    log::{
+        log_iterator::{Direction, LogIterator},
+        log_manager::LogManager,
+    },
+    util::Result,
+};
+
+use super::log_record::{create_log_record, LogRecord};
+
+// Reads decoded log records with their LSNs, starting at a given LSN in either direction.
+// Records of unknown types are skipped.
+pub struct LogReader {
+    iter: LogIterator,
+}
+
+impl LogReader {
+    pub fn new(lm: &LogManager, lsn: i32, direction: Direction) -> Result<Self> {
+        Ok(Self {
+            iter: lm.iter_from(lsn, direction)?,
+        })
+    }
+
+    // From the record `lsn` to the newest one.
+    pub fn forward(lm: &LogManager, lsn: i32) -> Result<Self> {
+        Self::new(lm, lsn, Direction::Forward)
+    }
+
+    // From the record `lsn` back to the oldest one still in the log.
+    pub fn backward(lm: &LogManager, lsn: i32) -> Result<Self> {
+        Self::new(lm, lsn, Direction::Backward)
+    }
+
+    // Whether the reader stopped at a corrupt record.
+    pub fn is_corrupt(&self) -> bool {
+        self.iter.is_corrupt()
+    }
+}
+
+// Decode the records of an iterator, e.g. one over a log not opened by a log manager.
+impl From<LogIterator> for LogReader {
+    fn from(iter: LogIterator) -> Self {
+        Self { iter }
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<(i32, Box<dyn LogRecord>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (lsn, bytes) = match self.iter.next()? {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Some(record) = create_log_record(bytes) {
+                return Some(Ok((lsn, record)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        file::block_id::BlockId,
+        server::simple_db::SimpleDB,
+        tx::recovery::log_record::{COMMIT, SET_INT, START},
+    };
+
+    use super::*;
+
+    #[test]
+    fn reads_in_both_directions() {
+        let db = SimpleDB::new(
+            "testdata/tx/recovery/log_reader/reads_in_both_directions",
+            400,
+            8,
+            "templog",
+        );
+        let lm = db.log_manager();
+        let block = BlockId::new("tempfile".to_string(), 0);
+
+        let mut tx = db.new_tx();
+        let tx_num = tx.tx_num();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 0, 7, true).unwrap();
+        tx.commit().unwrap();
+        let last_lsn = lm.latest_lsn();
+
+        // the transaction's records are the last three
+        let records: Vec<_> = LogReader::forward(&lm, last_lsn - 2)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let lsns: Vec<i32> = records.iter().map(|(lsn, _)| *lsn).collect();
+        assert_eq!(lsns, vec![last_lsn - 2, last_lsn - 1, last_lsn]);
+        let ops: Vec<i32> = records.iter().map(|(_, record)| record.op()).collect();
+        assert_eq!(ops, vec![START, SET_INT, COMMIT]);
+        assert!(records.iter().all(|(_, record)| record.tx_num() == tx_num));
+
+        let mut backward = LogReader::backward(&lm, last_lsn).unwrap();
+        let (lsn, record) = backward.next().unwrap().unwrap();
+        assert_eq!(lsn, last_lsn);
+        assert_eq!(record.to_string(), format!("<COMMIT {}>", tx_num));
+        assert_eq!(backward.next().unwrap().unwrap().1.op(), SET_INT);
+    }
+}
@@ -1,14 +1,23 @@
 #![allow(dead_code)]
 
-use crate::{file::page::Page, tx::transaction::Transaction};
+use std::fmt::Display;
+
+use crate::{
+    file::{block_id::BlockId, page::Page},
+    query::constant::Constant,
+    tx::transaction::Transaction,
+    util::INTEGER_BYTES,
+};
 
 use super::{
     checkpoint_record::CheckpointRecord, commit_record::CommitRecord, nq_ckpt_record::NqCkptRecord,
-    rollback_record::RollbackRecord, set_bool_record::SetBoolRecord,
-    set_bytes_record::SetBytesRecord, set_date_record::SetDateRecord,
-    set_datetime_record::SetDatetimeRecord, set_double_record::SetDoubleRecord,
-    set_int_record::SetIntRecord, set_json_record::SetJsonRecord,
-    set_string_record::SetStringRecord, set_time_record::SetTimeRecord, start_record::StartRecord,
+    prepare_record::PrepareRecord, rollback_record::RollbackRecord,
+    rollback_to_record::RollbackToRecord, savepoint_record::SavepointRecord,
+    set_bool_record::SetBoolRecord, set_bytes_record::SetBytesRecord,
+    set_date_record::SetDateRecord, set_datetime_record::SetDatetimeRecord,
+    set_double_record::SetDoubleRecord, set_int_record::SetIntRecord,
+    set_json_record::SetJsonRecord, set_string_record::SetStringRecord,
+    set_time_record::SetTimeRecord, start_record::StartRecord,
 };
 
 pub const CHECKPOINT: i32 = 0;
@@ -25,19 +34,105 @@ pub const SET_DATE: i32 = 9;
 pub const SET_TIME: i32 = 10;
 pub const SET_DATETIME: i32 = 11;
 pub const SET_JSON: i32 = 12;
+pub const SAVEPOINT: i32 = 14;
+pub const PREPARE: i32 = 15;
+pub const ROLLBACK_TO: i32 = 16;
 
-pub trait LogRecord {
+pub trait LogRecord: Display + Send {
     fn op(&self) -> i32;
 
     fn tx_num(&self) -> i32;
 
-    fn undo(&self, tx: &mut Transaction);
+    // The block whose value the record saves, if any.
+    fn block(&self) -> Option<&BlockId> {
+        None
+    }
+
+    // The offset in the block of the value the record saves, if any.
+    fn offset(&self) -> Option<i32> {
+        None
+    }
+
+    // The value before and after the change; None without a value, or for a null.
+    fn old_value(&self) -> Option<Constant> {
+        None
+    }
+
+    fn new_value(&self) -> Option<Constant> {
+        None
+    }
+
+    fn to_json(&self) -> serde_json::Value;
+
+    // Write the old value back, logging the write if `log` is set.
+    fn undo(&self, tx: &mut Transaction, log: bool);
+
+    // Apply the change again, when the log is replayed or shipped.
+    fn redo(&self, _tx: &mut Transaction, _log: bool) {}
+}
+
+const OP_NAMES: [(i32, &str); 17] = [
+    (CHECKPOINT, "CHECKPOINT"),
+    (NQCKPT, "NQCKPT"),
+    (START, "START"),
+    (COMMIT, "COMMIT"),
+    (ROLLBACK, "ROLLBACK"),
+    (SET_INT, "SET_INT"),
+    (SET_BYTES, "SET_BYTES"),
+    (SET_STRING, "SET_STRING"),
+    (SET_BOOL, "SET_BOOL"),
+    (SET_DOUBLE, "SET_DOUBLE"),
+    (SET_DATE, "SET_DATE"),
+    (SET_TIME, "SET_TIME"),
+    (SET_DATETIME, "SET_DATETIME"),
+    (SET_JSON, "SET_JSON"),
+    (SAVEPOINT, "SAVEPOINT"),
+    (PREPARE, "PREPARE"),
+    (ROLLBACK_TO, "ROLLBACK_TO"),
+];
+
+pub fn op_name(op: i32) -> &'static str {
+    OP_NAMES
+        .iter()
+        .find(|(o, _)| *o == op)
+        .map_or("UNKNOWN", |(_, name)| name)
+}
+
+// The record type with the given name, ignoring case.
+pub fn op_from_name(name: &str) -> Option<i32> {
+    OP_NAMES
+        .iter()
+        .find(|(_, n)| n.eq_ignore_ascii_case(name))
+        .map(|(op, _)| *op)
+}
+
+// The JSON form shared by the records that save a change of a value.
+pub(super) fn set_record_json(
+    op: i32,
+    tx_num: i32,
+    block: &BlockId,
+    offset: i32,
+    old_value: serde_json::Value,
+    new_value: serde_json::Value,
+) -> serde_json::Value {
+    serde_json::json!({
+        "type": op_name(op),
+        "tx": tx_num,
+        "file": block.filename(),
+        "block": block.block_num(),
+        "offset": offset,
+        "old_value": old_value,
+        "new_value": new_value,
+    })
 }
 
 pub fn create_log_record(bytes: Vec<u8>) -> Option<Box<dyn LogRecord>> {
+    if bytes.len() < INTEGER_BYTES as usize {
+        return None;
+    }
     let p = Page::from(bytes);
     match p.get_int(0) {
-        CHECKPOINT => Some(Box::new(CheckpointRecord::new())),
+        CHECKPOINT => Some(Box::new(CheckpointRecord::from(p))),
         NQCKPT => Some(Box::new(NqCkptRecord::from(p))),
         START => Some(Box::new(StartRecord::from(p))),
         COMMIT => Some(Box::new(CommitRecord::from(p))),
@@ -51,6 +146,9 @@ pub fn create_log_record(bytes: Vec<u8>) -> Option<Box<dyn LogRecord>> {
         SET_TIME => Some(Box::new(SetTimeRecord::from(p))),
         SET_DATETIME => Some(Box::new(SetDatetimeRecord::from(p))),
         SET_JSON => Some(Box::new(SetJsonRecord::from(p))),
+        SAVEPOINT => Some(Box::new(SavepointRecord::from(p))),
+        PREPARE => Some(Box::new(PrepareRecord::from(p))),
+        ROLLBACK_TO => Some(Box::new(RollbackToRecord::from(p))),
         _ => None,
     }
 }
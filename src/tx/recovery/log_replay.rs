@@ -0,0 +1,160 @@
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::{
+    file::{block_id::BlockId, page::Page},
+    log::{log_iterator::Direction, log_manager::LogManager},
+    tx::transaction::Transaction,
+    util::Result,
+};
+
+use super::{
+    commit_record::CommitRecord,
+    commit_tracker::Changes,
+    log_record::{
+        create_log_record, LogRecord, CHECKPOINT, COMMIT, NQCKPT, ROLLBACK, ROLLBACK_TO, SAVEPOINT,
+    },
+    nq_ckpt_record::NqCkptRecord,
+    rollback_to_record::RollbackToRecord,
+    savepoint_record::SavepointRecord,
+};
+
+// How far the log is replayed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestoreTarget {
+    // Up to the end of the log.
+    End,
+    // Up to and including the record with this LSN.
+    Lsn(i32),
+    // Up to the end of the transaction, its COMMIT or ROLLBACK record.
+    Tx(i32),
+    // Up to the last COMMIT record stamped at or before this time.
+    Time(DateTime<FixedOffset>),
+}
+
+impl RestoreTarget {
+    // Whether the replay stops before the record.
+    fn is_before(&self, lsn: i32, op: i32, bytes: &[u8]) -> bool {
+        match self {
+            RestoreTarget::Lsn(target) => lsn > *target,
+            RestoreTarget::Time(time) if op == COMMIT => {
+                CommitRecord::from(Page::from(bytes.to_vec())).time() > *time
+            }
+            _ => false,
+        }
+    }
+
+    // Whether the replay stops after the record.
+    fn is_reached_by(&self, record: &dyn LogRecord) -> bool {
+        match self {
+            RestoreTarget::Tx(tx_num) => {
+                record.tx_num() == *tx_num && (record.op() == COMMIT || record.op() == ROLLBACK)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Replays `log` from its first record on the database of `tx` and returns the LSN of the last replayed record.
+// History is repeated: the changes of every transaction are redone, and undone again where the
+// transaction was rolled back, either by itself or by the recovery before a checkpoint.
+// The transactions still unfinished at the target are then rolled back.
+// Changes are written without logging, so `tx` must be committed afterwards.
+pub fn replay(tx: &mut Transaction, log: &LogManager, target: RestoreTarget) -> Result<i32> {
+    // The changes of the unfinished transactions, by transaction.
+    let mut changes: HashMap<i32, Changes> = HashMap::new();
+    // The LSNs of the savepoints, by transaction and name.
+    let mut savepoints: HashMap<(i32, String), i32> = HashMap::new();
+    let mut last_lsn = 0;
+    for record in log.iter_from(0, Direction::Forward)? {
+        let (lsn, bytes) = record?;
+        let Some(record) = create_log_record(bytes.clone()) else {
+            break;
+        };
+        if target.is_before(lsn, record.op(), &bytes) {
+            break;
+        }
+        last_lsn = lsn;
+
+        let reached = target.is_reached_by(record.as_ref());
+        match record.op() {
+            COMMIT => {
+                changes.remove(&record.tx_num());
+            }
+            ROLLBACK => {
+                let tx_changes = changes.remove(&record.tx_num()).unwrap_or_default();
+                undo(tx, tx_changes);
+            }
+            // A checkpoint follows either no transaction or a recovery.
+            CHECKPOINT => undo(tx, changes.drain().flat_map(|(_, c)| c).collect()),
+            // The transactions not listed were rolled back by the recovery that wrote it.
+            NQCKPT => {
+                let listed: HashSet<i32> = NqCkptRecord::from(Page::from(bytes)).tx_nums();
+                let rolled_back: Vec<i32> = changes
+                    .keys()
+                    .filter(|tx_num| !listed.contains(tx_num))
+                    .copied()
+                    .collect();
+                let undone = rolled_back
+                    .iter()
+                    .flat_map(|tx_num| changes.remove(tx_num).unwrap())
+                    .collect();
+                undo(tx, undone);
+            }
+            SAVEPOINT => {
+                let name = SavepointRecord::from(Page::from(bytes)).name().to_string();
+                savepoints.insert((record.tx_num(), name), lsn);
+            }
+            ROLLBACK_TO => {
+                let name = RollbackToRecord::from(Page::from(bytes)).name().to_string();
+                // A savepoint older than the log predates every change seen.
+                let savepoint = savepoints
+                    .get(&(record.tx_num(), name))
+                    .copied()
+                    .unwrap_or(0);
+                let tx_changes = changes.entry(record.tx_num()).or_default();
+                let at = tx_changes.partition_point(|(lsn, _)| *lsn < savepoint);
+                undo(tx, tx_changes.split_off(at));
+            }
+            _ if record.block().is_some() => {
+                redo(tx, record.as_ref())?;
+                changes
+                    .entry(record.tx_num())
+                    .or_default()
+                    .push((lsn, record));
+            }
+            _ => {}
+        }
+        if reached {
+            break;
+        }
+    }
+    undo(tx, changes.into_values().flatten().collect());
+    Ok(last_lsn)
+}
+
+fn redo(tx: &mut Transaction, record: &dyn LogRecord) -> Result<()> {
+    extend_to(tx, record.block().unwrap())?;
+    record.redo(tx, false);
+    Ok(())
+}
+
+// Append blocks to the file of `block` up to it.
+// A base backup may predate the blocks the log writes to.
+pub fn extend_to(tx: &mut Transaction, block: &BlockId) -> Result<()> {
+    while tx.size(block.filename())? <= block.block_num() {
+        tx.append(block.filename())?;
+    }
+    Ok(())
+}
+
+// Undo changes from the newest to the oldest.
+fn undo(tx: &mut Transaction, mut changes: Changes) {
+    changes.sort_by_key(|(lsn, _)| -lsn);
+    for (_, record) in changes {
+        record.undo(tx, false);
+    }
+}
@@ -25,7 +25,7 @@ impl NqCkptRecord {
         self.tx_nums.iter().cloned().collect()
     }
 
-    pub fn write_to_log(&self, lm: &mut LogManager) -> Result<i32> {
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
         let page = Page::from(self);
         lm.append(page.buffer())
     }
@@ -70,7 +70,11 @@ impl LogRecord for NqCkptRecord {
         -1
     }
 
-    fn undo(&self, _tx: &mut Transaction) {}
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({"type": "NQCKPT", "txs": self.tx_nums})
+    }
+
+    fn undo(&self, _tx: &mut Transaction, _log: bool) {}
 }
 
 impl std::fmt::Display for NqCkptRecord {
@@ -0,0 +1,84 @@
+#![allow(dead_code)]
+
+use crate::{
+    file::page::Page,
+    log::log_manager::LogManager,
+    tx::transaction::Transaction,
+    util::{Result, INTEGER_BYTES},
+};
+
+use super::log_record::{LogRecord, PREPARE};
+
+#[derive(PartialEq, Debug)]
+pub struct PrepareRecord {
+    tx_num: i32,
+}
+
+impl PrepareRecord {
+    pub fn new(tx_num: i32) -> Self {
+        Self { tx_num }
+    }
+
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
+        let page = Page::from(self);
+        lm.append(page.buffer())
+    }
+}
+
+impl From<Page> for PrepareRecord {
+    fn from(page: Page) -> Self {
+        let tx_num = page.get_int(INTEGER_BYTES);
+        PrepareRecord { tx_num }
+    }
+}
+impl From<&PrepareRecord> for Page {
+    fn from(record: &PrepareRecord) -> Self {
+        let mut page = Page::new(2 * INTEGER_BYTES);
+        page.set_int(0, PREPARE);
+        page.set_int(INTEGER_BYTES, record.tx_num);
+        page
+    }
+}
+
+impl LogRecord for PrepareRecord {
+    fn op(&self) -> i32 {
+        PREPARE
+    }
+
+    fn tx_num(&self) -> i32 {
+        self.tx_num
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({"type": "PREPARE", "tx": self.tx_num})
+    }
+
+    fn undo(&self, _tx: &mut Transaction, _log: bool) {}
+}
+
+impl std::fmt::Display for PrepareRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<PREPARE {}>", self.tx_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let record = PrepareRecord::new(1);
+
+        let record2 = PrepareRecord::from(Page::from(&record));
+
+        assert_eq!(record, record2);
+    }
+
+    #[test]
+    fn to_string() {
+        let record = PrepareRecord::new(1);
+
+        assert_eq!(format!("{}", record), "<PREPARE 1>");
+    }
+}
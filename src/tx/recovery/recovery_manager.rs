@@ -1,10 +1,15 @@
 #![allow(dead_code)]
 
-use std::{collections::HashSet, sync::Arc, vec};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    vec,
+};
 
 use crate::{
     buffer::{buffer::Buffer, buffer_manager::BufferManager},
-    file::page::Page,
+    error::DbError,
+    file::{block_id::BlockId, page::Page},
     log::log_manager::LogManager,
     tx::{
         recovery::{
@@ -19,9 +24,15 @@ use crate::{
 use super::{
     checkpoint_record::CheckpointRecord,
     commit_record::CommitRecord,
-    log_record::{create_log_record, CHECKPOINT, COMMIT, NQCKPT, ROLLBACK, START},
+    log_record::{
+        create_log_record, LogRecord, CHECKPOINT, COMMIT, NQCKPT, PREPARE, ROLLBACK, ROLLBACK_TO,
+        SAVEPOINT, START,
+    },
     nq_ckpt_record::NqCkptRecord,
+    prepare_record::PrepareRecord,
     rollback_record::RollbackRecord,
+    rollback_to_record::RollbackToRecord,
+    savepoint_record::SavepointRecord,
     set_bytes_record::SetBytesRecord,
     set_date_record::SetDateRecord,
     set_int_record::SetIntRecord,
@@ -30,174 +41,325 @@ use super::{
     start_record::StartRecord,
 };
 
+#[derive(Clone)]
 pub struct RecoveryManager {
     lm: Arc<LogManager>,
     bm: Arc<BufferManager>,
     tx_num: i32,
+    // A read-only transaction writes no log records.
+    read_only: bool,
+    // Whether committing leaves the commit record to be flushed in the background.
+    async_commit: bool,
 }
 
 impl RecoveryManager {
     pub fn new(tx_num: i32, lm: Arc<LogManager>, bm: Arc<BufferManager>) -> Self {
-        {
-            let lm = Arc::as_ptr(&lm) as *mut LogManager;
-            unsafe {
-                StartRecord::new(tx_num).write_to_log(&mut *lm).unwrap();
-            }
+        StartRecord::new(tx_num).write_to_log(&lm).unwrap();
+        Self {
+            tx_num,
+            lm,
+            bm,
+            read_only: false,
+            async_commit: false,
+        }
+    }
+
+    pub fn new_read_only(tx_num: i32, lm: Arc<LogManager>, bm: Arc<BufferManager>) -> Self {
+        Self {
+            tx_num,
+            lm,
+            bm,
+            read_only: true,
+            async_commit: false,
         }
-        Self { tx_num, lm, bm }
     }
 
-    pub fn commit(&mut self) -> Result<()> {
-        let bm = Arc::as_ptr(&self.bm) as *mut BufferManager;
-        unsafe {
-            (*bm).flush_all(self.tx_num)?;
+    pub fn with_async_commit(mut self, async_commit: bool) -> Self {
+        self.async_commit = async_commit;
+        self
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
         }
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe {
-            let lsn = CommitRecord::new(self.tx_num).write_to_log(&mut *lm)?;
-            (*lm).flush(lsn)?;
+        // Recovery has no redo pass, so the pages are forced even by an asynchronous commit.
+        self.bm.flush_all(self.tx_num)?;
+        let lsn = CommitRecord::new(self.tx_num).write_to_log(&self.lm)?;
+        if self.async_commit {
+            self.lm.flush_later();
+        } else {
+            self.lm.flush(lsn)?;
         }
         Ok(())
     }
 
-    pub fn rollback(&mut self, tx: &mut Transaction) {
-        self.do_rollback(tx);
-        let bm = Arc::as_ptr(&self.bm) as *mut BufferManager;
-        unsafe {
-            (*bm).flush_all(self.tx_num).unwrap();
+    // The first phase of two-phase commit.
+    // After this, the changes are on disk and recovery leaves the transaction in doubt.
+    pub fn prepare(&self) -> Result<()> {
+        self.bm.flush_all(self.tx_num)?;
+        let lsn = PrepareRecord::new(self.tx_num).write_to_log(&self.lm)?;
+        self.lm.flush(lsn)?;
+        Ok(())
+    }
+
+    pub fn rollback(&self, tx: &mut Transaction) -> Result<()> {
+        if self.read_only {
+            return Ok(());
         }
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe {
-            let lsn = RollbackRecord::new(self.tx_num)
-                .write_to_log(&mut *lm)
-                .unwrap();
-            (*lm).flush(lsn).unwrap();
+        self.do_rollback(tx, self.tx_num)?;
+        self.bm.flush_all(self.tx_num)?;
+        let lsn = RollbackRecord::new(self.tx_num).write_to_log(&self.lm)?;
+        self.lm.flush(lsn)?;
+        Ok(())
+    }
+
+    pub fn savepoint(&self, name: &str) -> Result<()> {
+        if self.read_only {
+            return Ok(());
         }
+        SavepointRecord::new(self.tx_num, name).write_to_log(&self.lm)?;
+        Ok(())
     }
 
-    pub fn recover(&mut self, tx: &mut Transaction) {
-        self.do_recover(tx);
-        let bm = Arc::as_ptr(&self.bm) as *mut BufferManager;
-        unsafe {
-            (*bm).flush_all(self.tx_num).unwrap();
+    // Undo the records written after the latest savepoint named `name`.
+    pub fn rollback_to(&self, tx: &mut Transaction, name: &str) -> Result<()> {
+        if self.read_only {
+            return Ok(());
         }
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe {
-            let lsn = CheckpointRecord::new().write_to_log(&mut *lm).unwrap();
-            (*lm).flush(lsn).unwrap();
+        self.undo_changes(tx, self.tx_num, Some(name))?;
+        // The undo is not logged, so a replay of the log needs to know about it.
+        RollbackToRecord::new(self.tx_num, name).write_to_log(&self.lm)?;
+        Ok(())
+    }
+
+    // Returns the in-doubt transactions.
+    pub fn recover(&self, tx: &mut Transaction) -> Result<Vec<i32>> {
+        let in_doubt_txs = self.do_recover(tx)?;
+        self.bm.flush_all(self.tx_num)?;
+        // In-doubt transactions must be found again by the next recovery.
+        if in_doubt_txs.is_empty() {
+            let lsn = CheckpointRecord::new(self.tx_num).write_to_log(&self.lm)?;
+            self.lm.flush(lsn)?;
+            self.lm.remove_old_segments()?;
+        } else {
+            let lsn = NqCkptRecord::new(in_doubt_txs.clone()).write_to_log(&self.lm)?;
+            self.lm.flush(lsn)?;
+        }
+        Ok(in_doubt_txs)
+    }
+
+    // The blocks changed by each of the transactions, found back to their START records.
+    pub fn changed_blocks(&self, tx_nums: &[i32]) -> Result<HashMap<i32, HashSet<BlockId>>> {
+        let mut blocks: HashMap<i32, HashSet<BlockId>> = HashMap::new();
+        let mut unstarted: HashSet<i32> = tx_nums.iter().copied().collect();
+        for bytes in self.lm.iter()? {
+            let bytes = bytes?;
+            if unstarted.is_empty() {
+                break;
+            }
+            let Some(rec) = create_log_record(bytes) else {
+                break;
+            };
+            if !unstarted.contains(&rec.tx_num()) {
+                continue;
+            }
+            if rec.op() == START {
+                unstarted.remove(&rec.tx_num());
+            } else if let Some(block) = rec.block() {
+                blocks
+                    .entry(rec.tx_num())
+                    .or_default()
+                    .insert(block.clone());
+            }
+        }
+        Ok(blocks)
+    }
+
+    // Finish an in-doubt transaction by committing or rolling it back.
+    pub fn resolve(&self, tx: &mut Transaction, tx_num: i32, commit: bool) -> Result<()> {
+        if !Self::in_doubt_txs(&self.lm)?.contains(&tx_num) {
+            return Err(DbError::NotInDoubt(tx_num));
+        }
+
+        if commit {
+            let lsn = CommitRecord::new(tx_num).write_to_log(&self.lm)?;
+            self.lm.flush(lsn)?;
+            return Ok(());
+        }
+
+        self.do_rollback(tx, tx_num)?;
+        self.bm.flush_all(self.tx_num)?;
+        let lsn = RollbackRecord::new(tx_num).write_to_log(&self.lm)?;
+        self.lm.flush(lsn)?;
+        Ok(())
+    }
+
+    // Prepared transactions that have been neither committed nor rolled back.
+    pub fn in_doubt_txs(lm: &LogManager) -> Result<Vec<i32>> {
+        Self::analyze(lm, |_| {})
+    }
+
+    // The largest transaction number in the log.
+    pub fn max_tx_num(lm: &LogManager) -> Result<i32> {
+        let mut max_tx_num = 0;
+        for bytes in lm.iter()? {
+            let bytes = bytes?;
+            if let Some(rec) = create_log_record(bytes.clone()) {
+                max_tx_num = max_tx_num.max(rec.tx_num());
+                if rec.op() == CHECKPOINT {
+                    let rec = CheckpointRecord::from(Page::from(bytes));
+                    max_tx_num = max_tx_num.max(rec.last_tx_num());
+                }
+            }
         }
+        Ok(max_tx_num)
     }
 
-    pub fn set_int(&mut self, buff: &Buffer, offset: i32, _new_value: i32) -> Result<i32> {
+    pub fn set_int(&self, buff: &Buffer, offset: i32, new_value: i32) -> Result<i32> {
         let old_value = buff.contents.get_int(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe { SetIntRecord::new(self.tx_num, block, offset, old_value).write_to_log(&mut *lm) }
+        SetIntRecord::new(self.tx_num, block, offset, old_value, new_value).write_to_log(&self.lm)
     }
 
-    pub fn set_bytes(&mut self, buff: &Buffer, offset: i32, _new_value: &[u8]) -> Result<i32> {
+    pub fn set_bytes(&self, buff: &Buffer, offset: i32, new_value: &[u8]) -> Result<i32> {
         let old_value = buff.contents.get_bytes(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe { SetBytesRecord::new(self.tx_num, block, offset, old_value).write_to_log(&mut *lm) }
+        SetBytesRecord::new(self.tx_num, block, offset, old_value, new_value.to_vec())
+            .write_to_log(&self.lm)
     }
 
-    pub fn set_bool(&mut self, buff: &Buffer, offset: i32, _new_value: bool) -> Result<i32> {
+    pub fn set_bool(&self, buff: &Buffer, offset: i32, new_value: bool) -> Result<i32> {
         let old_value = buff.contents.get_bool(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe { SetBoolRecord::new(self.tx_num, block, offset, old_value).write_to_log(&mut *lm) }
+        SetBoolRecord::new(self.tx_num, block, offset, old_value, new_value).write_to_log(&self.lm)
     }
 
-    pub fn set_string(&mut self, buff: &Buffer, offset: i32, _new_value: &str) -> Result<i32> {
+    pub fn set_string(&self, buff: &Buffer, offset: i32, new_value: &str) -> Result<i32> {
         let old_value = buff.contents.get_string(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe {
-            SetStringRecord::new(self.tx_num, block, offset, &old_value).write_to_log(&mut *lm)
-        }
+        SetStringRecord::new(self.tx_num, block, offset, &old_value, new_value)
+            .write_to_log(&self.lm)
     }
 
-    pub fn set_double(&mut self, buff: &Buffer, offset: i32, _new_value: f64) -> Result<i32> {
+    pub fn set_double(&self, buff: &Buffer, offset: i32, new_value: f64) -> Result<i32> {
         let old_value = buff.contents.get_double(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe {
-            SetDoubleRecord::new(self.tx_num, block, offset, old_value).write_to_log(&mut *lm)
-        }
+        SetDoubleRecord::new(self.tx_num, block, offset, old_value, new_value)
+            .write_to_log(&self.lm)
     }
 
     pub fn set_date(
-        &mut self,
+        &self,
         buff: &Buffer,
         offset: i32,
-        _new_value: &Option<chrono::NaiveDate>,
+        new_value: &Option<chrono::NaiveDate>,
     ) -> Result<i32> {
         let old_value = buff.contents.get_date(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe { SetDateRecord::new(self.tx_num, block, offset, old_value).write_to_log(&mut *lm) }
+        SetDateRecord::new(self.tx_num, block, offset, old_value, *new_value).write_to_log(&self.lm)
     }
 
     pub fn set_time(
-        &mut self,
+        &self,
         buff: &Buffer,
         offset: i32,
-        _new_value: &Option<chrono::NaiveTime>,
+        new_value: &Option<chrono::NaiveTime>,
     ) -> Result<i32> {
         let old_value = buff.contents.get_time(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe { SetTimeRecord::new(self.tx_num, block, offset, old_value).write_to_log(&mut *lm) }
+        SetTimeRecord::new(self.tx_num, block, offset, old_value, *new_value).write_to_log(&self.lm)
     }
 
     pub fn set_datetime(
-        &mut self,
+        &self,
         buff: &Buffer,
         offset: i32,
-        _new_value: &Option<chrono::DateTime<chrono::FixedOffset>>,
+        new_value: &Option<chrono::DateTime<chrono::FixedOffset>>,
     ) -> Result<i32> {
         let old_value = buff.contents.get_datetime(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe {
-            SetDatetimeRecord::new(self.tx_num, block, offset, old_value).write_to_log(&mut *lm)
-        }
+        SetDatetimeRecord::new(self.tx_num, block, offset, old_value, *new_value)
+            .write_to_log(&self.lm)
     }
 
     pub fn set_json(
-        &mut self,
+        &self,
         buff: &Buffer,
         offset: i32,
-        _new_value: &Option<serde_json::Value>,
+        new_value: &Option<serde_json::Value>,
     ) -> Result<i32> {
         let old_value = buff.contents.get_json(offset);
         let block = buff.block().clone().unwrap();
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        unsafe { SetJsonRecord::new(self.tx_num, block, offset, &old_value).write_to_log(&mut *lm) }
+        SetJsonRecord::new(self.tx_num, block, offset, &old_value, new_value).write_to_log(&self.lm)
+    }
+
+    fn do_rollback(&self, tx: &mut Transaction, tx_num: i32) -> Result<()> {
+        self.undo_changes(tx, tx_num, None)
     }
 
-    fn do_rollback(&mut self, tx: &mut Transaction) {
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
-        for bytes in unsafe { (*lm).iter().unwrap() } {
-            let rec = create_log_record(bytes).unwrap();
-            if rec.tx_num() == self.tx_num {
-                if rec.op() == START {
-                    return;
+    // Undo the changes of the transaction back to its start, or to the latest savepoint named `savepoint`.
+    // The changes undone by an earlier rollback to a savepoint are skipped,
+    // since other transactions may have changed the values since.
+    fn undo_changes(
+        &self,
+        tx: &mut Transaction,
+        tx_num: i32,
+        savepoint: Option<&str>,
+    ) -> Result<()> {
+        let mut skipped = SkippedChanges::default();
+        for bytes in self.lm.iter()? {
+            let bytes = bytes?;
+            let Some(rec) = create_log_record(bytes.clone()) else {
+                break;
+            };
+            if rec.tx_num() != tx_num {
+                continue;
+            }
+            // The savepoint ends the undo even when it also ends a range skipped for it.
+            if let (SAVEPOINT, Some(name)) = (rec.op(), savepoint) {
+                if skipped
+                    .skipping_to(tx_num)
+                    .is_none_or(|skipping| skipping == name)
+                    && SavepointRecord::from(Page::from(bytes.clone())).name() == name
+                {
+                    break;
                 }
-                rec.undo(tx);
+            }
+            if skipped.skips(rec.as_ref(), &bytes) {
+                continue;
+            }
+            match rec.op() {
+                START => break,
+                _ => rec.undo(tx, false),
             }
         }
+        Ok(())
     }
 
-    fn do_recover(&mut self, tx: &mut Transaction) {
-        let lm = Arc::as_ptr(&self.lm) as *mut LogManager;
+    // Undo unfinished transactions except prepared ones, and return the prepared ones.
+    fn do_recover(&self, tx: &mut Transaction) -> Result<Vec<i32>> {
+        Self::analyze(&self.lm, |rec| rec.undo(tx, false))
+    }
+
+    // Scan the log backwards until the last checkpoint,
+    // passing records of unfinished and not prepared transactions to `undo`.
+    fn analyze(lm: &LogManager, mut undo: impl FnMut(Box<dyn LogRecord>)) -> Result<Vec<i32>> {
         let mut finished_txs = vec![];
+        let mut in_doubt_txs = vec![];
         let mut unfinished_txs: Option<HashSet<i32>> = None;
-        for bytes in unsafe { (*lm).iter().unwrap() } {
-            let rec = create_log_record(bytes.clone()).unwrap();
+        let mut skipped = SkippedChanges::default();
+        for bytes in lm.iter()? {
+            let bytes = bytes?;
+            // The log ends at an unreadable record.
+            let Some(rec) = create_log_record(bytes.clone()) else {
+                break;
+            };
+            if skipped.skips(rec.as_ref(), &bytes) {
+                continue;
+            }
             if rec.op() == CHECKPOINT {
-                return;
+                break;
             } else if rec.op() == NQCKPT && unfinished_txs.is_none() {
                 let rec = NqCkptRecord::from(Page::from(bytes));
                 unfinished_txs = Some(rec.tx_nums());
@@ -205,14 +367,59 @@ impl RecoveryManager {
                 if let Some(unfinished_txs) = &mut unfinished_txs {
                     unfinished_txs.remove(&rec.tx_num());
                     if unfinished_txs.is_empty() {
-                        return;
+                        break;
                     }
                 }
             } else if rec.op() == COMMIT || rec.op() == ROLLBACK {
                 finished_txs.push(rec.tx_num());
-            } else if !finished_txs.contains(&rec.tx_num()) {
-                rec.undo(tx);
+            } else if rec.op() == PREPARE {
+                if !finished_txs.contains(&rec.tx_num()) {
+                    in_doubt_txs.push(rec.tx_num());
+                }
+            } else if !finished_txs.contains(&rec.tx_num()) && !in_doubt_txs.contains(&rec.tx_num())
+            {
+                undo(rec);
+            }
+        }
+        in_doubt_txs.sort();
+        Ok(in_doubt_txs)
+    }
+}
+
+// Tracks, while the log is read backwards, the changes already undone by a rollback to a savepoint:
+// those between a ROLLBACK_TO record and the latest SAVEPOINT record of the same name before it.
+#[derive(Default)]
+struct SkippedChanges {
+    // The savepoint each transaction is skipping back to.
+    savepoints: HashMap<i32, String>,
+}
+
+impl SkippedChanges {
+    // The savepoint the transaction is skipping back to, if any.
+    fn skipping_to(&self, tx_num: i32) -> Option<&str> {
+        self.savepoints.get(&tx_num).map(|name| name.as_str())
+    }
+
+    // Whether the record is one of the changes already undone, or marks the range of them.
+    fn skips(&mut self, rec: &dyn LogRecord, bytes: &[u8]) -> bool {
+        let tx_num = rec.tx_num();
+        match rec.op() {
+            // A rollback within a skipped range undid nothing outside it.
+            ROLLBACK_TO => {
+                let name = RollbackToRecord::from(Page::from(bytes.to_vec()))
+                    .name()
+                    .to_string();
+                self.savepoints.entry(tx_num).or_insert(name);
+                true
+            }
+            SAVEPOINT if self.savepoints.contains_key(&tx_num) => {
+                let rec = SavepointRecord::from(Page::from(bytes.to_vec()));
+                if self.savepoints[&tx_num] == rec.name() {
+                    self.savepoints.remove(&tx_num);
+                }
+                true
             }
+            _ => rec.block().is_some() && self.savepoints.contains_key(&tx_num),
         }
     }
 }
@@ -19,7 +19,7 @@ impl RollbackRecord {
         Self { tx_num }
     }
 
-    pub fn write_to_log(&self, lm: &mut LogManager) -> Result<i32> {
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
         let page = Page::from(self);
         lm.append(page.buffer())
     }
@@ -49,7 +49,11 @@ impl LogRecord for RollbackRecord {
         self.tx_num
     }
 
-    fn undo(&self, _tx: &mut Transaction) {}
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({"type": "ROLLBACK", "tx": self.tx_num})
+    }
+
+    fn undo(&self, _tx: &mut Transaction, _log: bool) {}
 }
 
 impl std::fmt::Display for RollbackRecord {
@@ -0,0 +1,102 @@
+#![allow(dead_code)]
+
+use crate::{
+    file::page::Page,
+    log::log_manager::LogManager,
+    tx::transaction::Transaction,
+    util::{Result, INTEGER_BYTES},
+};
+
+use super::log_record::{LogRecord, ROLLBACK_TO};
+
+// Marks the changes after the savepoint as undone, for the log to be replayed.
+#[derive(PartialEq, Debug)]
+pub struct RollbackToRecord {
+    tx_num: i32,
+    name: String,
+}
+
+impl RollbackToRecord {
+    pub fn new(tx_num: i32, name: &str) -> Self {
+        Self {
+            tx_num,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
+        let page = Page::from(self);
+        lm.append(page.buffer())
+    }
+}
+
+impl From<Page> for RollbackToRecord {
+    fn from(page: Page) -> Self {
+        let tpos = INTEGER_BYTES;
+        let tx_num = page.get_int(tpos);
+
+        let npos = tpos + INTEGER_BYTES;
+        let name = page.get_string(npos);
+
+        Self { tx_num, name }
+    }
+}
+impl From<&RollbackToRecord> for Page {
+    fn from(record: &RollbackToRecord) -> Self {
+        let tpos = INTEGER_BYTES;
+        let npos = tpos + INTEGER_BYTES;
+
+        let mut page = Page::new(npos + Page::str_len(&record.name));
+        page.set_int(0, ROLLBACK_TO);
+        page.set_int(tpos, record.tx_num);
+        page.set_string(npos, &record.name);
+        page
+    }
+}
+
+impl LogRecord for RollbackToRecord {
+    fn op(&self) -> i32 {
+        ROLLBACK_TO
+    }
+
+    fn tx_num(&self) -> i32 {
+        self.tx_num
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({"type": "ROLLBACK_TO", "tx": self.tx_num, "name": self.name})
+    }
+
+    fn undo(&self, _tx: &mut Transaction, _log: bool) {}
+}
+
+impl std::fmt::Display for RollbackToRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<ROLLBACK_TO {} {}>", self.tx_num, self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let record = RollbackToRecord::new(1, "sp1");
+
+        let record2 = RollbackToRecord::from(Page::from(&record));
+
+        assert_eq!(record, record2);
+    }
+
+    #[test]
+    fn to_string() {
+        let record = RollbackToRecord::new(1, "sp1");
+
+        assert_eq!(record.to_string(), "<ROLLBACK_TO 1 sp1>");
+    }
+}
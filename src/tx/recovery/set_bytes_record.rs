@@ -3,31 +3,40 @@
 use crate::{
     file::{block_id::BlockId, page::Page},
     log::log_manager::LogManager,
+    query::constant::Constant,
     tx::transaction::Transaction,
     util::{Result, INTEGER_BYTES},
 };
 
-use super::log_record::{LogRecord, SET_BYTES};
+use super::log_record::{set_record_json, LogRecord, SET_BYTES};
 
 #[derive(PartialEq, Debug)]
 pub struct SetBytesRecord {
     tx_num: i32,
     offset: i32,
     old_value: Vec<u8>,
+    new_value: Vec<u8>,
     block: BlockId,
 }
 
 impl SetBytesRecord {
-    pub fn new(tx_num: i32, block: BlockId, offset: i32, old_value: Vec<u8>) -> Self {
+    pub fn new(
+        tx_num: i32,
+        block: BlockId,
+        offset: i32,
+        old_value: Vec<u8>,
+        new_value: Vec<u8>,
+    ) -> Self {
         Self {
             tx_num,
             offset,
             old_value,
+            new_value,
             block,
         }
     }
 
-    pub fn write_to_log(&self, lm: &mut LogManager) -> Result<i32> {
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
         let page = Page::from(self);
         lm.append(page.buffer())
     }
@@ -50,10 +59,14 @@ impl From<Page> for SetBytesRecord {
         let vpos = opos + INTEGER_BYTES;
         let old_value: Vec<u8> = page.get_bytes(vpos).to_vec();
 
+        let npos = vpos + Page::bytes_len(&old_value);
+        let new_value: Vec<u8> = page.get_bytes(npos).to_vec();
+
         Self {
             tx_num,
             offset,
             old_value,
+            new_value,
             block: BlockId::new(filename, block_num),
         }
     }
@@ -65,8 +78,9 @@ impl From<&SetBytesRecord> for Page {
         let bpos = fpos + Page::str_len(record.block.filename());
         let opos = bpos + INTEGER_BYTES;
         let vpos = opos + INTEGER_BYTES;
+        let npos = vpos + Page::bytes_len(&record.old_value);
 
-        let mut page = Page::new(vpos + Page::bytes_len(&record.old_value));
+        let mut page = Page::new(npos + Page::bytes_len(&record.new_value));
 
         page.set_int(0, SET_BYTES);
         page.set_int(tpos, record.tx_num);
@@ -74,6 +88,7 @@ impl From<&SetBytesRecord> for Page {
         page.set_int(bpos, record.block.block_num());
         page.set_int(opos, record.offset);
         page.set_bytes(vpos, &record.old_value);
+        page.set_bytes(npos, &record.new_value);
 
         page
     }
@@ -88,9 +103,43 @@ impl LogRecord for SetBytesRecord {
         self.tx_num
     }
 
-    fn undo(&self, tx: &mut Transaction) {
+    fn block(&self) -> Option<&BlockId> {
+        Some(&self.block)
+    }
+
+    fn offset(&self) -> Option<i32> {
+        Some(self.offset)
+    }
+
+    fn old_value(&self) -> Option<Constant> {
+        Some(Constant::from(self.old_value.clone()))
+    }
+
+    fn new_value(&self) -> Option<Constant> {
+        Some(Constant::from(self.new_value.clone()))
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        set_record_json(
+            SET_BYTES,
+            self.tx_num,
+            &self.block,
+            self.offset,
+            serde_json::json!(self.old_value),
+            serde_json::json!(self.new_value),
+        )
+    }
+
+    fn undo(&self, tx: &mut Transaction, log: bool) {
         tx.pin(&self.block).unwrap();
-        tx.set_bytes(&self.block, self.offset, &self.old_value, false)
+        tx.set_bytes(&self.block, self.offset, &self.old_value, log)
+            .unwrap();
+        tx.unpin(&self.block);
+    }
+
+    fn redo(&self, tx: &mut Transaction, log: bool) {
+        tx.pin(&self.block).unwrap();
+        tx.set_bytes(&self.block, self.offset, &self.new_value, log)
             .unwrap();
         tx.unpin(&self.block);
     }
@@ -112,8 +161,13 @@ mod tests {
 
     #[test]
     fn test() {
-        let record =
-            SetBytesRecord::new(1, BlockId::new("filename".to_string(), 2), 3, vec![4, 5, 6]);
+        let record = SetBytesRecord::new(
+            1,
+            BlockId::new("filename".to_string(), 2),
+            3,
+            vec![4, 5, 6],
+            vec![7],
+        );
 
         let record2 = SetBytesRecord::from(Page::from(&record));
 
@@ -122,8 +176,13 @@ mod tests {
 
     #[test]
     fn to_string() {
-        let record =
-            SetBytesRecord::new(1, BlockId::new("filename".to_string(), 2), 3, vec![4, 5, 6]);
+        let record = SetBytesRecord::new(
+            1,
+            BlockId::new("filename".to_string(), 2),
+            3,
+            vec![4, 5, 6],
+            vec![7],
+        );
 
         assert_eq!(
             record.to_string(),
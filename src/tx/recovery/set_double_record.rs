@@ -3,31 +3,34 @@
 use crate::{
     file::{block_id::BlockId, page::Page},
     log::log_manager::LogManager,
+    query::constant::Constant,
     tx::transaction::Transaction,
     util::{Result, INTEGER_BYTES},
 };
 
-use super::log_record::{LogRecord, SET_DOUBLE};
+use super::log_record::{set_record_json, LogRecord, SET_DOUBLE};
 
 #[derive(PartialEq, Debug)]
 pub struct SetDoubleRecord {
     tx_num: i32,
     offset: i32,
     old_value: f64,
+    new_value: f64,
     block: BlockId,
 }
 
 impl SetDoubleRecord {
-    pub fn new(tx_num: i32, block: BlockId, offset: i32, old_value: f64) -> Self {
+    pub fn new(tx_num: i32, block: BlockId, offset: i32, old_value: f64, new_value: f64) -> Self {
         Self {
             tx_num,
             offset,
             old_value,
+            new_value,
             block,
         }
     }
 
-    pub fn write_to_log(&self, lm: &mut LogManager) -> Result<i32> {
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
         let page = Page::from(self);
         lm.append(page.buffer())
     }
@@ -50,10 +53,14 @@ impl From<Page> for SetDoubleRecord {
         let vpos = opos + INTEGER_BYTES;
         let old_value = page.get_double(vpos);
 
+        let npos = vpos + Page::double_len(old_value);
+        let new_value = page.get_double(npos);
+
         Self {
             tx_num,
             offset,
             old_value,
+            new_value,
             block: BlockId::new(filename, block_num),
         }
     }
@@ -65,8 +72,9 @@ impl From<&SetDoubleRecord> for Page {
         let bpos = fpos + Page::str_len(record.block.filename());
         let opos = bpos + INTEGER_BYTES;
         let vpos = opos + INTEGER_BYTES;
+        let npos = vpos + Page::double_len(record.old_value);
 
-        let mut page = Page::new(vpos + Page::double_len(record.old_value));
+        let mut page = Page::new(npos + Page::double_len(record.new_value));
 
         page.set_int(0, SET_DOUBLE);
         page.set_int(tpos, record.tx_num);
@@ -74,6 +82,7 @@ impl From<&SetDoubleRecord> for Page {
         page.set_int(bpos, record.block.block_num());
         page.set_int(opos, record.offset);
         page.set_double(vpos, record.old_value);
+        page.set_double(npos, record.new_value);
 
         page
     }
@@ -88,9 +97,43 @@ impl LogRecord for SetDoubleRecord {
         self.tx_num
     }
 
-    fn undo(&self, tx: &mut Transaction) {
+    fn block(&self) -> Option<&BlockId> {
+        Some(&self.block)
+    }
+
+    fn offset(&self) -> Option<i32> {
+        Some(self.offset)
+    }
+
+    fn old_value(&self) -> Option<Constant> {
+        Some(Constant::from(self.old_value))
+    }
+
+    fn new_value(&self) -> Option<Constant> {
+        Some(Constant::from(self.new_value))
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        set_record_json(
+            SET_DOUBLE,
+            self.tx_num,
+            &self.block,
+            self.offset,
+            serde_json::json!(self.old_value),
+            serde_json::json!(self.new_value),
+        )
+    }
+
+    fn undo(&self, tx: &mut Transaction, log: bool) {
+        tx.pin(&self.block).unwrap();
+        tx.set_double(&self.block, self.offset, self.old_value, log)
+            .unwrap();
+        tx.unpin(&self.block);
+    }
+
+    fn redo(&self, tx: &mut Transaction, log: bool) {
         tx.pin(&self.block).unwrap();
-        tx.set_double(&self.block, self.offset, self.old_value, false)
+        tx.set_double(&self.block, self.offset, self.new_value, log)
             .unwrap();
         tx.unpin(&self.block);
     }
@@ -112,7 +155,7 @@ mod test {
 
     #[test]
     fn test() {
-        let record = SetDoubleRecord::new(3, BlockId::new("filename".to_string(), 2), 4, 5.0);
+        let record = SetDoubleRecord::new(3, BlockId::new("filename".to_string(), 2), 4, 5.0, 6.0);
 
         let record2 = SetDoubleRecord::from(Page::from(&record));
 
@@ -121,7 +164,7 @@ mod test {
 
     #[test]
     fn to_string() {
-        let record = SetDoubleRecord::new(3, BlockId::new("filename".to_string(), 2), 4, 5.6);
+        let record = SetDoubleRecord::new(3, BlockId::new("filename".to_string(), 2), 4, 5.6, 7.8);
 
         assert_eq!(
             format!("{}", record),
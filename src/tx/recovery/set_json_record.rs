@@ -3,17 +3,19 @@
 use crate::{
     file::{block_id::BlockId, page::Page},
     log::log_manager::LogManager,
+    query::constant::Constant,
     tx::transaction::Transaction,
     util::{Result, INTEGER_BYTES},
 };
 
-use super::log_record::{LogRecord, SET_JSON};
+use super::log_record::{set_record_json, LogRecord, SET_JSON};
 
 #[derive(PartialEq, Debug)]
 pub struct SetJsonRecord {
     tx_num: i32,
     offset: i32,
     old_value: Option<serde_json::Value>,
+    new_value: Option<serde_json::Value>,
     block: BlockId,
 }
 
@@ -23,16 +25,18 @@ impl SetJsonRecord {
         block: BlockId,
         offset: i32,
         old_value: &Option<serde_json::Value>,
+        new_value: &Option<serde_json::Value>,
     ) -> Self {
         Self {
             tx_num,
             offset,
             old_value: old_value.clone(),
+            new_value: new_value.clone(),
             block,
         }
     }
 
-    pub fn write_to_log(&self, lm: &mut LogManager) -> Result<i32> {
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
         let page = Page::from(self);
         lm.append(page.buffer())
     }
@@ -55,10 +59,14 @@ impl From<Page> for SetJsonRecord {
         let vpos = opos + INTEGER_BYTES;
         let old_value = page.get_json(vpos);
 
+        let npos = vpos + Page::json_len(&old_value);
+        let new_value = page.get_json(npos);
+
         Self {
             tx_num,
             offset,
             old_value,
+            new_value,
             block: BlockId::new(filename, block_num),
         }
     }
@@ -70,8 +78,9 @@ impl From<&SetJsonRecord> for Page {
         let bpos = fpos + Page::str_len(record.block.filename());
         let opos = bpos + INTEGER_BYTES;
         let vpos = opos + INTEGER_BYTES;
+        let npos = vpos + Page::json_len(&record.old_value);
 
-        let mut page = Page::new(vpos + Page::json_len(&record.old_value));
+        let mut page = Page::new(npos + Page::json_len(&record.new_value));
 
         page.set_int(0, SET_JSON);
         page.set_int(tpos, record.tx_num);
@@ -79,6 +88,7 @@ impl From<&SetJsonRecord> for Page {
         page.set_int(bpos, record.block.block_num());
         page.set_int(opos, record.offset);
         page.set_json(vpos, &record.old_value);
+        page.set_json(npos, &record.new_value);
 
         page
     }
@@ -93,9 +103,43 @@ impl LogRecord for SetJsonRecord {
         self.tx_num
     }
 
-    fn undo(&self, tx: &mut Transaction) {
+    fn block(&self) -> Option<&BlockId> {
+        Some(&self.block)
+    }
+
+    fn offset(&self) -> Option<i32> {
+        Some(self.offset)
+    }
+
+    fn old_value(&self) -> Option<Constant> {
+        self.old_value.clone().map(Constant::from)
+    }
+
+    fn new_value(&self) -> Option<Constant> {
+        self.new_value.clone().map(Constant::from)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        set_record_json(
+            SET_JSON,
+            self.tx_num,
+            &self.block,
+            self.offset,
+            serde_json::json!(self.old_value),
+            serde_json::json!(self.new_value),
+        )
+    }
+
+    fn undo(&self, tx: &mut Transaction, log: bool) {
+        tx.pin(&self.block).unwrap();
+        tx.set_json(&self.block, self.offset, &self.old_value, log)
+            .unwrap();
+        tx.unpin(&self.block);
+    }
+
+    fn redo(&self, tx: &mut Transaction, log: bool) {
         tx.pin(&self.block).unwrap();
-        tx.set_json(&self.block, self.offset, &self.old_value, false)
+        tx.set_json(&self.block, self.offset, &self.new_value, log)
             .unwrap();
         tx.unpin(&self.block);
     }
@@ -122,6 +166,7 @@ mod test {
             BlockId::new("filename".to_string(), 2),
             3,
             &Some(serde_json::json!({ "key": "value" })),
+            &None,
         );
 
         let record2 = SetJsonRecord::from(Page::from(&record));
@@ -136,6 +181,7 @@ mod test {
             BlockId::new("filename".to_string(), 2),
             3,
             &Some(serde_json::json!({ "key": "value" })),
+            &None,
         );
         assert_eq!(
             record.to_string(),
@@ -3,17 +3,19 @@
 use crate::{
     file::{block_id::BlockId, page::Page},
     log::log_manager::LogManager,
+    query::constant::Constant,
     tx::transaction::Transaction,
     util::{Result, INTEGER_BYTES},
 };
 
-use super::log_record::{LogRecord, SET_TIME};
+use super::log_record::{set_record_json, LogRecord, SET_TIME};
 
 #[derive(PartialEq, Debug)]
 pub struct SetTimeRecord {
     tx_num: i32,
     offset: i32,
     old_value: Option<chrono::NaiveTime>,
+    new_value: Option<chrono::NaiveTime>,
     block: BlockId,
 }
 
@@ -23,16 +25,18 @@ impl SetTimeRecord {
         block: BlockId,
         offset: i32,
         old_value: Option<chrono::NaiveTime>,
+        new_value: Option<chrono::NaiveTime>,
     ) -> Self {
         Self {
             tx_num,
             offset,
             old_value,
+            new_value,
             block,
         }
     }
 
-    pub fn write_to_log(&self, lm: &mut LogManager) -> Result<i32> {
+    pub fn write_to_log(&self, lm: &LogManager) -> Result<i32> {
         let page = Page::from(self);
         lm.append(page.buffer())
     }
@@ -55,10 +59,14 @@ impl From<Page> for SetTimeRecord {
         let vpos = opos + INTEGER_BYTES;
         let old_value = page.get_time(vpos);
 
+        let npos = vpos + Page::time_len(&old_value);
+        let new_value = page.get_time(npos);
+
         Self {
             tx_num,
             offset,
             old_value,
+            new_value,
             block: BlockId::new(filename, block_num),
         }
     }
@@ -70,8 +78,9 @@ impl From<&SetTimeRecord> for Page {
         let bpos = fpos + Page::str_len(record.block.filename());
         let opos = bpos + INTEGER_BYTES;
         let vpos = opos + INTEGER_BYTES;
+        let npos = vpos + Page::time_len(&record.old_value);
 
-        let mut page = Page::new(vpos + Page::time_len(&record.old_value));
+        let mut page = Page::new(npos + Page::time_len(&record.new_value));
 
         page.set_int(0, SET_TIME);
         page.set_int(tpos, record.tx_num);
@@ -79,6 +88,7 @@ impl From<&SetTimeRecord> for Page {
         page.set_int(bpos, record.block.block_num());
         page.set_int(opos, record.offset);
         page.set_time(vpos, &record.old_value);
+        page.set_time(npos, &record.new_value);
 
         page
     }
@@ -93,9 +103,43 @@ impl LogRecord for SetTimeRecord {
         self.tx_num
     }
 
-    fn undo(&self, tx: &mut Transaction) {
+    fn block(&self) -> Option<&BlockId> {
+        Some(&self.block)
+    }
+
+    fn offset(&self) -> Option<i32> {
+        Some(self.offset)
+    }
+
+    fn old_value(&self) -> Option<Constant> {
+        self.old_value.map(Constant::from)
+    }
+
+    fn new_value(&self) -> Option<Constant> {
+        self.new_value.map(Constant::from)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        set_record_json(
+            SET_TIME,
+            self.tx_num,
+            &self.block,
+            self.offset,
+            serde_json::json!(self.old_value.map(|v| v.to_string())),
+            serde_json::json!(self.new_value.map(|v| v.to_string())),
+        )
+    }
+
+    fn undo(&self, tx: &mut Transaction, log: bool) {
+        tx.pin(&self.block).unwrap();
+        tx.set_time(&self.block, self.offset, &self.old_value, log)
+            .unwrap();
+        tx.unpin(&self.block);
+    }
+
+    fn redo(&self, tx: &mut Transaction, log: bool) {
         tx.pin(&self.block).unwrap();
-        tx.set_time(&self.block, self.offset, &self.old_value, false)
+        tx.set_time(&self.block, self.offset, &self.new_value, log)
             .unwrap();
         tx.unpin(&self.block);
     }
@@ -122,6 +166,7 @@ mod test {
             BlockId::new("filename".to_string(), 2),
             3,
             chrono::NaiveTime::from_hms_opt(4, 5, 6),
+            chrono::NaiveTime::from_hms_opt(7, 8, 9),
         );
 
         let record2 = SetTimeRecord::from(Page::from(&record));
@@ -136,6 +181,7 @@ mod test {
             BlockId::new("filename".to_string(), 2),
             3,
             chrono::NaiveTime::from_hms_opt(4, 5, 6),
+            chrono::NaiveTime::from_hms_opt(7, 8, 9),
         );
 
         assert_eq!(
@@ -0,0 +1,249 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use crate::{error::DbError, file::block_id::BlockId, util::Result};
+
+use super::snapshot::Snapshot;
+
+struct TxState {
+    snapshot: Snapshot,
+    committed: bool,
+    // Concurrent transactions that read what this one wrote.
+    in_conflicts: HashSet<i32>,
+    // Concurrent transactions that wrote what this one read.
+    out_conflicts: HashSet<i32>,
+}
+
+#[derive(Default)]
+struct State {
+    txs: HashMap<i32, TxState>,
+    // SIREAD markers: the transactions that read each record slot.
+    readers: HashMap<(BlockId, i32), HashSet<i32>>,
+    writers: HashMap<(BlockId, i32), HashSet<i32>>,
+}
+
+// Read-write antidependencies between transactions running under serializable snapshot isolation.
+// A transaction with both an incoming and an outgoing antidependency may break serializability,
+// so it is aborted when it commits.
+// The reads and writes of a committed transaction are kept until no running transaction overlaps it.
+pub struct SireadTable {
+    state: Mutex<State>,
+}
+
+impl Default for SireadTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SireadTable {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn register(&self, snapshot: Snapshot) {
+        let mut state = self.state.lock().unwrap();
+        let tx_num = snapshot.tx_num();
+        state.txs.insert(
+            tx_num,
+            TxState {
+                snapshot,
+                committed: false,
+                in_conflicts: HashSet::new(),
+                out_conflicts: HashSet::new(),
+            },
+        );
+    }
+
+    // Leave a SIREAD marker on the slot, and record that `tx_num` did not see
+    // the writes of the concurrent transactions to it.
+    pub fn read(&self, tx_num: i32, block: &BlockId, slot: i32) {
+        let mut state = self.state.lock().unwrap();
+        if !state.txs.contains_key(&tx_num) {
+            return;
+        }
+        let key = (block.clone(), slot);
+        state.readers.entry(key.clone()).or_default().insert(tx_num);
+        let writers = state.writers.get(&key).cloned().unwrap_or_default();
+        for writer in writers {
+            state.add_conflict(tx_num, writer);
+        }
+    }
+
+    // Record that the readers of the slot running concurrently with `tx_num` did not see its write.
+    pub fn write(&self, tx_num: i32, block: &BlockId, slot: i32) {
+        let mut state = self.state.lock().unwrap();
+        if !state.txs.contains_key(&tx_num) {
+            return;
+        }
+        let key = (block.clone(), slot);
+        state.writers.entry(key.clone()).or_default().insert(tx_num);
+        let readers = state.readers.get(&key).cloned().unwrap_or_default();
+        for reader in readers {
+            state.add_conflict(reader, tx_num);
+        }
+    }
+
+    // Fail if the transaction is the pivot of a dangerous structure,
+    // or reads past a committed pivot.
+    pub fn commit(&self, tx_num: i32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(tx) = state.txs.get(&tx_num) else {
+            return Ok(());
+        };
+        let is_pivot = !tx.in_conflicts.is_empty() && !tx.out_conflicts.is_empty();
+        let past_pivot = tx.out_conflicts.iter().any(|t| {
+            let out = &state.txs[t];
+            out.committed && !out.out_conflicts.is_empty()
+        });
+        if is_pivot || past_pivot {
+            return Err(DbError::SerializationFailure);
+        }
+        state.txs.get_mut(&tx_num).unwrap().committed = true;
+        Ok(())
+    }
+
+    // Forget a transaction that rolled back, and the committed ones no running transaction overlaps.
+    pub fn finish(&self, tx_num: i32) {
+        let mut state = self.state.lock().unwrap();
+        if state.txs.get(&tx_num).is_some_and(|tx| !tx.committed) {
+            state.remove(tx_num);
+        }
+        let settled: Vec<i32> = state
+            .txs
+            .iter()
+            .filter(|(&t, tx)| {
+                tx.committed
+                    && state
+                        .txs
+                        .iter()
+                        .all(|(&o, other)| other.committed || !State::concurrent(tx, t, other, o))
+            })
+            .map(|(&t, _)| t)
+            .collect();
+        for t in settled {
+            state.remove(t);
+        }
+    }
+}
+
+impl State {
+    // `reader` did not see the write of `writer`.
+    fn add_conflict(&mut self, reader: i32, writer: i32) {
+        if reader == writer {
+            return;
+        }
+        let (Some(r), Some(w)) = (self.txs.get(&reader), self.txs.get(&writer)) else {
+            return;
+        };
+        if !Self::concurrent(r, reader, w, writer) {
+            return;
+        }
+        self.txs
+            .get_mut(&reader)
+            .unwrap()
+            .out_conflicts
+            .insert(writer);
+        self.txs
+            .get_mut(&writer)
+            .unwrap()
+            .in_conflicts
+            .insert(reader);
+    }
+
+    // Neither transaction sees the changes of the other.
+    fn concurrent(a: &TxState, a_num: i32, b: &TxState, b_num: i32) -> bool {
+        !a.snapshot.is_visible(b_num) && !b.snapshot.is_visible(a_num)
+    }
+
+    fn remove(&mut self, tx_num: i32) {
+        self.txs.remove(&tx_num);
+        for tx in self.txs.values_mut() {
+            tx.in_conflicts.remove(&tx_num);
+            tx.out_conflicts.remove(&tx_num);
+        }
+        for markers in [&mut self.readers, &mut self.writers] {
+            for txs in markers.values_mut() {
+                txs.remove(&tx_num);
+            }
+            markers.retain(|_, txs| !txs.is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(tx_nums: &[i32]) -> SireadTable {
+        let table = SireadTable::new();
+        for &tx_num in tx_nums {
+            table.register(Snapshot::new(tx_num, tx_nums.iter().cloned()));
+        }
+        table
+    }
+
+    #[test]
+    fn write_skew() {
+        let table = table(&[1, 2]);
+        let x = BlockId::new("file".to_string(), 0);
+        let y = BlockId::new("file".to_string(), 1);
+        for tx_num in [1, 2] {
+            table.read(tx_num, &x, 0);
+            table.read(tx_num, &y, 0);
+        }
+        table.write(1, &x, 0);
+        table.write(2, &y, 0);
+
+        assert!(table.commit(1).is_err());
+        table.finish(1);
+        assert!(table.commit(2).is_ok());
+    }
+
+    #[test]
+    fn single_conflict() {
+        let table = table(&[1, 2]);
+        let x = BlockId::new("file".to_string(), 0);
+        table.read(1, &x, 0);
+        table.write(2, &x, 0);
+
+        assert!(table.commit(2).is_ok());
+        assert!(table.commit(1).is_ok());
+    }
+
+    #[test]
+    fn committed_pivot() {
+        let table = table(&[1, 2, 3]);
+        let x = BlockId::new("file".to_string(), 0);
+        let y = BlockId::new("file".to_string(), 1);
+        table.read(2, &y, 0);
+        table.write(3, &y, 0);
+        assert!(table.commit(3).is_ok());
+        table.write(2, &x, 0);
+        assert!(table.commit(2).is_ok());
+
+        // 1 -> 2 -> 3, where 2 has committed
+        table.read(1, &x, 0);
+        assert!(table.commit(1).is_err());
+    }
+
+    #[test]
+    fn sequential_txs_do_not_conflict() {
+        let table = SireadTable::new();
+        let x = BlockId::new("file".to_string(), 0);
+        table.register(Snapshot::new(1, vec![]));
+        table.read(1, &x, 0);
+        assert!(table.commit(1).is_ok());
+        table.finish(1);
+
+        table.register(Snapshot::new(2, vec![]));
+        table.write(2, &x, 0);
+        assert!(table.state.lock().unwrap().txs[&2].in_conflicts.is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+
+// The transactions whose changes a transaction sees, fixed when it starts.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    tx_num: i32,
+    active: HashSet<i32>,
+}
+
+impl Snapshot {
+    pub fn new(tx_num: i32, active: impl IntoIterator<Item = i32>) -> Self {
+        Self {
+            tx_num,
+            active: active.into_iter().filter(|&t| t != tx_num).collect(),
+        }
+    }
+
+    pub fn tx_num(&self) -> i32 {
+        self.tx_num
+    }
+
+    // Changes are visible if they are the transaction's own or were committed before it started.
+    // Changes of rolled back transactions are undone before they finish, so finished means committed.
+    // 0 stands for data written outside of any transaction.
+    pub fn is_visible(&self, xid: i32) -> bool {
+        xid == 0 || xid == self.tx_num || (xid < self.tx_num && !self.active.contains(&xid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_visible() {
+        let snapshot = Snapshot::new(5, vec![2, 5]);
+
+        assert!(snapshot.is_visible(0));
+        assert!(snapshot.is_visible(1));
+        assert!(!snapshot.is_visible(2)); // active when the snapshot was taken
+        assert!(snapshot.is_visible(3));
+        assert!(snapshot.is_visible(5)); // own changes
+        assert!(!snapshot.is_visible(6)); // started later
+    }
+}
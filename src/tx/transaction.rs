@@ -2,62 +2,144 @@
 #![allow(unused_variables)]
 
 use std::{
-    sync::{Arc, Mutex, RwLock, RwLockReadGuard},
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
     thread,
     time::Duration,
 };
 
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
     buffer::buffer_manager::BufferManager,
-    file::{block_id::BlockId, file_manager::FileManager},
+    error::DbError,
+    file::{block_id::BlockId, file_manager::FileManager, page::Page},
     log::log_manager::LogManager,
     util::{current_time_millis, waiting_too_long, Result},
 };
 
 use super::{
     buffer_list::BufferList,
-    concurrency::{concurrency_manager::ConcurrencyManager, lock_table::LockTable},
+    concurrency::{
+        concurrency_manager::ConcurrencyManager,
+        lock_table::{LockTable, LockTarget},
+        occ_validator::{OccValidator, RangeSet},
+    },
     recovery::{
         checkpoint_record::CheckpointRecord, nq_ckpt_record::NqCkptRecord,
         recovery_manager::RecoveryManager,
     },
+    siread_table::SireadTable,
+    snapshot::Snapshot,
+    tx_options::{IsolationLevel, TxOptions},
+    version_store::VersionStore,
 };
 
 static NEXT_TX_NUM: Mutex<i32> = Mutex::new(0);
 static CHECKPOINT_LOCK: Mutex<()> = Mutex::new(());
-static TRANSACTION_LOCK: RwLock<()> = RwLock::new(());
+static ACTIVE_TXS: Mutex<i32> = Mutex::new(0);
 static TRANSACTIONS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
 const END_OF_FILE: i32 = -1;
 
-pub struct Transaction<'a> {
+type DeferredWrite = Box<dyn FnOnce(&mut Transaction, &BlockId) -> Result<()> + Send>;
+
+// Held by every transaction that may write while it runs, so that a quiescent checkpoint can wait for them.
+struct ActiveTx;
+
+impl ActiveTx {
+    fn new() -> Self {
+        *ACTIVE_TXS.lock().unwrap() += 1;
+        ActiveTx
+    }
+}
+
+impl Drop for ActiveTx {
+    fn drop(&mut self) {
+        *ACTIVE_TXS.lock().unwrap() -= 1;
+    }
+}
+
+// The private state of an optimistic transaction.
+// Its writes go to private copies of the blocks, and are applied when it commits.
+#[derive(Default)]
+struct WriteBuffer {
+    read_set: RangeSet,
+    write_set: RangeSet,
+    pages: HashMap<BlockId, Page>,
+    writes: Vec<(BlockId, DeferredWrite)>,
+}
+
+pub struct Transaction {
     rm: RecoveryManager,
     cm: ConcurrencyManager,
     bm: Arc<BufferManager>,
     fm: Arc<FileManager>,
     tx_num: i32,
     my_buffers: BufferList,
-    tx_lock: RwLockReadGuard<'a, ()>,
+    active: Option<ActiveTx>,
+    // Savepoints and the blocks locked when each of them was created, oldest first.
+    savepoints: Vec<(String, HashSet<LockTarget>)>,
+    prepared: bool,
+    read_only: bool,
+    isolation_level: IsolationLevel,
+    snapshot: Snapshot,
+    versions: Arc<VersionStore>,
+    sireads: Arc<SireadTable>,
+    validator: Arc<OccValidator>,
+    optimistic: Option<WriteBuffer>,
+    // What the transaction wrote under locks, for optimistic transactions to validate against.
+    written: RangeSet,
 }
 
-impl<'a> Transaction<'a> {
+impl Transaction {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         fm: Arc<FileManager>,
         lm: Arc<LogManager>,
         bm: Arc<BufferManager>,
         lock_table: Arc<LockTable>,
+        versions: Arc<VersionStore>,
+        sireads: Arc<SireadTable>,
+        validator: Arc<OccValidator>,
+        options: TxOptions,
     ) -> Self {
-        let tx_num = Self::next_tx_number();
-        let tx_lock = {
+        let start_time = current_time_millis();
+        let (tx_num, snapshot, active) = {
             // Wait if a checkpoint is in progress.
-            let _cp_lock = CHECKPOINT_LOCK.lock().unwrap();
+            // A read-only transaction writes nothing, so it neither waits for a checkpoint nor holds one up.
+            let read_only = options.is_read_only();
+            let _cp_lock = (!read_only).then(|| CHECKPOINT_LOCK.lock().unwrap());
             // Mark that some transactions are in progress.
-            TRANSACTIONS.lock().unwrap().push(tx_num);
-            TRANSACTION_LOCK.read().unwrap()
+            // The number is taken together with the snapshot,
+            // so that every transaction with a smaller number is either finished or in the snapshot.
+            let mut txs = TRANSACTIONS.lock().unwrap();
+            let tx_num = Self::next_tx_number();
+            // A read-only transaction has nothing for recovery to undo.
+            if !read_only {
+                txs.push(tx_num);
+            }
+            let snapshot = Snapshot::new(tx_num, txs.iter().cloned());
+            (tx_num, snapshot, (!read_only).then(ActiveTx::new))
+        };
+        versions.register(snapshot.clone());
+        if options.isolation_level() == IsolationLevel::SerializableSnapshot {
+            sireads.register(snapshot.clone());
+        }
+        // Optimistic reads take no locks. The blocks read are locked without waiting when committing,
+        // and what was read is checked against the writes committed since.
+        let isolation_level = if options.is_optimistic() {
+            validator.begin(tx_num);
+            IsolationLevel::ReadUncommitted
+        } else {
+            options.isolation_level()
+        };
+        let rm = if options.is_read_only() {
+            RecoveryManager::new_read_only(tx_num, lm, bm.clone())
+        } else {
+            RecoveryManager::new(tx_num, lm, bm.clone())
+                .with_async_commit(options.is_async_commit())
         };
-        let rm = RecoveryManager::new(tx_num, lm, bm.clone());
-        let cm = ConcurrencyManager::new(lock_table);
+        let cm = ConcurrencyManager::new(tx_num, start_time, isolation_level, lock_table);
         let my_buffers = BufferList::new(bm.clone());
         Self {
             rm,
@@ -66,16 +148,52 @@ impl<'a> Transaction<'a> {
             fm,
             tx_num,
             my_buffers,
-            tx_lock,
+            active,
+            savepoints: Vec::new(),
+            prepared: false,
+            read_only: options.is_read_only(),
+            isolation_level,
+            snapshot,
+            versions,
+            sireads,
+            validator,
+            optimistic: options.is_optimistic().then(WriteBuffer::default),
+            written: RangeSet::default(),
         }
     }
 
     pub fn commit(mut self) -> Result<()> {
+        let validated = self
+            .sireads
+            .commit(self.tx_num)
+            .and_then(|_| self.write_back());
+        if let Err(e) = validated {
+            self.rollback()?;
+            return Err(e);
+        }
         self.rm.commit()?;
         info!(self.tx_num, "transaction committed");
-        self.cm.release();
-        self.my_buffers.unpin_all();
-        TRANSACTIONS.lock().unwrap().retain(|&x| x != self.tx_num);
+        // Whoever pins a block should unpin it, so pins left at commit are leaks.
+        let leaked = self.pinned_blocks();
+        if !leaked.is_empty() {
+            warn!(
+                self.tx_num,
+                ?leaked,
+                "transaction committed with pinned blocks"
+            );
+        }
+        self.finish();
+        Ok(())
+    }
+
+    // The first phase of two-phase commit.
+    // The transaction keeps its locks and can no longer modify data,
+    // and it is left in doubt by recovery until it is committed or rolled back.
+    pub fn prepare(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.rm.prepare()?;
+        self.prepared = true;
+        info!(self.tx_num, "transaction prepared");
         Ok(())
     }
 
@@ -86,27 +204,31 @@ impl<'a> Transaction<'a> {
         // Wait for existing transactions to finish.
         let start_time = current_time_millis();
         loop {
-            let tx_lock = TRANSACTION_LOCK.try_write();
-            if tx_lock.is_ok() {
+            if *ACTIVE_TXS.lock().unwrap() == 0 {
                 break;
             }
             thread::sleep(Duration::from_millis(10));
             if waiting_too_long(start_time) {
-                return Err("checkpoint timeout".into());
+                return Err(DbError::CheckpointTimeout);
             }
         }
 
         // Flush all modified buffers.
-        let bm = Arc::as_ptr(&bm) as *mut BufferManager;
-        unsafe {
-            (*bm).flush_all(-1)?;
-        }
+        bm.flush_all(-1)?;
 
         // Append a quiescent checkpoint record to the log and flush it to disk.
-        let lm = Arc::as_ptr(&lm) as *mut LogManager;
-        unsafe {
-            let lsn = CheckpointRecord::new().write_to_log(&mut *lm)?;
-            (*lm).flush(lsn)?;
+        // If some transactions are in doubt, a nonquiescent one is used instead
+        // so that they are found by the next recovery.
+        // The log before a quiescent checkpoint is not needed anymore.
+        let in_doubt_txs = RecoveryManager::in_doubt_txs(&lm)?;
+        if in_doubt_txs.is_empty() {
+            let last_tx_num = *NEXT_TX_NUM.lock().unwrap();
+            let lsn = CheckpointRecord::new(last_tx_num).write_to_log(&lm)?;
+            lm.flush(lsn)?;
+            lm.remove_old_segments()?;
+        } else {
+            let lsn = NqCkptRecord::new(in_doubt_txs).write_to_log(&lm)?;
+            lm.flush(lsn)?;
         }
 
         Ok(())
@@ -118,48 +240,95 @@ impl<'a> Transaction<'a> {
         let _cp_lock = CHECKPOINT_LOCK.lock().unwrap();
 
         // Flush all modified buffers.
-        let bm = Arc::as_ptr(&bm) as *mut BufferManager;
-        unsafe {
-            (*bm).flush_all(-1)?;
-        }
+        bm.flush_all(-1)?;
 
         // Write the record <NQCKPT T1 k> into the log.
-        let tx_nums = TRANSACTIONS.lock().unwrap().clone();
-        let lm = Arc::as_ptr(&lm) as *mut LogManager;
-        unsafe {
-            let lsn = NqCkptRecord::new(tx_nums).write_to_log(&mut *lm)?;
-            (*lm).flush(lsn)?;
-        }
+        let mut tx_nums = TRANSACTIONS.lock().unwrap().clone();
+        tx_nums.extend(RecoveryManager::in_doubt_txs(&lm)?);
+        let lsn = NqCkptRecord::new(tx_nums).write_to_log(&lm)?;
+        lm.flush(lsn)?;
 
         Ok(())
     }
 
-    pub fn rollback(mut self) {
-        let rm = &mut self.rm as *mut RecoveryManager;
-        unsafe {
-            (*rm).rollback(&mut self);
+    pub fn rollback(mut self) -> Result<()> {
+        // A prepared transaction can still be rolled back.
+        self.prepared = false;
+        self.cm.start_rollback();
+        let rm = self.rm.clone();
+        let res = rm.rollback(&mut self);
+        // Undoing writes changes what optimistic transactions may have read, like committing them.
+        self.validator.record(std::mem::take(&mut self.written));
+        self.versions.discard(self.tx_num);
+        if res.is_ok() {
+            info!(self.tx_num, "transaction rolled back");
         }
-        info!(self.tx_num, "transaction rolled back");
-        self.cm.release();
-        self.my_buffers.unpin_all();
-        TRANSACTIONS.lock().unwrap().retain(|&x| x != self.tx_num);
+        self.finish();
+        res
+    }
+
+    pub fn savepoint(&mut self, name: &str) -> Result<()> {
+        self.rm.savepoint(name)?;
+        // A new savepoint replaces an existing one with the same name.
+        self.savepoints.retain(|(n, _)| n != name);
+        self.savepoints
+            .push((name.to_string(), self.cm.locked_targets()));
+        Ok(())
+    }
+
+    // Undo the changes made after the savepoint, keeping the transaction alive.
+    // Savepoints created after it are discarded, and locks acquired after it are released.
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        let pos = self
+            .savepoints
+            .iter()
+            .position(|(n, _)| n == name)
+            .ok_or_else(|| DbError::SavepointNotFound(name.to_string()))?;
+        self.savepoints.truncate(pos + 1);
+
+        let rm = self.rm.clone();
+        rm.rollback_to(self, name)?;
+        info!(self.tx_num, name, "transaction rolled back to savepoint");
+        let targets = &self.savepoints[pos].1;
+        self.cm.release_except(targets);
+        Ok(())
+    }
+
+    pub fn recover(mut self) -> Result<()> {
+        let res = self.do_recover();
+        self.finish();
+        res
     }
 
-    pub fn recover(mut self) {
-        let bm = Arc::as_ptr(&self.bm) as *mut BufferManager;
-        unsafe {
-            (*bm).flush_all(self.tx_num).unwrap();
+    fn do_recover(&mut self) -> Result<()> {
+        self.bm.flush_all(self.tx_num)?;
+        let rm = self.rm.clone();
+        let in_doubt_txs = rm.recover(self)?;
+        // In-doubt transactions keep their blocks locked until they are resolved.
+        for (tx_num, blocks) in rm.changed_blocks(&in_doubt_txs)? {
+            self.cm.x_lock_in_doubt(tx_num, &blocks)?;
         }
-        let rm = &mut self.rm as *mut RecoveryManager;
-        unsafe {
-            (*rm).recover(&mut self);
+        Ok(())
+    }
+
+    pub fn in_doubt_txs(lm: Arc<LogManager>) -> Result<Vec<i32>> {
+        RecoveryManager::in_doubt_txs(&lm)
+    }
+
+    // Commit or roll back an in-doubt transaction left by recovery.
+    pub fn resolve(mut self, tx_num: i32, commit: bool) -> Result<()> {
+        self.cm.take_over(tx_num);
+        let rm = self.rm.clone();
+        let res = rm.resolve(&mut self, tx_num, commit);
+        if res.is_ok() {
+            info!(self.tx_num, tx_num, commit, "in-doubt transaction resolved");
         }
-        self.cm.release();
-        self.my_buffers.unpin_all();
-        TRANSACTIONS.lock().unwrap().retain(|&x| x != self.tx_num);
+        self.finish();
+        res
     }
 
     pub fn pin(&mut self, block: &BlockId) -> Result<()> {
+        self.cm.check_wounded()?;
         self.my_buffers.pin(block.clone())
     }
 
@@ -167,17 +336,61 @@ impl<'a> Transaction<'a> {
         self.my_buffers.unpin(block.clone());
     }
 
+    // The blocks the transaction has pinned and not unpinned yet, once for each pin.
+    pub fn pinned_blocks(&self) -> Vec<BlockId> {
+        self.my_buffers.pinned_blocks()
+    }
+
+    // Lock a single record, so that other records in the block stay available to other transactions.
+    // The values in the slot, found from the size of the slots of the file,
+    // can then be read or written without locking the block.
+    pub fn s_lock_record(&mut self, block: &BlockId, slot: i32, slot_size: i32) -> Result<()> {
+        self.cm.set_slot_size(block.filename(), slot_size);
+        self.cm.s_lock_record(block, slot)
+    }
+
+    pub fn x_lock_record(&mut self, block: &BlockId, slot: i32, slot_size: i32) -> Result<()> {
+        self.check_writable()?;
+        if self.optimistic.is_some() {
+            return Ok(());
+        }
+        self.cm.set_slot_size(block.filename(), slot_size);
+        self.cm.x_lock_record(block, slot)
+    }
+
+    // Lock a whole block ahead of writing to it.
+    pub fn x_lock(&mut self, block: &BlockId) -> Result<()> {
+        self.check_writable()?;
+        if self.optimistic.is_some() {
+            return Ok(());
+        }
+        self.cm.x_lock(block)
+    }
+
+    // The getters read the current contents of the block.
+    // Under the snapshot levels they take no lock and ignore versions,
+    // so snapshot reads must go through a record page, which checks the versions.
     pub fn get_int(&mut self, block: &BlockId, offset: i32) -> Result<i32> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_int(offset))
+        self.read(
+            block,
+            offset,
+            |page| page.get_int(offset),
+            |&value| Page::int_len(value),
+        )
     }
     pub fn set_int(&mut self, block: &BlockId, offset: i32, value: i32, log: bool) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::int_len(value)) {
+            page.set_int(offset, value);
+            return self.defer(block, move |tx, block| {
+                tx.set_int(block, offset, value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::int_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_int(buffer, offset, value)?;
+            lsn = self.rm.set_int(&buffer, offset, value)?;
         }
         buffer.contents.set_int(offset, value);
         buffer.set_modified(self.tx_num, lsn);
@@ -185,9 +398,12 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn get_bytes(&mut self, block: &BlockId, offset: i32) -> Result<Vec<u8>> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_bytes(offset))
+        self.read(
+            block,
+            offset,
+            |page| page.get_bytes(offset),
+            |value| Page::bytes_len(value),
+        )
     }
     pub fn set_bytes(
         &mut self,
@@ -196,11 +412,19 @@ impl<'a> Transaction<'a> {
         value: &[u8],
         log: bool,
     ) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::bytes_len(value)) {
+            page.set_bytes(offset, value);
+            let value = value.to_vec();
+            return self.defer(block, move |tx, block| {
+                tx.set_bytes(block, offset, &value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::bytes_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_bytes(buffer, offset, value)?;
+            lsn = self.rm.set_bytes(&buffer, offset, value)?;
         }
         buffer.contents.set_bytes(offset, value);
         buffer.set_modified(self.tx_num, lsn);
@@ -208,9 +432,12 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn get_string(&mut self, block: &BlockId, offset: i32) -> Result<String> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_string(offset))
+        self.read(
+            block,
+            offset,
+            |page| page.get_string(offset),
+            |value| Page::str_len(value),
+        )
     }
     pub fn set_string(
         &mut self,
@@ -219,11 +446,19 @@ impl<'a> Transaction<'a> {
         value: &str,
         log: bool,
     ) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::str_len(value)) {
+            page.set_string(offset, value);
+            let value = value.to_string();
+            return self.defer(block, move |tx, block| {
+                tx.set_string(block, offset, &value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::str_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_string(buffer, offset, value)?;
+            lsn = self.rm.set_string(&buffer, offset, value)?;
         }
         buffer.contents.set_string(offset, value);
         buffer.set_modified(self.tx_num, lsn);
@@ -231,16 +466,26 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn get_bool(&mut self, block: &BlockId, offset: i32) -> Result<bool> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_bool(offset))
+        self.read(
+            block,
+            offset,
+            |page| page.get_bool(offset),
+            |&value| Page::bool_len(value),
+        )
     }
     pub fn set_bool(&mut self, block: &BlockId, offset: i32, value: bool, log: bool) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::bool_len(value)) {
+            page.set_bool(offset, value);
+            return self.defer(block, move |tx, block| {
+                tx.set_bool(block, offset, value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::bool_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_bool(buffer, offset, value)?;
+            lsn = self.rm.set_bool(&buffer, offset, value)?;
         }
         buffer.contents.set_bool(offset, value);
         buffer.set_modified(self.tx_num, lsn);
@@ -248,9 +493,12 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn get_double(&mut self, block: &BlockId, offset: i32) -> Result<f64> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_double(offset))
+        self.read(
+            block,
+            offset,
+            |page| page.get_double(offset),
+            |&value| Page::double_len(value),
+        )
     }
     pub fn set_double(
         &mut self,
@@ -259,11 +507,18 @@ impl<'a> Transaction<'a> {
         value: f64,
         log: bool,
     ) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::double_len(value)) {
+            page.set_double(offset, value);
+            return self.defer(block, move |tx, block| {
+                tx.set_double(block, offset, value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::double_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_double(buffer, offset, value)?;
+            lsn = self.rm.set_double(&buffer, offset, value)?;
         }
         buffer.contents.set_double(offset, value);
         buffer.set_modified(self.tx_num, lsn);
@@ -271,9 +526,7 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn get_date(&mut self, block: &BlockId, offset: i32) -> Result<Option<chrono::NaiveDate>> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_date(offset))
+        self.read(block, offset, |page| page.get_date(offset), Page::date_len)
     }
     pub fn set_date(
         &mut self,
@@ -282,11 +535,19 @@ impl<'a> Transaction<'a> {
         value: &Option<chrono::NaiveDate>,
         log: bool,
     ) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::date_len(value)) {
+            page.set_date(offset, value);
+            let value = *value;
+            return self.defer(block, move |tx, block| {
+                tx.set_date(block, offset, &value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::date_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_date(buffer, offset, value)?;
+            lsn = self.rm.set_date(&buffer, offset, value)?;
         }
         buffer.contents.set_date(offset, value);
         buffer.set_modified(self.tx_num, lsn);
@@ -294,9 +555,7 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn get_time(&mut self, block: &BlockId, offset: i32) -> Result<Option<chrono::NaiveTime>> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_time(offset))
+        self.read(block, offset, |page| page.get_time(offset), Page::time_len)
     }
     pub fn set_time(
         &mut self,
@@ -305,11 +564,19 @@ impl<'a> Transaction<'a> {
         value: &Option<chrono::NaiveTime>,
         log: bool,
     ) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::time_len(value)) {
+            page.set_time(offset, value);
+            let value = *value;
+            return self.defer(block, move |tx, block| {
+                tx.set_time(block, offset, &value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::time_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_time(buffer, offset, value)?;
+            lsn = self.rm.set_time(&buffer, offset, value)?;
         }
         buffer.contents.set_time(offset, value);
         buffer.set_modified(self.tx_num, lsn);
@@ -321,9 +588,12 @@ impl<'a> Transaction<'a> {
         block: &BlockId,
         offset: i32,
     ) -> Result<Option<chrono::DateTime<chrono::FixedOffset>>> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_datetime(offset))
+        self.read(
+            block,
+            offset,
+            |page| page.get_datetime(offset),
+            Page::datetime_len,
+        )
     }
     pub fn set_datetime(
         &mut self,
@@ -332,11 +602,19 @@ impl<'a> Transaction<'a> {
         value: &Option<chrono::DateTime<chrono::FixedOffset>>,
         log: bool,
     ) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::datetime_len(value)) {
+            page.set_datetime(offset, value);
+            let value = *value;
+            return self.defer(block, move |tx, block| {
+                tx.set_datetime(block, offset, &value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::datetime_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_datetime(buffer, offset, value)?;
+            lsn = self.rm.set_datetime(&buffer, offset, value)?;
         }
         buffer.contents.set_datetime(offset, value);
         buffer.set_modified(self.tx_num, lsn);
@@ -344,9 +622,7 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn get_json(&mut self, block: &BlockId, offset: i32) -> Result<Option<serde_json::Value>> {
-        self.cm.s_lock(block)?;
-        let buffer = self.my_buffers.buffer(block);
-        Ok(buffer.contents.get_json(offset))
+        self.read(block, offset, |page| page.get_json(offset), Page::json_len)
     }
     pub fn set_json(
         &mut self,
@@ -355,29 +631,93 @@ impl<'a> Transaction<'a> {
         value: &Option<serde_json::Value>,
         log: bool,
     ) -> Result<()> {
-        self.cm.x_lock(block)?;
-        let buffer = self.my_buffers.buffer_mut(block);
+        self.check_writable()?;
+        if let Some(page) = self.private_page(block, offset, Page::json_len(value)) {
+            page.set_json(offset, value);
+            let value = value.clone();
+            return self.defer(block, move |tx, block| {
+                tx.set_json(block, offset, &value, log)
+            });
+        }
+        self.write_lock(block, offset, Page::json_len(value))?;
+        let mut buffer = self.my_buffers.write(block);
         let mut lsn = -1;
         if log {
-            lsn = self.rm.set_json(buffer, offset, value)?;
+            lsn = self.rm.set_json(&buffer, offset, value)?;
         }
         buffer.contents.set_json(offset, value);
         buffer.set_modified(self.tx_num, lsn);
         Ok(())
     }
 
+    // The size is protected by a lock on a dummy block past the end of the file,
+    // so that blocks appended by other transactions do not show up as phantoms.
     pub fn size(&mut self, filename: &str) -> Result<i32> {
         let dummy = BlockId::new(filename.to_string(), END_OF_FILE);
-        self.cm.x_lock(&dummy)?;
-        let fm = Arc::as_ptr(&self.fm) as *mut FileManager;
-        unsafe { (*fm).length(filename) }
+        if let Some(buffer) = self.optimistic.as_mut() {
+            buffer.read_set.insert_block(&dummy);
+        }
+        let short_locks = self.cm.size_lock(&dummy)?;
+        let size = self.fm.length(filename);
+        self.cm.release_short(short_locks);
+        size
     }
 
     pub fn append(&mut self, filename: &str) -> Result<BlockId> {
+        self.check_writable()?;
         let dummy = BlockId::new(filename.to_string(), END_OF_FILE);
+        // Appending cannot be deferred, so even an optimistic transaction locks the end of the file.
+        if let Some(buffer) = self.optimistic.as_mut() {
+            buffer.write_set.insert_block(&dummy);
+        }
         self.cm.x_lock(&dummy)?;
-        let fm = Arc::as_ptr(&self.fm) as *mut FileManager;
-        unsafe { (*fm).append(filename) }
+        self.fm.append(filename)
+    }
+
+    pub fn tx_num(&self) -> i32 {
+        self.tx_num
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn is_snapshot_isolation(&self) -> bool {
+        matches!(
+            self.isolation_level,
+            IsolationLevel::Snapshot | IsolationLevel::SerializableSnapshot
+        )
+    }
+
+    // Track the reads and writes of record slots under serializable snapshot isolation.
+    pub fn mark_read(&self, block: &BlockId, slot: i32) {
+        self.sireads.read(self.tx_num, block, slot);
+    }
+
+    pub fn mark_write(&self, block: &BlockId, slot: i32) {
+        self.sireads.write(self.tx_num, block, slot);
+    }
+
+    // Keep the current image of a record slot for the snapshots that do not see the update to come.
+    pub fn save_version(&mut self, block: &BlockId, slot: i32, offset: i32, len: i32) {
+        let buffer = self.my_buffers.read(block);
+        let bytes = &buffer.contents.buffer()[offset as usize..(offset + len) as usize];
+        self.versions
+            .push(block, slot, self.tx_num, Page::from(bytes.to_vec()));
+    }
+
+    // The newest older image of a record slot visible to the snapshot.
+    pub fn find_version(&self, block: &BlockId, slot: i32) -> Option<Page> {
+        self.versions.find(block, slot, &self.snapshot)
+    }
+
+    pub fn forget_versions(&self, block: &BlockId, slot: i32) {
+        self.versions.remove(block, slot);
+    }
+
+    // Whether the changes made by `xid` are seen by every running and future transaction.
+    pub fn is_settled(&self, xid: i32) -> bool {
+        self.versions.is_settled(xid)
     }
 
     pub fn block_size(&self) -> i32 {
@@ -388,6 +728,116 @@ impl<'a> Transaction<'a> {
         self.bm.available()
     }
 
+    // Make sure new transactions are numbered after the ones in the log,
+    // so that they are not mistaken for transactions before a restart.
+    pub fn init_tx_number(lm: Arc<LogManager>) -> Result<()> {
+        let max_tx_num = RecoveryManager::max_tx_num(&lm)?;
+        let mut next_tx_num = NEXT_TX_NUM.lock().unwrap();
+        *next_tx_num = (*next_tx_num).max(max_tx_num);
+        Ok(())
+    }
+
+    // Read the value at `offset`, whose length in bytes is given by `len`.
+    fn read<T>(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        f: impl Fn(&Page) -> T,
+        len: impl Fn(&T) -> i32,
+    ) -> Result<T> {
+        if let Some(page) = self.optimistic.as_ref().and_then(|b| b.pages.get(block)) {
+            let value = f(page);
+            let buffer = self.optimistic.as_ref().unwrap();
+            if buffer.write_set.overlaps(block, offset, len(&value)) {
+                return Ok(value);
+            }
+        }
+        let short_locks = self.cm.read_lock(block, offset)?;
+        let value = f(&self.my_buffers.read(block).contents);
+        self.cm.release_short(short_locks);
+        if let Some(buffer) = self.optimistic.as_mut() {
+            buffer.read_set.insert(block, offset, len(&value));
+        }
+        Ok(value)
+    }
+
+    // The private copy of a block to write `len` bytes at `offset`, if the transaction is optimistic.
+    fn private_page(&mut self, block: &BlockId, offset: i32, len: i32) -> Option<&mut Page> {
+        let buffer = self.optimistic.as_mut()?;
+        buffer.write_set.insert(block, offset, len);
+        if !buffer.pages.contains_key(block) {
+            let contents = self.my_buffers.read(block).contents.buffer().to_vec();
+            buffer.pages.insert(block.clone(), Page::from(contents));
+        }
+        buffer.pages.get_mut(block)
+    }
+
+    fn defer(
+        &mut self,
+        block: &BlockId,
+        write: impl FnOnce(&mut Transaction, &BlockId) -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        let buffer = self.optimistic.as_mut().unwrap();
+        buffer.writes.push((block.clone(), Box::new(write)));
+        Ok(())
+    }
+
+    fn write_lock(&mut self, block: &BlockId, offset: i32, len: i32) -> Result<()> {
+        self.cm.write_lock(block, offset)?;
+        self.written.insert(block, offset, len);
+        Ok(())
+    }
+
+    // The validation and write phases of an optimistic transaction.
+    // Other transactions only record what they wrote, before their locks are released.
+    fn write_back(&mut self) -> Result<()> {
+        let Some(buffer) = self.optimistic.take() else {
+            self.validator.record(std::mem::take(&mut self.written));
+            return Ok(());
+        };
+        // Lock the blocks first without waiting, since the write phase must not wait while others validate.
+        // A block read that another transaction has locked may hold changes it has yet to commit.
+        for block in buffer.write_set.blocks() {
+            if !self.cm.try_x_lock(block) {
+                return Err(DbError::ValidationFailure);
+            }
+        }
+        for block in buffer.read_set.blocks() {
+            if !self.cm.try_s_lock(block) {
+                return Err(DbError::ValidationFailure);
+            }
+        }
+        let validator = self.validator.clone();
+        validator.validate(self.tx_num, &buffer.read_set, buffer.write_set, || {
+            for (block, write) in buffer.writes {
+                self.pin(&block)?;
+                write(self, &block)?;
+                self.unpin(&block);
+            }
+            Ok(())
+        })
+    }
+
+    // Release everything the transaction holds once it is over.
+    fn finish(&mut self) {
+        self.cm.release();
+        self.my_buffers.unpin_all();
+        TRANSACTIONS.lock().unwrap().retain(|&x| x != self.tx_num);
+        self.versions.unregister(self.tx_num);
+        self.sireads.finish(self.tx_num);
+        self.validator.finish(self.tx_num);
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(DbError::ReadOnly);
+        }
+        if self.prepared {
+            return Err(DbError::Prepared);
+        }
+        Ok(())
+    }
+
     fn next_tx_number() -> i32 {
         let mut next_tx_num = NEXT_TX_NUM.lock().unwrap();
         *next_tx_num += 1;
@@ -429,7 +879,7 @@ mod tests {
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
             tx.set_int(&block, 0, 456, true).unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -453,7 +903,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -494,7 +944,7 @@ mod tests {
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
             tx.set_bytes(&block, 0, &[4, 5, 6], true).unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -518,7 +968,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -559,7 +1009,7 @@ mod tests {
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
             tx.set_string(&block, 0, "def", true).unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -583,7 +1033,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -624,7 +1074,7 @@ mod tests {
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
             tx.set_bool(&block, 0, false, true).unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -648,7 +1098,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -689,7 +1139,7 @@ mod tests {
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
             tx.set_double(&block, 0, 4.56, true).unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -713,7 +1163,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -765,7 +1215,7 @@ mod tests {
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
             tx.set_date(&block, 0, &date2, true).unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -791,7 +1241,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -843,7 +1293,7 @@ mod tests {
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
             tx.set_time(&block, 0, &time2, true).unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -869,7 +1319,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -918,7 +1368,7 @@ mod tests {
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
             tx.set_datetime(&block, 0, &Some(datetime2), true).unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -949,7 +1399,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -996,7 +1446,7 @@ mod tests {
             tx.pin(&block).unwrap();
             tx.set_json(&block, 0, &Some(serde_json::json!({"key": "value2"})), true)
                 .unwrap();
-            tx.rollback();
+            tx.rollback().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -1025,7 +1475,7 @@ mod tests {
             tx.cm.release();
 
             let tx = db.new_tx();
-            tx.recover();
+            tx.recover().unwrap();
 
             let mut tx = db.new_tx();
             tx.pin(&block).unwrap();
@@ -1036,122 +1486,1240 @@ mod tests {
         }
     }
 
-    mod checkpoint {
-
+    mod savepoint {
         use super::*;
 
         #[test]
-        fn error_if_blocked() {
+        fn rollback_to() {
             let db = SimpleDB::new(
-                "testdata/tx/transaction/checkpoint/error_if_blocked",
+                "testdata/tx/transaction/savepoint/rollback_to",
                 400,
                 8,
                 "templog",
             );
+            let block = BlockId::new("tempfile".to_string(), 0);
 
-            let tx = db.new_tx();
-            let res = Transaction::checkpoint(db.buffer_manager(), db.log_manager());
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            tx.savepoint("sp").unwrap();
+            tx.set_int(&block, 0, 456, true).unwrap();
+            tx.set_int(&block, 4, 456, true).unwrap();
+            tx.rollback_to("sp").unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+            assert_eq!(tx.get_int(&block, 4).unwrap(), 0);
 
-            assert!(res.is_err());
+            tx.set_int(&block, 4, 789, true).unwrap();
+            tx.commit().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+            assert_eq!(tx.get_int(&block, 4).unwrap(), 789);
         }
 
         #[test]
-        fn ok_if_not_blocked() {
+        fn rollback_after_rollback_to() {
             let db = SimpleDB::new(
-                "testdata/tx/transaction/checkpoint/ok_if_not_blocked",
+                "testdata/tx/transaction/savepoint/rollback_after_rollback_to",
                 400,
                 8,
                 "templog",
             );
+            let block = BlockId::new("tempfile".to_string(), 0);
 
-            let tx = db.new_tx();
-            let t = {
-                let db = db.clone();
-                thread::spawn(move || {
-                    let res = Transaction::checkpoint(db.buffer_manager(), db.log_manager());
-                    assert!(res.is_ok());
-                })
-            };
-            drop(tx);
-            t.join().unwrap();
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            tx.commit().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 456, true).unwrap();
+            tx.savepoint("sp").unwrap();
+            tx.set_int(&block, 0, 789, true).unwrap();
+            tx.rollback_to("sp").unwrap();
+            tx.set_int(&block, 0, 999, true).unwrap();
+            tx.rollback().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
         }
 
         #[test]
-        fn new_tx_is_kept_waiting() {
+        fn later_savepoints_are_discarded() {
             let db = SimpleDB::new(
-                "testdata/tx/transaction/checkpoint/new_tx_is_kept_waiting",
+                "testdata/tx/transaction/savepoint/later_savepoints_are_discarded",
                 400,
                 8,
                 "templog",
             );
+            let block = BlockId::new("tempfile".to_string(), 0);
 
-            let tx1 = db.new_tx();
-            let t1 = {
-                let db = db.clone();
-                thread::spawn(move || {
-                    let res = Transaction::checkpoint(db.buffer_manager(), db.log_manager());
-                    assert!(res.is_ok());
-                })
-            };
-            let t2 = {
-                let db = db.clone();
-                thread::spawn(move || {
-                    let tx2 = db.new_tx();
-                })
-            };
-            assert!(!t2.is_finished()); // starting new tx is blocked until checkpoint is finished
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 100, true).unwrap();
+            tx.savepoint("sp1").unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            tx.savepoint("sp2").unwrap();
+            tx.set_int(&block, 0, 456, true).unwrap();
+            tx.rollback_to("sp1").unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 100);
+
+            assert!(tx.rollback_to("sp2").is_err());
+            tx.set_int(&block, 4, 789, true).unwrap();
+            assert!(tx.rollback_to("sp1").is_ok());
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 100);
+            assert_eq!(tx.get_int(&block, 4).unwrap(), 0);
+            assert!(tx.rollback_to("unknown").is_err());
+        }
 
-            drop(tx1);
-            t1.join().unwrap();
-            thread::sleep(std::time::Duration::from_millis(100)); // HACK
-            assert!(t2.is_finished());
+        #[test]
+        fn rollback_to_twice() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/savepoint/rollback_to_twice",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            tx.savepoint("sp").unwrap();
+            tx.set_int(&block, 0, 456, true).unwrap();
+            tx.rollback_to("sp").unwrap();
+            tx.set_int(&block, 4, 789, true).unwrap();
+            tx.rollback_to("sp").unwrap();
+
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+            assert_eq!(tx.get_int(&block, 4).unwrap(), 0);
         }
-    }
 
-    mod nq_ckpt {
-        use super::*;
+        #[test]
+        fn releases_locks_acquired_after_savepoint() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/savepoint/releases_locks_acquired_after_savepoint",
+                400,
+                8,
+                "templog",
+            );
+            let block1 = BlockId::new("tempfile".to_string(), 0);
+            let block2 = BlockId::new("tempfile".to_string(), 1);
+
+            let mut tx1 = db.new_tx();
+            tx1.pin(&block1).unwrap();
+            tx1.pin(&block2).unwrap();
+            tx1.set_int(&block1, 0, 123, true).unwrap();
+            tx1.savepoint("sp").unwrap();
+            tx1.set_int(&block2, 0, 456, true).unwrap();
+            tx1.rollback_to("sp").unwrap();
+
+            let mut tx2 = db.new_tx();
+            tx2.pin(&block1).unwrap();
+            tx2.pin(&block2).unwrap();
+            assert!(tx2.set_int(&block1, 0, 789, true).is_err());
+            assert!(tx2.set_int(&block2, 0, 789, true).is_ok());
+        }
+
+        // Set block 2 in a savepoint rolled back to, then let another transaction commit to it.
+        fn commit_after_release(db: &SimpleDB, block1: &BlockId, block2: &BlockId) -> Transaction {
+            let mut tx1 = db.new_tx();
+            tx1.pin(block1).unwrap();
+            tx1.pin(block2).unwrap();
+            tx1.set_int(block1, 0, 123, true).unwrap();
+            tx1.savepoint("sp").unwrap();
+            tx1.set_int(block2, 0, 456, true).unwrap();
+            tx1.rollback_to("sp").unwrap();
+
+            let mut tx2 = db.new_tx();
+            tx2.pin(block2).unwrap();
+            tx2.set_int(block2, 0, 789, true).unwrap();
+            tx2.commit().unwrap();
+            tx1
+        }
 
         #[test]
-        fn not_blocked() {
+        fn rollback_skips_changes_rolled_back_to() {
             let db = SimpleDB::new(
-                "testdata/tx/transaction/nq_ckpt/not_blocked",
+                "testdata/tx/transaction/savepoint/rollback_skips_changes_rolled_back_to",
                 400,
                 8,
                 "templog",
             );
+            let block1 = BlockId::new("tempfile".to_string(), 0);
+            let block2 = BlockId::new("tempfile".to_string(), 1);
 
-            let tx = db.new_tx();
-            let res = Transaction::nq_ckpt(db.buffer_manager(), db.log_manager());
+            let tx1 = commit_after_release(&db, &block1, &block2);
+            tx1.rollback().unwrap();
 
-            assert!(res.is_ok());
+            let mut tx = db.new_tx();
+            tx.pin(&block1).unwrap();
+            tx.pin(&block2).unwrap();
+            assert_eq!(tx.get_int(&block1, 0).unwrap(), 0);
+            assert_eq!(tx.get_int(&block2, 0).unwrap(), 789);
         }
 
         #[test]
-        fn new_tx_is_kept_waiting() {
+        fn recovery_skips_changes_rolled_back_to() {
             let db = SimpleDB::new(
-                "testdata/tx/transaction/nq_ckpt/new_tx_is_kept_waiting",
+                "testdata/tx/transaction/savepoint/recovery_skips_changes_rolled_back_to",
                 400,
                 8,
                 "templog",
             );
+            let block1 = BlockId::new("tempfile".to_string(), 0);
+            let block2 = BlockId::new("tempfile".to_string(), 1);
 
-            let cp_lock = CHECKPOINT_LOCK.lock().unwrap(); // HACK to make nqckpt start waiting
-            let t1 = {
-                let db = db.clone();
-                thread::spawn(move || {
-                    let res = Transaction::nq_ckpt(db.buffer_manager(), db.log_manager());
-                    assert!(res.is_ok());
-                })
-            };
-            let t2 = thread::spawn(move || {
-                let tx = db.new_tx();
-            });
-            assert!(!t2.is_finished()); // starting new tx is blocked until nqckpt is finished
+            // tx 1 crashes
+            let mut tx1 = commit_after_release(&db, &block1, &block2);
+            tx1.unpin(&block1);
+            tx1.unpin(&block2);
+            tx1.cm.release();
+            db.new_tx().recover().unwrap();
 
-            drop(cp_lock); // start nqckpt
-            t1.join().unwrap();
+            let mut tx = db.new_tx();
+            tx.pin(&block1).unwrap();
+            tx.pin(&block2).unwrap();
+            assert_eq!(tx.get_int(&block1, 0).unwrap(), 0);
+            assert_eq!(tx.get_int(&block2, 0).unwrap(), 789);
+        }
+    }
+
+    mod prepare {
+        use super::*;
+
+        #[test]
+        fn rejects_writes() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/prepare/rejects_writes",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            tx.prepare().unwrap();
+
+            assert!(tx.set_int(&block, 0, 456, true).is_err());
+            assert!(tx.append("tempfile").is_err());
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+            tx.commit().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+        }
+
+        #[test]
+        fn rollback() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/prepare/rollback",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            tx.commit().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 456, true).unwrap();
+            tx.prepare().unwrap();
+            tx.rollback().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+        }
+
+        // Prepare a transaction that changes 123 to 456, then crash and recover.
+        fn recover_in_doubt(db: &SimpleDB, block: &BlockId) -> i32 {
+            let mut tx = db.new_tx();
+            tx.pin(block).unwrap();
+            tx.set_int(block, 0, 123, true).unwrap();
+            tx.commit().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(block).unwrap();
+            tx.set_int(block, 0, 456, true).unwrap();
+            tx.prepare().unwrap();
+            tx.unpin(block);
+            tx.cm.release();
+            let in_doubt_tx_num = tx.tx_num;
+
+            let tx = db.new_tx();
+            tx.recover().unwrap();
+
+            in_doubt_tx_num
+        }
+
+        #[test]
+        fn recover_leaves_in_doubt() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/prepare/recover_leaves_in_doubt",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let tx_num = recover_in_doubt(&db, &block);
+
+            // its block stays locked
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert!(tx.get_int(&block, 0).is_err());
+            tx.rollback().unwrap();
+            assert_eq!(
+                Transaction::in_doubt_txs(db.log_manager()).unwrap(),
+                vec![tx_num]
+            );
+
+            // still in doubt after another recovery and checkpoint
+            db.new_tx().recover().unwrap();
+            Transaction::nq_ckpt(db.buffer_manager(), db.log_manager()).unwrap();
+            assert_eq!(
+                Transaction::in_doubt_txs(db.log_manager()).unwrap(),
+                vec![tx_num]
+            );
+        }
+
+        #[test]
+        fn resolve_commit() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/prepare/resolve_commit",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let tx_num = recover_in_doubt(&db, &block);
+            db.new_tx().resolve(tx_num, true).unwrap();
+
+            assert!(Transaction::in_doubt_txs(db.log_manager())
+                .unwrap()
+                .is_empty());
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 456);
+        }
+
+        #[test]
+        fn resolve_rollback() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/prepare/resolve_rollback",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let tx_num = recover_in_doubt(&db, &block);
+            db.new_tx().resolve(tx_num, false).unwrap();
+
+            assert!(Transaction::in_doubt_txs(db.log_manager())
+                .unwrap()
+                .is_empty());
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+        }
+
+        #[test]
+        fn resolve_unknown() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/prepare/resolve_unknown",
+                400,
+                8,
+                "templog",
+            );
+
+            let tx_num = db.new_tx().tx_num;
+            assert!(db.new_tx().resolve(tx_num, true).is_err());
+        }
+    }
+
+    mod checkpoint {
+
+        use super::*;
+
+        #[test]
+        fn error_if_blocked() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/checkpoint/error_if_blocked",
+                400,
+                8,
+                "templog",
+            );
+
+            let tx = db.new_tx();
+            let res = Transaction::checkpoint(db.buffer_manager(), db.log_manager());
+
+            assert!(res.is_err());
+        }
+
+        #[test]
+        fn ok_if_not_blocked() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/checkpoint/ok_if_not_blocked",
+                400,
+                8,
+                "templog",
+            );
+
+            let tx = db.new_tx();
+            let t = {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let res = Transaction::checkpoint(db.buffer_manager(), db.log_manager());
+                    assert!(res.is_ok());
+                })
+            };
+            drop(tx);
+            t.join().unwrap();
+        }
+
+        #[test]
+        fn read_only_tx_is_not_waited_for() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/checkpoint/read_only_tx_is_not_waited_for",
+                400,
+                8,
+                "templog",
+            );
+
+            let tx = db.new_read_only_tx();
+            let res = Transaction::checkpoint(db.buffer_manager(), db.log_manager());
+            assert!(res.is_ok());
+            drop(tx);
+        }
+
+        #[test]
+        fn new_tx_is_kept_waiting() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/checkpoint/new_tx_is_kept_waiting",
+                400,
+                8,
+                "templog",
+            );
+
+            let tx1 = db.new_tx();
+            let t1 = {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let res = Transaction::checkpoint(db.buffer_manager(), db.log_manager());
+                    assert!(res.is_ok());
+                })
+            };
+            let t2 = {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let tx2 = db.new_tx();
+                })
+            };
+            assert!(!t2.is_finished()); // starting new tx is blocked until checkpoint is finished
+
+            drop(tx1);
+            t1.join().unwrap();
             thread::sleep(std::time::Duration::from_millis(100)); // HACK
             assert!(t2.is_finished());
         }
     }
+
+    mod log_segments {
+        use super::*;
+
+        #[test]
+        fn recovery_removes_old_segments() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/log_segments/recovery_removes_old_segments",
+                400,
+                8,
+                "templog",
+            );
+            let lm = db.log_manager();
+            lm.set_segment_blocks(1);
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx_num = 0;
+            for _ in 0..20 {
+                let mut tx = db.new_tx();
+                tx_num = tx.tx_num();
+                tx.pin(&block).unwrap();
+                tx.set_string(&block, 0, "a long enough value", true)
+                    .unwrap();
+                tx.commit().unwrap();
+            }
+            assert!(lm.segment_range().count() > 1);
+
+            db.new_tx().recover().unwrap();
+            assert_eq!(lm.segment_range().count(), 1);
+            // the numbers of the transactions in the removed segments are not reused
+            assert!(RecoveryManager::max_tx_num(&lm).unwrap() > tx_num);
+        }
+    }
+
+    mod corrupt_log {
+        use super::*;
+
+        #[test]
+        fn recovery_undoes_past_torn_tail() {
+            let dir = "testdata/tx/transaction/corrupt_log/recovery_undoes_past_torn_tail";
+            let db = SimpleDB::new(dir, 400, 8, "simpledb.log");
+            let block = BlockId::new("data.tbl".to_string(), 0);
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 1, true).unwrap();
+            tx.commit().unwrap();
+
+            // an uncommitted change reaches the disk before the crash
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 2, true).unwrap();
+            db.buffer_manager().flush_all(tx.tx_num).unwrap();
+            tx.unpin(&block);
+            tx.cm.release();
+
+            // and the newest record is torn
+            let lm = db.log_manager();
+            lm.flush(lm.append(b"torn").unwrap()).unwrap();
+            let path = format!("{}/simpledb.log.0", dir);
+            let mut bytes = std::fs::read(&path).unwrap();
+            let block_start = bytes.len() - 400;
+            let boundary =
+                i32::from_be_bytes(bytes[block_start..block_start + 4].try_into().unwrap());
+            bytes[block_start + boundary as usize + 12] ^= 0xff;
+            std::fs::write(&path, bytes).unwrap();
+            drop(tx);
+            drop(lm);
+            drop(db);
+
+            let db = SimpleDB::new(dir, 400, 8, "simpledb.log");
+            db.new_tx().recover().unwrap();
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 1);
+            tx.commit().unwrap();
+        }
+
+        #[test]
+        fn recovery_fails_at_corrupt_block() {
+            let dir = "testdata/tx/transaction/corrupt_log/recovery_fails_at_corrupt_block";
+            let db = SimpleDB::new(dir, 400, 8, "simpledb.log");
+            let block = BlockId::new("data.tbl".to_string(), 0);
+            let set = |offset: i32, value: i32| {
+                let mut tx = db.new_tx();
+                tx.pin(&block).unwrap();
+                tx.set_int(&block, offset, value, true).unwrap();
+                tx.commit().unwrap();
+            };
+            set(0, 42);
+            let path = format!("{}/simpledb.log.0", dir);
+            // the block holding the commit record
+            let commit_block = std::fs::metadata(&path).unwrap().len() as usize / 400 - 1;
+            for value in 0..6 {
+                set(4, value);
+            }
+            assert!(std::fs::metadata(&path).unwrap().len() as usize / 400 > commit_block + 1);
+            drop(db);
+
+            let db = SimpleDB::new(dir, 400, 8, "simpledb.log");
+            let mut bytes = std::fs::read(&path).unwrap();
+            let block_start = commit_block * 400;
+            let boundary =
+                i32::from_be_bytes(bytes[block_start..block_start + 4].try_into().unwrap());
+            bytes[block_start + boundary as usize + 12] ^= 0xff;
+            std::fs::write(&path, bytes).unwrap();
+
+            // the committed change is not undone
+            let err = db.new_tx().recover().err().unwrap();
+            assert!(matches!(err, DbError::CorruptLog(_)));
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 42);
+            tx.commit().unwrap();
+            drop(db);
+
+            let err = SimpleDB::open(dir, 400, 8, "simpledb.log").err().unwrap();
+            assert!(matches!(err, DbError::CorruptLog(_)));
+        }
+    }
+
+    mod nq_ckpt {
+        use super::*;
+
+        #[test]
+        fn not_blocked() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/nq_ckpt/not_blocked",
+                400,
+                8,
+                "templog",
+            );
+
+            let tx = db.new_tx();
+            let res = Transaction::nq_ckpt(db.buffer_manager(), db.log_manager());
+
+            assert!(res.is_ok());
+        }
+
+        #[test]
+        fn new_tx_is_kept_waiting() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/nq_ckpt/new_tx_is_kept_waiting",
+                400,
+                8,
+                "templog",
+            );
+
+            let cp_lock = CHECKPOINT_LOCK.lock().unwrap(); // HACK to make nqckpt start waiting
+            let t1 = {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let res = Transaction::nq_ckpt(db.buffer_manager(), db.log_manager());
+                    assert!(res.is_ok());
+                })
+            };
+            let t2 = thread::spawn(move || {
+                let tx = db.new_tx();
+            });
+            assert!(!t2.is_finished()); // starting new tx is blocked until nqckpt is finished
+
+            drop(cp_lock); // start nqckpt
+            t1.join().unwrap();
+            thread::sleep(std::time::Duration::from_millis(100)); // HACK
+            assert!(t2.is_finished());
+        }
+    }
+
+    mod wound_wait {
+        use crate::tx::concurrency::lock_table::DeadlockPolicy;
+
+        use super::*;
+
+        #[test]
+        fn wounded_tx_is_rolled_back() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/wound_wait/wounded_tx_is_rolled_back",
+                400,
+                8,
+                "templog",
+            )
+            .with_lock_table(LockTable::new().with_policy(DeadlockPolicy::WoundWait));
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx1 = db.new_tx();
+            let mut tx2 = db.new_tx();
+            tx2.pin(&block).unwrap();
+            tx2.set_int(&block, 0, 123, true).unwrap();
+
+            // The older transaction wounds the younger one while waiting for its lock.
+            tx1.pin(&block).unwrap();
+            assert!(tx1.get_int(&block, 0).is_err());
+
+            let block2 = BlockId::new("tempfile".to_string(), 1);
+            assert_eq!(
+                tx2.pin(&block2).unwrap_err().to_string(),
+                "transaction wounded"
+            );
+            assert_eq!(
+                tx2.get_int(&block, 0).unwrap_err().to_string(),
+                "transaction wounded"
+            );
+            tx2.rollback().unwrap();
+
+            assert_eq!(tx1.get_int(&block, 0).unwrap(), 0);
+        }
+    }
+
+    mod record_lock {
+        use super::*;
+
+        #[test]
+        fn writers_share_block() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/record_lock/writers_share_block",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx1 = db.new_tx();
+            let mut tx2 = db.new_tx();
+            tx1.pin(&block).unwrap();
+            tx2.pin(&block).unwrap();
+            // slots of 40 bytes
+            tx1.x_lock_record(&block, 0, 40).unwrap();
+            tx1.set_int(&block, 0, 123, true).unwrap();
+            tx2.x_lock_record(&block, 1, 40).unwrap();
+            tx2.set_int(&block, 40, 456, true).unwrap();
+            assert!(tx2.s_lock_record(&block, 0, 40).is_err());
+            tx1.commit().unwrap();
+            tx2.rollback().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+            assert_eq!(tx.get_int(&block, 40).unwrap(), 0);
+        }
+
+        #[test]
+        fn record_lock_covers_only_its_slot() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/record_lock/record_lock_covers_only_its_slot",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx1 = db.new_tx();
+            let mut tx2 = db.new_tx();
+            tx1.pin(&block).unwrap();
+            tx2.pin(&block).unwrap();
+            tx1.x_lock_record(&block, 0, 40).unwrap();
+            tx1.set_int(&block, 0, 123, true).unwrap();
+            tx2.x_lock_record(&block, 1, 40).unwrap();
+            // the values outside the locked slot need the block
+            assert!(tx2.get_int(&block, 0).is_err());
+            assert!(tx2.set_int(&block, 0, 456, true).is_err());
+            assert!(tx1.set_int(&block, 40, 789, true).is_err());
+            tx2.rollback().unwrap();
+
+            assert!(tx1.set_int(&block, 40, 789, true).is_ok());
+            tx1.commit().unwrap();
+        }
+    }
+
+    mod end_of_file {
+        use super::*;
+
+        #[test]
+        fn readers_share_size() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/end_of_file/readers_share_size",
+                400,
+                8,
+                "templog",
+            );
+
+            let mut tx1 = db.new_tx();
+            let mut tx2 = db.new_tx();
+            let mut tx3 = db.new_tx();
+            assert_eq!(tx1.size("tempfile").unwrap(), 0);
+            assert_eq!(tx2.size("tempfile").unwrap(), 0);
+            assert!(tx3.append("tempfile").is_err());
+
+            tx1.commit().unwrap();
+            tx2.commit().unwrap();
+            assert!(tx3.append("tempfile").is_ok());
+        }
+
+        #[test]
+        fn append_blocks_size() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/end_of_file/append_blocks_size",
+                400,
+                8,
+                "templog",
+            );
+
+            let mut tx1 = db.new_tx();
+            let mut tx2 = db.new_tx();
+            tx1.append("tempfile").unwrap();
+            assert!(tx2.size("tempfile").is_err());
+
+            tx1.commit().unwrap();
+        }
+    }
+
+    mod isolation {
+        use super::*;
+
+        fn options(isolation_level: IsolationLevel) -> TxOptions {
+            TxOptions::new().with_isolation_level(isolation_level)
+        }
+
+        #[test]
+        fn read_uncommitted() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/isolation/read_uncommitted",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut writer = db.new_tx();
+            writer.pin(&block).unwrap();
+            writer.set_int(&block, 0, 123, true).unwrap();
+
+            let mut reader = db.new_tx_with(options(IsolationLevel::ReadUncommitted));
+            reader.pin(&block).unwrap();
+            assert_eq!(reader.get_int(&block, 0).unwrap(), 123); // dirty read
+            assert_eq!(reader.size("tempfile").unwrap(), 0);
+        }
+
+        #[test]
+        fn read_committed() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/isolation/read_committed",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut writer = db.new_tx();
+            writer.pin(&block).unwrap();
+            writer.set_int(&block, 0, 123, true).unwrap();
+
+            let mut reader = db.new_tx_with(options(IsolationLevel::ReadCommitted));
+            reader.pin(&block).unwrap();
+            assert!(reader.get_int(&block, 0).is_err());
+
+            writer.commit().unwrap();
+            assert_eq!(reader.get_int(&block, 0).unwrap(), 123);
+
+            // the read lock is not kept
+            let mut writer = db.new_tx();
+            writer.pin(&block).unwrap();
+            assert!(writer.set_int(&block, 0, 456, true).is_ok());
+        }
+
+        #[test]
+        fn repeatable_read() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/isolation/repeatable_read",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut reader = db.new_tx_with(options(IsolationLevel::RepeatableRead));
+            reader.pin(&block).unwrap();
+            reader.get_int(&block, 0).unwrap();
+            reader.size("tempfile").unwrap();
+
+            let mut writer = db.new_tx();
+            writer.pin(&block).unwrap();
+            assert!(writer.set_int(&block, 0, 123, true).is_err());
+            assert!(writer.append("tempfile").is_ok()); // phantoms are allowed
+        }
+
+        #[test]
+        fn serializable() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/isolation/serializable",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut reader = db.new_tx_with(options(IsolationLevel::Serializable));
+            reader.pin(&block).unwrap();
+            reader.get_int(&block, 0).unwrap();
+            reader.size("tempfile").unwrap();
+
+            let mut writer = db.new_tx();
+            writer.pin(&block).unwrap();
+            assert!(writer.set_int(&block, 0, 123, true).is_err());
+            assert!(writer.append("tempfile").is_err());
+        }
+    }
+
+    mod optimistic {
+        use super::*;
+
+        fn optimistic_tx(db: &SimpleDB) -> Transaction {
+            db.new_tx_with(TxOptions::new().with_optimistic(true))
+        }
+
+        #[test]
+        fn writes_are_private_until_commit() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/optimistic/writes_are_private_until_commit",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx = optimistic_tx(&db);
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            tx.set_string(&block, 4, "abc", true).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 123);
+            assert_eq!(tx.get_string(&block, 4).unwrap(), "abc");
+
+            // no lock is held before commit
+            let mut other = db.new_tx();
+            other.pin(&block).unwrap();
+            assert_eq!(other.get_int(&block, 0).unwrap(), 0);
+            other.commit().unwrap();
+
+            tx.commit().unwrap();
+            let mut other = db.new_tx();
+            other.pin(&block).unwrap();
+            assert_eq!(other.get_int(&block, 0).unwrap(), 123);
+            assert_eq!(other.get_string(&block, 4).unwrap(), "abc");
+        }
+
+        #[test]
+        fn conflict_fails_validation() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/optimistic/conflict_fails_validation",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx1 = optimistic_tx(&db);
+            let mut tx2 = optimistic_tx(&db);
+            for tx in [&mut tx1, &mut tx2] {
+                tx.pin(&block).unwrap();
+                let value = tx.get_int(&block, 0).unwrap();
+                tx.set_int(&block, 0, value + 1, true).unwrap();
+            }
+            tx1.commit().unwrap();
+            let err = tx2.commit().unwrap_err();
+            assert!(matches!(err, DbError::ValidationFailure));
+            assert!(err.is_retryable());
+
+            let mut tx = optimistic_tx(&db);
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 1);
+        }
+
+        #[test]
+        fn disjoint_writes_commit() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/optimistic/disjoint_writes_commit",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx1 = optimistic_tx(&db);
+            let mut tx2 = optimistic_tx(&db);
+            for (tx, offset) in [(&mut tx1, 0), (&mut tx2, 4)] {
+                tx.pin(&block).unwrap();
+                let value = tx.get_int(&block, offset).unwrap();
+                tx.set_int(&block, offset, value + 1, true).unwrap();
+            }
+            tx1.commit().unwrap();
+            tx2.commit().unwrap();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 1);
+            assert_eq!(tx.get_int(&block, 4).unwrap(), 1);
+        }
+
+        #[test]
+        fn lock_based_write_fails_validation() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/optimistic/lock_based_write_fails_validation",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx1 = optimistic_tx(&db);
+            tx1.pin(&block).unwrap();
+            let value = tx1.get_int(&block, 8).unwrap();
+
+            // a string written under locks ends over the int read
+            let mut tx2 = db.new_tx();
+            tx2.pin(&block).unwrap();
+            tx2.set_string(&block, 0, "abcdef", true).unwrap();
+            tx2.commit().unwrap();
+
+            tx1.set_int(&block, 100, value + 1, true).unwrap();
+            let err = tx1.commit().unwrap_err();
+            assert!(matches!(err, DbError::ValidationFailure));
+        }
+
+        #[test]
+        fn reads_do_not_wait_for_writers() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/optimistic/reads_do_not_wait_for_writers",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            // a lock wait would end in a lock timeout
+            let mut tx1 = db.new_tx();
+            tx1.pin(&block).unwrap();
+            tx1.set_int(&block, 0, 5, true).unwrap();
+
+            let mut tx2 = optimistic_tx(&db);
+            tx2.pin(&block).unwrap();
+            assert_eq!(tx2.get_int(&block, 0).unwrap(), 5);
+            // the value read is not committed yet
+            let err = tx2.commit().unwrap_err();
+            assert!(matches!(err, DbError::ValidationFailure));
+            tx1.commit().unwrap();
+        }
+
+        #[test]
+        fn commit_does_not_wait_for_locks() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/optimistic/commit_does_not_wait_for_locks",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx1 = optimistic_tx(&db);
+            tx1.pin(&block).unwrap();
+            tx1.set_int(&block, 0, 1, true).unwrap();
+
+            let mut tx2 = db.new_tx();
+            tx2.pin(&block).unwrap();
+            tx2.set_int(&block, 4, 2, true).unwrap();
+
+            let err = tx1.commit().unwrap_err();
+            assert!(matches!(err, DbError::ValidationFailure));
+            tx2.commit().unwrap();
+        }
+
+        #[test]
+        fn rolled_back_write_fails_validation() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/optimistic/rolled_back_write_fails_validation",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx1 = db.new_tx();
+            tx1.pin(&block).unwrap();
+            tx1.set_int(&block, 0, 5, true).unwrap();
+
+            let mut tx2 = optimistic_tx(&db);
+            tx2.pin(&block).unwrap();
+            let value = tx2.get_int(&block, 0).unwrap();
+            tx1.rollback().unwrap();
+
+            tx2.set_int(&block, 4, value, true).unwrap();
+            let err = tx2.commit().unwrap_err();
+            assert!(matches!(err, DbError::ValidationFailure));
+        }
+    }
+
+    mod read_only {
+        use super::*;
+
+        fn log_len(db: &SimpleDB) -> usize {
+            db.log_manager().iter().unwrap().count()
+        }
+
+        #[test]
+        fn rejects_writes() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/read_only/rejects_writes",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx = db.new_read_only_tx();
+            tx.pin(&block).unwrap();
+            let err = tx.set_int(&block, 0, 123, true).unwrap_err();
+            assert!(matches!(err, DbError::ReadOnly));
+            assert!(!err.is_retryable());
+            assert!(tx.append("tempfile").is_err());
+            assert!(tx.prepare().is_err());
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 0);
+        }
+
+        #[test]
+        fn logs_nothing() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/read_only/logs_nothing",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+            let len = log_len(&db);
+
+            let mut tx = db.new_read_only_tx();
+            tx.pin(&block).unwrap();
+            tx.get_int(&block, 0).unwrap();
+            tx.commit().unwrap();
+            db.new_read_only_tx().rollback().unwrap();
+
+            assert_eq!(log_len(&db), len);
+        }
+    }
+
+    mod async_commit {
+        use super::*;
+
+        #[test]
+        fn commit_record_is_flushed_later() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/async_commit/commit_record_is_flushed_later",
+                400,
+                8,
+                "templog",
+            )
+            .with_async_commit(true);
+            let lm = db.log_manager();
+            // long enough for the checks right after the commit
+            lm.set_async_flush_interval(500);
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            let flush_count = lm.flush_count();
+            tx.commit().unwrap();
+            let lsn = lm.latest_lsn();
+            assert!(lm.last_saved_lsn() < lsn);
+
+            // written by the background flusher alone, after the page forced by the commit
+            lm.wait_until_saved(lsn);
+            assert!(lm.last_saved_lsn() >= lsn);
+            assert_eq!(lm.flush_count(), flush_count + 2);
+        }
+
+        #[test]
+        fn sync_commit_by_default() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/async_commit/sync_commit_by_default",
+                400,
+                8,
+                "templog",
+            );
+            let lm = db.log_manager();
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.set_int(&block, 0, 123, true).unwrap();
+            tx.commit().unwrap();
+            assert_eq!(lm.last_saved_lsn(), lm.latest_lsn());
+        }
+    }
+
+    mod pins {
+        use super::*;
+
+        #[test]
+        fn commit_releases_leaked_pins() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/pins/commit_releases_leaked_pins",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+            let bm = db.buffer_manager();
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            tx.pin(&block).unwrap();
+            tx.unpin(&block);
+            assert_eq!(tx.pinned_blocks(), vec![block.clone()]);
+            assert_eq!(bm.available(), 7);
+
+            tx.commit().unwrap();
+            assert_eq!(bm.available(), 8);
+        }
+    }
+
+    mod threads {
+        use crate::{buffer::buffer_manager::BufferManager, log::log_manager::LogManager};
+
+        use super::*;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>() {}
+
+        #[test]
+        fn shared_state_is_send_and_sync() {
+            assert_send_sync::<FileManager>();
+            assert_send_sync::<LogManager>();
+            assert_send_sync::<BufferManager>();
+            assert_send_sync::<LockTable>();
+            assert_send_sync::<SimpleDB>();
+            assert_send::<Transaction>();
+        }
+
+        // Increment the int at `offset` in a transaction of its own, retrying when it is aborted.
+        fn increment(db: &SimpleDB, block: &BlockId, offset: i32) {
+            loop {
+                let mut tx = db.new_tx();
+                let res = tx.pin(block).and_then(|_| {
+                    let value = tx.get_int(block, offset)?;
+                    tx.set_int(block, offset, value + 1, true)
+                });
+                match res {
+                    Ok(()) => return tx.commit().unwrap(),
+                    Err(e) if e.is_retryable() => tx.rollback().unwrap(),
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        }
+
+        #[test]
+        fn concurrent_increments() {
+            let db = SimpleDB::new(
+                "testdata/tx/transaction/threads/concurrent_increments",
+                400,
+                8,
+                "templog",
+            );
+            let block = BlockId::new("tempfile".to_string(), 0);
+
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let db = db.clone();
+                    let block = block.clone();
+                    thread::spawn(move || {
+                        for _ in 0..25 {
+                            // one counter shared by all threads, and one of its own
+                            increment(&db, &block, 0);
+                            increment(&db, &block, 4 * (i + 1));
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+
+            let mut tx = db.new_tx();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 0).unwrap(), 100);
+            for i in 0..4 {
+                assert_eq!(tx.get_int(&block, 4 * (i + 1)).unwrap(), 25);
+            }
+        }
+    }
 }
@@ -0,0 +1,88 @@
+#![allow(dead_code)]
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IsolationLevel {
+    // Reads take no locks and may see uncommitted changes.
+    ReadUncommitted,
+    // Reads hold their locks only while reading.
+    ReadCommitted,
+    // Reads hold their locks until the end, but new blocks appended to a file may show up.
+    RepeatableRead,
+    Serializable,
+    // Reads take no locks and see the committed data as of the start of the transaction.
+    // Only records read through a record page are versioned; `Transaction::get_*` are not supported.
+    // Updating a record changed by a concurrent transaction fails.
+    Snapshot,
+    // Snapshot reads, plus tracking of the reads and writes of concurrent transactions.
+    // Committing fails if the transaction may break serializability.
+    SerializableSnapshot,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TxOptions {
+    isolation_level: IsolationLevel,
+    optimistic: bool,
+    read_only: bool,
+    async_commit: bool,
+}
+
+impl TxOptions {
+    pub fn new() -> Self {
+        Self {
+            isolation_level: IsolationLevel::Serializable,
+            optimistic: false,
+            read_only: false,
+            async_commit: false,
+        }
+    }
+
+    pub fn with_isolation_level(mut self, isolation_level: IsolationLevel) -> Self {
+        self.isolation_level = isolation_level;
+        self
+    }
+
+    // Run without locks, checking at commit that nothing read was changed by
+    // an optimistic transaction that committed in the meantime.
+    // The isolation level is then ignored.
+    pub fn with_optimistic(mut self, optimistic: bool) -> Self {
+        self.optimistic = optimistic;
+        self
+    }
+
+    // Reject writes, and log nothing.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // Return from commit once the commit record is in the log page, before it is on disk.
+    // The record is flushed in the background shortly after, and is lost if the system crashes before.
+    // Only the wait for the commit record is saved: the changed pages are still written before it,
+    // along with the log records they need, since recovery only undoes.
+    pub fn with_async_commit(mut self, async_commit: bool) -> Self {
+        self.async_commit = async_commit;
+        self
+    }
+
+    pub fn isolation_level(&self) -> IsolationLevel {
+        self.isolation_level
+    }
+
+    pub fn is_optimistic(&self) -> bool {
+        self.optimistic
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn is_async_commit(&self) -> bool {
+        self.async_commit
+    }
+}
+
+impl Default for TxOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,151 @@
+#![allow(dead_code)]
+
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{
+    file::{block_id::BlockId, page::Page},
+    record::layout::XMIN_OFFSET,
+};
+
+use super::snapshot::Snapshot;
+
+// An image of a record slot, as it was before `replaced_by` updated it.
+struct Version {
+    replaced_by: i32,
+    image: Page,
+}
+
+// Older versions of records still needed by the snapshots of running transactions.
+pub struct VersionStore {
+    snapshots: Mutex<HashMap<i32, Snapshot>>,
+    // Versions of each slot, oldest first.
+    versions: Mutex<HashMap<(BlockId, i32), Vec<Version>>>,
+}
+
+impl Default for VersionStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VersionStore {
+    pub fn new() -> Self {
+        Self {
+            snapshots: Mutex::new(HashMap::new()),
+            versions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self, snapshot: Snapshot) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.insert(snapshot.tx_num(), snapshot);
+    }
+
+    // Forget the snapshot of a finished transaction and the versions no snapshot needs anymore.
+    pub fn unregister(&self, tx_num: i32) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.remove(&tx_num);
+        let mut versions = self.versions.lock().unwrap();
+        for chain in versions.values_mut() {
+            chain.retain(|v| !Self::settled(&snapshots, v.replaced_by));
+        }
+        versions.retain(|_, chain| !chain.is_empty());
+    }
+
+    pub fn push(&self, block: &BlockId, slot: i32, replaced_by: i32, image: Page) {
+        let mut versions = self.versions.lock().unwrap();
+        let chain = versions.entry((block.clone(), slot)).or_default();
+        chain.push(Version { replaced_by, image });
+    }
+
+    // The newest version created by a transaction the snapshot sees.
+    pub fn find(&self, block: &BlockId, slot: i32, snapshot: &Snapshot) -> Option<Page> {
+        let versions = self.versions.lock().unwrap();
+        let chain = versions.get(&(block.clone(), slot))?;
+        chain
+            .iter()
+            .rev()
+            .find(|v| snapshot.is_visible(v.image.get_int(XMIN_OFFSET)))
+            .map(|v| Page::from(v.image.buffer().to_vec()))
+    }
+
+    // Drop the versions replaced by a transaction that rolled back.
+    pub fn discard(&self, tx_num: i32) {
+        let mut versions = self.versions.lock().unwrap();
+        for chain in versions.values_mut() {
+            chain.retain(|v| v.replaced_by != tx_num);
+        }
+        versions.retain(|_, chain| !chain.is_empty());
+    }
+
+    // Drop the versions of a slot that is reused for a new record.
+    pub fn remove(&self, block: &BlockId, slot: i32) {
+        let mut versions = self.versions.lock().unwrap();
+        versions.remove(&(block.clone(), slot));
+    }
+
+    // Whether every running and future transaction sees the changes made by `xid`.
+    pub fn is_settled(&self, xid: i32) -> bool {
+        let snapshots = self.snapshots.lock().unwrap();
+        Self::settled(&snapshots, xid)
+    }
+
+    fn settled(snapshots: &HashMap<i32, Snapshot>, xid: i32) -> bool {
+        !snapshots.contains_key(&xid) && snapshots.values().all(|s| s.is_visible(xid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(xmin: i32) -> Page {
+        let mut page = Page::new(12);
+        page.set_int(XMIN_OFFSET, xmin);
+        page
+    }
+
+    #[test]
+    fn find() {
+        let store = VersionStore::new();
+        let block = BlockId::new("file".to_string(), 0);
+        store.push(&block, 0, 2, image(1));
+        store.push(&block, 0, 3, image(2));
+
+        let find = |snapshot: &Snapshot| {
+            store
+                .find(&block, 0, snapshot)
+                .map(|p| p.get_int(XMIN_OFFSET))
+        };
+        assert_eq!(find(&Snapshot::new(4, vec![])), Some(2));
+        assert_eq!(find(&Snapshot::new(4, vec![2])), Some(1));
+        assert_eq!(find(&Snapshot::new(3, vec![1, 2])), None);
+    }
+
+    #[test]
+    fn unregister_prunes_settled_versions() {
+        let store = VersionStore::new();
+        let block = BlockId::new("file".to_string(), 0);
+        store.register(Snapshot::new(1, vec![]));
+        store.register(Snapshot::new(2, vec![1]));
+        store.push(&block, 0, 2, image(0));
+
+        store.unregister(2);
+        // tx 1 does not see the update by tx 2
+        assert!(store.find(&block, 0, &Snapshot::new(1, vec![])).is_some());
+
+        store.unregister(1);
+        assert!(store.find(&block, 0, &Snapshot::new(1, vec![])).is_none());
+        assert!(store.is_settled(2));
+    }
+
+    #[test]
+    fn discard() {
+        let store = VersionStore::new();
+        let block = BlockId::new("file".to_string(), 0);
+        store.push(&block, 0, 2, image(1));
+
+        store.discard(2);
+        assert!(store.find(&block, 0, &Snapshot::new(3, vec![])).is_none());
+    }
+}
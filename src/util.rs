@@ -2,12 +2,15 @@
 
 use std::{
     collections::HashMap,
+    path::Path,
     sync::{Condvar, Mutex},
 };
 
 use tracing::Level;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+use crate::error::DbError;
+
+pub type Result<T> = std::result::Result<T, DbError>;
 
 pub const BOOL_BYTES: i32 = 1;
 pub const SHORT_BYTES: i32 = 2;
@@ -28,6 +31,35 @@ pub fn waiting_too_long(start_time: u128) -> bool {
     current_time - start_time > MAX_WAIT_TIME_MILLIS
 }
 
+// CRC-32 (IEEE), as used by zlib.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Create `dir`, failing if it already holds anything.
+pub fn create_empty_dir(dir: &Path) -> Result<()> {
+    if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} is not empty", dir.display()),
+        )
+        .into());
+    }
+    std::fs::create_dir_all(dir)?;
+    Ok(())
+}
+
 pub fn init_log() {
     tracing_subscriber::fmt()
         .json()
@@ -53,7 +85,7 @@ impl<T> CondMutex<T> {
         }
     }
 
-    pub fn lock(&self) -> std::sync::MutexGuard<T> {
+    pub fn lock(&self) -> std::sync::MutexGuard<'_, T> {
         self.m.lock().unwrap()
     }
 
@@ -79,6 +111,12 @@ pub struct ConcurrentHashMap<K, V> {
     map: HashMap<K, V>,
 }
 
+impl<K: Eq + std::hash::Hash + Clone, V> Default for ConcurrentHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<K: Eq + std::hash::Hash + Clone, V> ConcurrentHashMap<K, V> {
     pub fn new() -> Self {
         Self {